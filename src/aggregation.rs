@@ -0,0 +1,207 @@
+use std::cmp;
+use std::collections::TreeMap;
+
+use serialize::json;
+
+use storage::Message;
+use filter::{Field, parse_field};
+
+/// Pseudo-river keeping progress of aggregations as its state, hidden from the list of rivers
+pub const AGGREGATIONS: &'static str = ".aggregations";
+
+/// Kind of state of AGGREGATIONS pseudo-river keeping progress of every aggregation
+pub const AGGREGATIONS_STATE: &'static str = "aggregations";
+
+#[deriving(Clone, Show, PartialEq)]
+/// Windows messages are aggregated in by their timestamps, sizes are in milliseconds
+pub enum Window {
+    /// Consecutive windows of the size, every message falls into exactly one of them
+    TumblingWindow(u64),
+    /// Windows of the size (the first number) starting every slide (the second number)
+    /// milliseconds, a message falls into every window overlapping its timestamp
+    SlidingWindow(u64, u64)
+}
+
+impl Window {
+    fn size_and_slide(&self) -> (u64, u64) {
+        match *self {
+            TumblingWindow(size) => (size, size),
+            SlidingWindow(size, slide) => (size, slide)
+        }
+    }
+
+    /// Starts of the windows the timestamp falls into, the earliest first
+    fn starts(&self, timestamp: u64) -> Vec < u64 > {
+        let (size, slide) = self.size_and_slide();
+        let mut starts = vec![];
+        let mut start = timestamp - timestamp % slide;
+
+        while start + size > timestamp {
+            starts.push(start);
+
+            if start < slide {
+                break
+            }
+
+            start -= slide;
+        }
+
+        starts.reverse();
+        starts
+    }
+}
+
+#[deriving(Clone)]
+/// Counts and sums of messages of a river per window, see AggregateCommand
+pub struct Aggregation {
+    window: Window,
+    key: Option < Field >,
+    value: Option < Field >,
+    grace: u64
+}
+
+impl Aggregation {
+    /// Aggregates messages in the windows, grouped by the key field and summing the value
+    /// field. Fields are the ones compared by filters, e.g. `$.region` (see Filter). Messages
+    /// arriving up to grace milliseconds after the end of their window are still counted in
+    /// it. Err tells what is wrong with the aggregation
+    pub fn new(window: Window, key: Option < &str >, value: Option < &str >, grace: u64) -> Result < Aggregation, String > {
+        match window.size_and_slide() {
+            (0, _) | (_, 0) => return Err("size and slide of the window should be positive".to_string()),
+            _ => {}
+        }
+
+        let key = match key {
+            Some(key) => Some(try!(parse_field(key))),
+            None => None
+        };
+        let value = match value {
+            Some(value) => Some(try!(parse_field(value))),
+            None => None
+        };
+
+        Ok(Aggregation {
+            window: window,
+            key: key,
+            value: value,
+            grace: grace
+        })
+    }
+
+    fn closes_at(&self, start: u64) -> u64 {
+        let (size, _) = self.window.size_and_slide();
+
+        start + size + self.grace
+    }
+}
+
+#[deriving(Encodable, Decodable, Clone, PartialEq)]
+/// Count and sum of messages with the key in the window starting at start
+struct Bucket {
+    start: u64,
+    key: String,
+    count: uint,
+    sum: f64
+}
+
+#[deriving(Encodable, Decodable, Clone, PartialEq)]
+/// Next input offset of an aggregation together with its open windows and payloads of results
+/// of closed windows, which are not pushed yet
+pub struct Progress {
+    pub offset: Option < uint >,
+    pub pending: Vec < String >,
+    watermark: u64,
+    ticked_at: u64,
+    buckets: Vec < Bucket >
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            offset: None,
+            pending: vec![],
+            watermark: 0,
+            ticked_at: 0,
+            buckets: vec![]
+        }
+    }
+
+    /// Advances the latest timestamp seen by the time passed since the last tick, so windows
+    /// close grace milliseconds after their end even when no messages arrive
+    pub fn tick(&mut self, now: u64) {
+        if self.ticked_at > 0 && now > self.ticked_at {
+            self.watermark += now - self.ticked_at;
+        }
+
+        self.ticked_at = cmp::max(self.ticked_at, now);
+    }
+
+    /// Adds the message to its windows, which are still open. Messages too late for all of
+    /// them are dropped
+    pub fn add(&mut self, aggregation: &Aggregation, message: &Message) {
+        let key = aggregation.key.as_ref()
+            .and_then(|key| key.value_of(message))
+            .unwrap_or(json::Null)
+            .to_string();
+        let value = aggregation.value.as_ref()
+            .and_then(|value| value.value_of(message))
+            .and_then(|value| value.as_f64())
+            .unwrap_or(0.0);
+
+        for &start in aggregation.window.starts(message.timestamp).iter() {
+            if aggregation.closes_at(start) <= self.watermark {
+                continue
+            }
+
+            let position = self.buckets.iter().position(|bucket| bucket.start == start && bucket.key == key);
+            match position {
+                Some(position) => {
+                    let bucket = &mut self.buckets.as_mut_slice()[position];
+                    bucket.count += 1;
+                    bucket.sum += value;
+                },
+                None => self.buckets.push(Bucket { start: start, key: key.clone(), count: 1, sum: value })
+            }
+        }
+
+        self.watermark = cmp::max(self.watermark, message.timestamp);
+    }
+
+    /// Removes windows closed by the latest timestamp seen, adds payloads of their results to
+    /// the pending ones, the earliest window first
+    pub fn close(&mut self, aggregation: &Aggregation) {
+        let watermark = self.watermark;
+        let (mut closed, open) = self.buckets.clone().partition(|bucket| aggregation.closes_at(bucket.start) <= watermark);
+        let (size, _) = aggregation.window.size_and_slide();
+
+        self.buckets = open;
+        closed.sort_by(|a, b| (a.start, &a.key).cmp(&(b.start, &b.key)));
+
+        let results: Vec < String > = closed.iter().map(|bucket| {
+            let mut result = TreeMap::new();
+            result.insert("start".to_string(), json::U64(bucket.start));
+            result.insert("end".to_string(), json::U64(bucket.start + size));
+            result.insert("key".to_string(), json::from_str(bucket.key.as_slice()).unwrap_or(json::Null));
+            result.insert("count".to_string(), json::U64(bucket.count as u64));
+            result.insert("sum".to_string(), json::F64(bucket.sum));
+
+            json::Object(result).to_string()
+        }).collect();
+
+        self.pending.push_all(results.as_slice());
+    }
+}
+
+/// Progress of aggregations by their names, Err tells why the saved progress is unreadable,
+/// as starting over would push results of windows again
+pub fn load_progress(state: Option < String >) -> Result < TreeMap < String, Progress >, String > {
+    match state {
+        Some(state) => json::decode::< TreeMap < String, Progress > >(state.as_slice())
+            .map_err(|err| format!("Unable to read progress of aggregations: {}", err)),
+        None => Ok(TreeMap::new())
+    }
+}
+
+pub fn dump_progress(progress: &TreeMap < String, Progress >) -> String {
+    json::encode(progress)
+}
@@ -0,0 +1,837 @@
+use std::cmp;
+use std::io;
+use std::collections::TreeMap;
+use std::sync::{Arc, Mutex};
+
+use serialize::json;
+
+use commands::{PeekCommand, PeekResult};
+use storage::{Storage, FileStorage, Message, RiverInfo, Usage};
+use error::{PushError, WriteFailed};
+use schema::SchemaCache;
+use metrics::Metrics;
+
+const CLUSTERS_ROOT: &'static str = "./tmp/clusters";
+const SNAPSHOT_THRESHOLD: uint = 64;
+
+/// Error of cluster operations
+#[deriving(Show, PartialEq, Clone)]
+pub enum ClusterError {
+    /// Less than a majority of nodes is up, so no leader can be elected
+    NoLeader,
+    /// Leader was unable to replicate an append to a majority of nodes
+    NoQuorum
+}
+
+#[deriving(Show, PartialEq, Clone)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader
+}
+
+#[deriving(Clone, Encodable, Decodable)]
+enum Command {
+    Noop,
+    Create(String),
+    Append(String, Message),
+    Truncate(String, uint),
+    Destroy(String),
+    SetState(String, String, String)
+}
+
+#[deriving(Clone, Encodable, Decodable)]
+struct Entry {
+    term: u64,
+    command: Command
+}
+
+#[deriving(Encodable, Decodable)]
+/// Part of the node, which it should not forget after a crash
+struct PersistentState {
+    term: u64,
+    voted_for: Option < uint >,
+    log: Vec < Entry >,
+    snapshot_index: uint,
+    snapshot_term: u64,
+    last_applied: uint,
+    applying: Option < (uint, uint) >
+}
+
+struct AppendEntries {
+    term: u64,
+    prev_index: uint,
+    prev_term: u64,
+    entries: Vec < Entry >,
+    leader_commit: uint
+}
+
+struct InstallSnapshot {
+    term: u64,
+    last_index: uint,
+    last_term: u64,
+    source: Path
+}
+
+struct AppendReply {
+    term: u64,
+    success: bool,
+    match_index: uint
+}
+
+struct VoteRequest {
+    term: u64,
+    candidate: uint,
+    last_index: uint,
+    last_term: u64
+}
+
+struct VoteReply {
+    term: u64,
+    granted: bool
+}
+
+struct Node {
+    id: uint,
+    root: Path,
+    up: bool,
+    role: Role,
+    term: u64,
+    voted_for: Option < uint >,
+    log: Vec < Entry >,
+    snapshot_index: uint,
+    snapshot_term: u64,
+    commit_index: uint,
+    last_applied: uint,
+    next_index: Vec < uint >,
+    match_index: Vec < uint >,
+    /// Index of the append being applied together with the latest offset of its river before
+    /// it, so the append is not applied twice after a crash
+    applying: Option < (uint, uint) >,
+    /// Results of entries applied by the leader, by their index, until they are submitted
+    outcomes: TreeMap < uint, Result < uint, PushError > >
+}
+
+impl Node {
+    /// Node with rivers in `<root>/rivers` and the Raft state saved to `<root>/raft`, which
+    /// resumes from the saved state unless there is none
+    fn load(id: uint, root: Path) -> Node {
+        let _ = io::fs::mkdir_recursive(&root.join("rivers"), io::USER_RWX);
+
+        let state = io::File::open(&root.join("raft")).read_to_string().ok()
+            .and_then(|state| json::decode::< PersistentState >(state.as_slice()).ok())
+            .unwrap_or(PersistentState {
+                term: 0,
+                voted_for: None,
+                log: vec![],
+                snapshot_index: 0,
+                snapshot_term: 0,
+                last_applied: 0,
+                applying: None
+            });
+
+        Node {
+            id: id,
+            root: root,
+            up: true,
+            role: Follower,
+            term: state.term,
+            voted_for: state.voted_for,
+            log: state.log,
+            snapshot_index: state.snapshot_index,
+            snapshot_term: state.snapshot_term,
+            commit_index: state.last_applied,
+            last_applied: state.last_applied,
+            next_index: vec![],
+            match_index: vec![],
+            applying: state.applying,
+            outcomes: TreeMap::new()
+        }
+    }
+
+    fn rivers(&self) -> Path {
+        self.root.join("rivers")
+    }
+
+    fn storage(&self) -> FileStorage {
+        FileStorage::in_root(&self.rivers())
+    }
+
+    /// Saves term, vote, log and the last applied index atomically and flushes them to disk,
+    /// so the node does not forget them after replying. A node, which is unable to save them,
+    /// must not reply, so it panics
+    fn persist(&self) {
+        let state = PersistentState {
+            term: self.term,
+            voted_for: self.voted_for,
+            log: self.log.clone(),
+            snapshot_index: self.snapshot_index,
+            snapshot_term: self.snapshot_term,
+            last_applied: self.last_applied,
+            applying: self.applying
+        };
+
+        let saving = self.root.join("raft.saving");
+        let result = io::File::create(&saving)
+            .and_then(|mut file| {
+                try!(file.write_str(json::encode(&state).as_slice()));
+                file.fsync()
+            })
+            .and_then(|_| io::fs::rename(&saving, &self.root.join("raft")));
+
+        match result {
+            Ok(_) => {},
+            Err(err) => panic!("Unable to save Raft state of node {}: {}", self.id, err)
+        }
+    }
+
+    fn last_index(&self) -> uint {
+        self.snapshot_index + self.log.len()
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap()
+    }
+
+    fn term_at(&self, index: uint) -> Option < u64 > {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else if index < self.snapshot_index || index > self.last_index() {
+            None
+        } else {
+            Some(self.log[index - self.snapshot_index - 1].term)
+        }
+    }
+
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Follower;
+            self.persist();
+        }
+    }
+
+    fn append(&mut self, command: Command) -> uint {
+        self.log.push(Entry { term: self.term, command: command });
+        self.persist();
+
+        let id = self.id;
+        let last_index = self.last_index();
+        self.match_index[id] = last_index;
+        self.next_index[id] = last_index + 1;
+        last_index
+    }
+
+    fn become_leader(&mut self, size: uint) {
+        let next_index = self.last_index() + 1;
+
+        self.role = Leader;
+        self.next_index = Vec::from_elem(size, next_index);
+        self.match_index = Vec::from_elem(size, 0);
+        self.outcomes = TreeMap::new();
+        self.append(Noop);
+    }
+
+    fn vote_request(&self) -> VoteRequest {
+        VoteRequest {
+            term: self.term,
+            candidate: self.id,
+            last_index: self.last_index(),
+            last_term: self.last_term()
+        }
+    }
+
+    fn handle_vote(&mut self, request: VoteRequest) -> VoteReply {
+        self.observe_term(request.term);
+
+        let log_is_up_to_date = request.last_term > self.last_term() ||
+            (request.last_term == self.last_term() && request.last_index >= self.last_index());
+        let can_vote = match self.voted_for {
+            Some(candidate) => candidate == request.candidate,
+            None => true
+        };
+        let granted = request.term == self.term && can_vote && log_is_up_to_date;
+
+        if granted {
+            self.voted_for = Some(request.candidate);
+            self.persist();
+        }
+
+        VoteReply { term: self.term, granted: granted }
+    }
+
+    fn append_request(&self, follower: uint) -> AppendEntries {
+        let prev_index = self.next_index[follower] - 1;
+
+        AppendEntries {
+            term: self.term,
+            prev_index: prev_index,
+            prev_term: self.term_at(prev_index).unwrap(),
+            entries: self.log.slice_from(prev_index - self.snapshot_index).to_vec(),
+            leader_commit: self.commit_index
+        }
+    }
+
+    fn handle_append(&mut self, request: AppendEntries) -> AppendReply {
+        self.observe_term(request.term);
+
+        if request.term < self.term {
+            return AppendReply { term: self.term, success: false, match_index: 0 }
+        }
+
+        self.role = Follower;
+
+        let prev_matches = request.prev_index < self.snapshot_index ||
+            self.term_at(request.prev_index) == Some(request.prev_term);
+
+        if ! prev_matches {
+            return AppendReply {
+                term: self.term,
+                success: false,
+                match_index: cmp::min(self.last_index(), request.prev_index - 1)
+            }
+        }
+
+        let last_new_index = request.prev_index + request.entries.len();
+
+        for (i, entry) in request.entries.into_iter().enumerate() {
+            let index = request.prev_index + 1 + i;
+
+            if index <= self.snapshot_index {
+                continue
+            }
+
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index - self.snapshot_index - 1),
+                None => {}
+            }
+
+            self.log.push(entry);
+        }
+
+        self.persist();
+
+        if request.leader_commit > self.commit_index {
+            self.commit_index = cmp::min(request.leader_commit, last_new_index);
+            self.apply_committed();
+        }
+
+        AppendReply { term: self.term, success: true, match_index: last_new_index }
+    }
+
+    fn snapshot_request(&self) -> InstallSnapshot {
+        InstallSnapshot {
+            term: self.term,
+            last_index: self.last_applied,
+            last_term: self.term_at(self.last_applied).unwrap(),
+            source: self.rivers()
+        }
+    }
+
+    fn handle_snapshot(&mut self, request: InstallSnapshot) -> AppendReply {
+        self.observe_term(request.term);
+
+        if request.term < self.term {
+            return AppendReply { term: self.term, success: false, match_index: 0 }
+        }
+
+        self.role = Follower;
+
+        if request.last_index <= self.last_applied {
+            return AppendReply { term: self.term, success: true, match_index: request.last_index }
+        }
+
+        if self.term_at(request.last_index) == Some(request.last_term) {
+            self.log = self.log.slice_from(request.last_index - self.snapshot_index).to_vec();
+        } else {
+            self.log = vec![];
+        }
+
+        replace_rivers(&request.source, &self.rivers());
+
+        self.snapshot_index = request.last_index;
+        self.snapshot_term = request.last_term;
+        self.commit_index = cmp::max(self.commit_index, request.last_index);
+        self.last_applied = request.last_index;
+        self.persist();
+        self.apply_committed();
+
+        AppendReply { term: self.term, success: true, match_index: request.last_index }
+    }
+
+    fn advance_commit(&mut self, size: uint) {
+        let mut index = self.last_index();
+
+        while index > self.commit_index {
+            let replicas = self.match_index.iter().filter(|&&m| m >= index).count();
+
+            if self.term_at(index) == Some(self.term) && replicas > size / 2 {
+                self.commit_index = index;
+                break
+            }
+
+            index -= 1;
+        }
+
+        self.apply_committed();
+    }
+
+    /// Applies committed entries to the rivers of the node, saving the last applied index after
+    /// each of them. Appends are not applied again after a crash, as the latest offset of their
+    /// river is saved before they are written, the rest of entries can be applied again
+    fn apply_committed(&mut self) {
+        if self.last_applied >= self.commit_index {
+            return
+        }
+
+        let storage = self.storage();
+
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+
+            let outcome = match self.log[index - self.snapshot_index - 1].command.clone() {
+                Noop => Ok(0),
+                Create(river) => storage.create(river.as_slice()).map(|_| 0).map_err(WriteFailed),
+                Append(river, message) => {
+                    let latest_offset = storage.latest_offset(river.as_slice());
+                    let applying = self.applying;
+
+                    match applying {
+                        Some((applying, before)) if applying == index && latest_offset > before => Ok(before + 1),
+                        _ => {
+                            self.applying = Some((index, latest_offset));
+                            self.persist();
+                            storage.write(river.as_slice(), &message)
+                        }
+                    }
+                },
+                Truncate(river, before) => storage.truncate(river.as_slice(), before).map(|_| 0).map_err(WriteFailed),
+                Destroy(river) => storage.destroy(river.as_slice()).map(|_| 0).map_err(WriteFailed),
+                SetState(river, kind, state) =>
+                    storage.update_state(river.as_slice(), kind.as_slice(), |_| state.clone()).map(|_| 0).map_err(WriteFailed)
+            };
+
+            if self.role == Leader {
+                self.outcomes.insert(index, outcome);
+            }
+
+            self.last_applied = index;
+            self.applying = None;
+
+            if self.last_applied - self.snapshot_index >= SNAPSHOT_THRESHOLD {
+                self.compact();
+            }
+
+            self.persist();
+        }
+    }
+
+    fn compact(&mut self) {
+        let last_applied = self.last_applied;
+
+        self.snapshot_term = self.term_at(last_applied).unwrap();
+        self.log = self.log.slice_from(last_applied - self.snapshot_index).to_vec();
+        self.snapshot_index = last_applied;
+    }
+}
+
+fn replace_rivers(source: &Path, destination: &Path) {
+    for path in io::fs::readdir(destination).unwrap_or(vec![]).iter() {
+        let _ = io::fs::unlink(path);
+    }
+
+    for path in io::fs::readdir(source).unwrap_or(vec![]).iter() {
+        match path.filename_str() {
+            Some(name) => { let _ = io::fs::copy(path, &destination.join(name)); },
+            None => {}
+        }
+    }
+}
+
+/// Raft cluster of local nodes
+///
+/// Every push goes through the replicated Raft log and is appended to the
+/// rivers of each node only after the entry is committed by a majority of
+/// nodes (see ClusterStorage to serve the cluster with Server):
+///
+/// ```
+/// let mut cluster = john::Cluster::new("a cluster", 3);
+///
+/// cluster.push("river name", "a message").unwrap();
+/// let leader = cluster.leader().unwrap();
+/// cluster.stop(leader);
+///
+/// // a new leader is elected and still has the message
+/// cluster.peek("river name", None);
+/// ```
+///
+/// Each node keeps its rivers in `./tmp/clusters/<cluster name>/node-<id>/rivers`
+/// and its term, vote and log in `./tmp/clusters/<cluster name>/node-<id>/raft`,
+/// which is flushed to disk before the node replies to other nodes, so a cluster
+/// created again with the same name (e.g. after a crash) resumes where it stopped.
+/// Nodes compact their log into a snapshot (rivers of the node) every
+/// `SNAPSHOT_THRESHOLD` applied entries; followers, which lag behind the
+/// snapshot of the leader, receive it instead of the log entries.
+pub struct Cluster {
+    nodes: Vec < Node >,
+    leader: Option < uint >
+}
+
+impl Cluster {
+    /// Constructor ::new()
+    ///
+    /// Creates cluster of `size` nodes (typically 3 or 5), which resumes from the state
+    /// saved by a cluster with the same name, or starts with empty rivers
+    pub fn new(name: &str, size: uint) -> Cluster {
+        assert!(size % 2 == 1, "Cluster should have an odd number of nodes");
+
+        let root = Path::new(CLUSTERS_ROOT).join(name);
+
+        Cluster {
+            nodes: range(0, size).map(|id| Node::load(id, root.join(format!("node-{}", id)))).collect(),
+            leader: None
+        }
+    }
+
+    /// Returns id of current leader node if there is one
+    pub fn leader(&self) -> Option < uint > {
+        match self.leader {
+            Some(leader) if self.nodes[leader].up && self.nodes[leader].role == Leader => Some(leader),
+            _ => None
+        }
+    }
+
+    /// Pushes a message to a river through the leader
+    ///
+    /// Elects a new leader when there is none. Returns Ok only when the message is
+    /// committed, i.e. its log entry is flushed to disk by a majority of nodes
+    pub fn push(&mut self, river: &str, message: &str) -> Result < (), ClusterError > {
+        self.submit(Append(river.to_string(), Message::new(message))).map(|_| ())
+    }
+
+    /// Peeks at a river on the leader node, offset semantics are the same as for PeekCommand
+    pub fn peek(&mut self, river: &str, offset: Option < uint >) -> Option < PeekResult > {
+        match self.ensure_leader() {
+            Ok(leader) => self.peek_on(leader, river, offset),
+            Err(_) => None
+        }
+    }
+
+    /// Peeks at a river on specific node, regardless of it being a leader
+    pub fn peek_on(&self, node: uint, river: &str, offset: Option < uint >) -> Option < PeekResult > {
+        PeekCommand::with_storage(self.nodes[node].storage()).execute(river, offset)
+    }
+
+    /// Simulates a crash of the node: it stops answering to other nodes
+    pub fn stop(&mut self, node: uint) {
+        self.nodes[node].up = false;
+    }
+
+    /// Brings the node back and lets the leader catch it up
+    pub fn start(&mut self, node: uint) {
+        self.nodes[node].up = true;
+        self.nodes[node].role = Follower;
+
+        match self.leader() {
+            Some(leader) if leader != node => { self.replicate(leader, node); },
+            _ => {}
+        }
+    }
+
+    /// Replicates the command through the leader, returns the result of applying it once it
+    /// is committed. The command is retracted when it is not committed, so it never is
+    fn submit(&mut self, command: Command) -> Result < Result < uint, PushError >, ClusterError > {
+        let leader = try!(self.ensure_leader());
+        let index = self.nodes[leader].append(command);
+
+        self.broadcast(leader);
+
+        if self.nodes[leader].commit_index < index {
+            self.retract(leader, index);
+            return Err(NoQuorum)
+        }
+
+        Ok(self.nodes[leader].outcomes.remove(&index)
+            .unwrap_or(Err(WriteFailed("entry was committed, but not applied by the leader".to_string()))))
+    }
+
+    /// Removes the entry, which was not committed, together with the entries after it from
+    /// the logs of the nodes, which received it
+    fn retract(&mut self, leader: uint, index: uint) {
+        let term = self.nodes[leader].term_at(index);
+
+        for node in self.nodes.iter_mut() {
+            if index > node.commit_index && index > node.snapshot_index && node.term_at(index) == term {
+                node.log.truncate(index - node.snapshot_index - 1);
+                node.persist();
+            }
+        }
+
+        let leader = &mut self.nodes[leader];
+        for follower in range(0, leader.next_index.len()) {
+            leader.match_index[follower] = cmp::min(leader.match_index[follower], index - 1);
+            leader.next_index[follower] = cmp::min(leader.next_index[follower], index);
+        }
+    }
+
+    /// Storage of the leader, None when no leader can be elected, so reads never see the
+    /// rivers of a node lagging behind
+    fn serving(&mut self) -> Option < FileStorage > {
+        match self.ensure_leader() {
+            Ok(leader) => Some(self.nodes[leader].storage()),
+            Err(_) => None
+        }
+    }
+
+    fn ensure_leader(&mut self) -> Result < uint, ClusterError > {
+        match self.leader() {
+            Some(leader) => Ok(leader),
+            None => self.elect()
+        }
+    }
+
+    fn elect(&mut self) -> Result < uint, ClusterError > {
+        self.leader = None;
+
+        for candidate in range(0, self.nodes.len()) {
+            if self.nodes[candidate].up && self.campaign(candidate) {
+                return Ok(candidate)
+            }
+        }
+
+        Err(NoLeader)
+    }
+
+    fn campaign(&mut self, candidate: uint) -> bool {
+        let size = self.nodes.len();
+
+        self.nodes[candidate].term += 1;
+        self.nodes[candidate].role = Candidate;
+        self.nodes[candidate].voted_for = Some(candidate);
+        self.nodes[candidate].persist();
+
+        let mut votes = 1u;
+
+        for voter in range(0, size) {
+            if voter == candidate || ! self.nodes[voter].up {
+                continue
+            }
+
+            let request = self.nodes[candidate].vote_request();
+            let reply = self.nodes[voter].handle_vote(request);
+
+            if reply.term > self.nodes[candidate].term {
+                self.nodes[candidate].observe_term(reply.term);
+                return false
+            }
+
+            if reply.granted {
+                votes += 1;
+            }
+        }
+
+        if votes <= size / 2 {
+            self.nodes[candidate].role = Follower;
+            return false
+        }
+
+        self.nodes[candidate].become_leader(size);
+        self.leader = Some(candidate);
+        self.broadcast(candidate);
+        true
+    }
+
+    fn broadcast(&mut self, leader: uint) {
+        let size = self.nodes.len();
+
+        for _ in range(0u, 2) {
+            for follower in range(0, size) {
+                if follower != leader && ! self.replicate(leader, follower) && self.leader.is_none() {
+                    return
+                }
+            }
+
+            self.nodes[leader].advance_commit(size);
+        }
+    }
+
+    fn replicate(&mut self, leader: uint, follower: uint) -> bool {
+        loop {
+            if ! self.nodes[follower].up {
+                return false
+            }
+
+            let next_index = self.nodes[leader].next_index[follower];
+            let reply = match next_index <= self.nodes[leader].snapshot_index {
+                true => {
+                    let request = self.nodes[leader].snapshot_request();
+                    self.nodes[follower].handle_snapshot(request)
+                },
+                false => {
+                    let request = self.nodes[leader].append_request(follower);
+                    self.nodes[follower].handle_append(request)
+                }
+            };
+
+            if reply.term > self.nodes[leader].term {
+                self.nodes[leader].observe_term(reply.term);
+                self.leader = None;
+                return false
+            }
+
+            if reply.success {
+                self.nodes[leader].match_index[follower] = reply.match_index;
+                self.nodes[leader].next_index[follower] = reply.match_index + 1;
+                return true
+            }
+
+            self.nodes[leader].next_index[follower] =
+                cmp::max(1, cmp::min(next_index - 1, reply.match_index + 1));
+        }
+    }
+}
+
+#[deriving(Clone)]
+/// Storage replicating its rivers with a local Raft cluster, see Cluster
+///
+/// Creations, appends, truncations and removals of rivers and updates of their states go
+/// through the replicated log and return once committed by a majority of nodes. Without a
+/// majority they are not done and fail, appends with WriteFailed. Reads are served by the
+/// leader and find nothing without a majority. Commands and Server work with it like with
+/// any other storage:
+///
+/// ```
+/// let storage = john::ClusterStorage::new("a cluster", 3);
+///
+/// john::PushCommand::with_storage(storage.clone()).execute("river name", "a message");
+/// john::Server::with_storage(3000, storage);
+/// ```
+pub struct ClusterStorage {
+    cluster: Arc < Mutex < Cluster > >,
+    state_lock: Arc < Mutex < () > >,
+    append_lock: Arc < Mutex < () > >,
+    write_lock: Arc < Mutex < () > >,
+    schemas: Arc < Mutex < SchemaCache > >,
+    metrics: Arc < Mutex < Metrics > >
+}
+
+impl ClusterStorage {
+    /// Constructor ::new()
+    ///
+    /// Creates storage replicated by the cluster with the name, see Cluster::new
+    pub fn new(name: &str, size: uint) -> ClusterStorage {
+        ClusterStorage {
+            cluster: Arc::new(Mutex::new(Cluster::new(name, size))),
+            state_lock: Arc::new(Mutex::new(())),
+            append_lock: Arc::new(Mutex::new(())),
+            write_lock: Arc::new(Mutex::new(())),
+            schemas: Arc::new(Mutex::new(SchemaCache::new())),
+            metrics: Arc::new(Mutex::new(Metrics::new()))
+        }
+    }
+
+    /// Cluster replicating the storage, e.g. to stop and start its nodes
+    pub fn cluster(&self) -> &Mutex < Cluster > {
+        &*self.cluster
+    }
+
+    fn serving(&self) -> Option < FileStorage > {
+        self.cluster.lock().serving()
+    }
+
+    /// Replicates the command, Err tells why it was not done
+    fn replicate(&self, command: Command) -> Result < (), String > {
+        match self.cluster.lock().submit(command) {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(WriteFailed(reason))) => Err(reason),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) => Err(err.to_string())
+        }
+    }
+}
+
+impl Storage for ClusterStorage {
+    fn create(&self, river: &str) -> Result < (), String > {
+        self.replicate(Create(river.to_string()))
+    }
+
+    fn write(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        let _lock = self.write_lock.lock();
+        match self.cluster.lock().submit(Append(river.to_string(), message.clone())) {
+            Ok(written) => written,
+            Err(err) => Err(WriteFailed(err.to_string()))
+        }
+    }
+
+    fn serialize_appends < T > (&self, appends: || -> T) -> T {
+        let _lock = self.append_lock.lock();
+        appends()
+    }
+
+    fn freeze < T > (&self, read: || -> T) -> T {
+        let _state_lock = self.state_lock.lock();
+        let _append_lock = self.append_lock.lock();
+        let _lock = self.write_lock.lock();
+        read()
+    }
+
+    fn usage(&self) -> Usage {
+        self.serving().map_or(Usage { bytes: 0, messages: 0 }, |storage| storage.usage())
+    }
+
+    fn schemas(&self) -> &Mutex < SchemaCache > {
+        &*self.schemas
+    }
+
+    fn metrics(&self) -> &Mutex < Metrics > {
+        &*self.metrics
+    }
+
+    fn read(&self, river: &str, offset: uint) -> Option < Message > {
+        self.serving().and_then(|storage| storage.read(river, offset))
+    }
+
+    fn earliest_offset(&self, river: &str) -> uint {
+        self.serving().map_or(1, |storage| storage.earliest_offset(river))
+    }
+
+    fn latest_offset(&self, river: &str) -> uint {
+        self.serving().map_or(0, |storage| storage.latest_offset(river))
+    }
+
+    fn truncate(&self, river: &str, before: uint) -> Result < (), String > {
+        let _lock = self.write_lock.lock();
+        self.replicate(Truncate(river.to_string(), before))
+    }
+
+    fn info(&self, river: &str) -> Option < RiverInfo > {
+        self.serving().and_then(|storage| storage.info(river))
+    }
+
+    fn rivers(&self) -> Vec < String > {
+        self.serving().map_or(vec![], |storage| storage.rivers())
+    }
+
+    fn message_size(&self, message: &Message) -> u64 {
+        // the size does not depend on the node
+        self.cluster.lock().nodes[0].storage().message_size(message)
+    }
+
+    fn destroy(&self, river: &str) -> Result < (), String > {
+        let _lock = self.write_lock.lock();
+        self.replicate(Destroy(river.to_string()))
+    }
+
+    fn state(&self, river: &str, kind: &str) -> Option < String > {
+        self.serving().and_then(|storage| storage.state(river, kind))
+    }
+
+    fn update_state(&self, river: &str, kind: &str, update: |Option < String >| -> String) -> Result < (), String > {
+        let _lock = self.state_lock.lock();
+        let state = update(self.state(river, kind));
+
+        self.replicate(SetState(river.to_string(), kind.to_string(), state))
+    }
+}
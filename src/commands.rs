@@ -1,5 +1,111 @@
-use river::River;
-pub use river::PeekResult;
+use std::cmp;
+use std::collections::TreeMap;
+use std::io;
+use std::io::timer;
+use std::time::Duration;
+
+use serialize::json;
+
+use storage::{Storage, FileStorage, DeadLetter, now};
+pub use error::{PushError, QuotaError, RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge, SchemaViolation, WriteFailed};
+use offset::{Offset, At};
+pub use storage::{Message, RiverInfo};
+use queue::{Queue, QUEUE_STATE, DEAD_LETTER_STATE, REPLAY_STATE, load_policy, dump_policy};
+use queue::{PRIORITY_STATE, load_prioritized, dump_prioritized};
+use dedup::{Dedup, DEDUP_STATE};
+use transaction::{TransactionLog, Transactions, TRANSACTIONS, TRANSACTIONS_STATE};
+use transaction::{InProgress, Committed, Aborted};
+pub use queue::DeadLetterPolicy;
+use quota::{QUOTA_STATE, STORAGE, dump_quota};
+pub use quota::{Quota, QuotaPolicy, RejectPushes, DropOldest};
+use config::{CONFIG_STATE, load_config, dump_config};
+use schema::{Registry, Validator, SCHEMAS, SCHEMAS_STATE, SCHEMA_STATE, MAX_SCHEMA_NAME_LENGTH, dump_binding};
+pub use schema::SchemaBinding;
+pub use storage::SchemaVersion;
+pub use config::{RiverConfig, RiverMode, LogMode, QueueMode};
+pub use filter::Filter;
+use transform::{TRANSFORMS, TRANSFORMS_STATE, PUSHED_STATE, load_offsets, dump_offsets, load_pushed, dump_pushed};
+pub use transform::{Transform, MapTransform, FilterTransform, FlatMapTransform};
+use aggregation::{Progress, AGGREGATIONS, AGGREGATIONS_STATE, load_progress, dump_progress};
+pub use aggregation::{Aggregation, Window, TumblingWindow, SlidingWindow};
+use routing::{Rules, ROUTING_STATE, load_rules, dump_rules};
+pub use routing::RoutingRule;
+use exchange::{EXCHANGES, EXCHANGES_STATE, load_exchanges, dump_exchanges};
+pub use exchange::{Exchange, ExchangeBinding, ExchangeKind, DirectExchange, FanoutExchange, TopicExchange};
+use metrics::{record_read, render};
+
+/// Maximum number of messages scanned by a single read with a filter
+const MAX_SCANNED: uint = 1000;
+
+/// Time in milliseconds between polls of the input river of a derived river, which caught up
+const POLL_INTERVAL: i64 = 100;
+
+/// Maximum number of input messages aggregated between saves of progress of an aggregation
+const AGGREGATION_BATCH: uint = 1000;
+
+/// Kinds of states kept alongside rivers, which are included into snapshots
+const RIVER_STATES: [&'static str, ..9] = [CONFIG_STATE, SCHEMA_STATE, QUOTA_STATE, PRIORITY_STATE, DEAD_LETTER_STATE,
+                                          QUEUE_STATE, REPLAY_STATE, DEDUP_STATE, ROUTING_STATE];
+
+/// States of the storage as a whole by their river and kind, which are included into snapshots
+/// besides the transaction log
+const STORAGE_STATES: [(&'static str, &'static str), ..6] = [(STORAGE, QUOTA_STATE), (SCHEMAS, SCHEMAS_STATE),
+                                                            (EXCHANGES, EXCHANGES_STATE), (TRANSFORMS, TRANSFORMS_STATE),
+                                                            (TRANSFORMS, PUSHED_STATE), (AGGREGATIONS, AGGREGATIONS_STATE)];
+
+#[deriving(Encodable, Decodable)]
+/// Result of PeekCommand, when it was successful
+/// Contains message and new offset to specify to peek command
+/// for continuous peeking
+pub struct PeekResult {
+    /// Contains message
+    pub message: String,
+    /// Contains next offset to be specified to read next message from river
+    pub offset: uint
+}
+
+impl Clone for PeekResult {
+    fn clone(&self) -> PeekResult {
+        PeekResult {
+            message: self.message.clone(),
+            offset: self.offset.clone()
+        }
+    }
+
+    fn clone_from(&mut self, source: &PeekResult) {
+        self.message = source.message.clone();
+        self.offset = source.offset.clone();
+    }
+}
+
+/// Error of PeekCommand, when it was unable to peek a message
+#[deriving(Show, PartialEq, Clone)]
+pub enum PeekError {
+    /// There is no message at this offset (yet)
+    NoMessage,
+    /// Message at this offset was discarded by truncation, contains earliest offset
+    /// still available in the river
+    OffsetExpired(uint),
+    /// Message at this offset is held back, contains time of its delivery in milliseconds
+    /// since Unix epoch
+    Delayed(u64),
+    /// Message at this offset outlived its time-to-live, contains time of its expiration in
+    /// milliseconds since Unix epoch
+    MessageExpired(u64),
+    /// Message at this offset was pushed in a transaction, which was aborted
+    RolledBack,
+    /// None of the scanned messages matches the filter, contains offset to continue from
+    NoMatchingMessage(uint)
+}
+
+/// Result of idempotent push with PushCommand
+#[deriving(Show, PartialEq, Clone)]
+pub enum PushResult {
+    /// Message was appended to the river, contains its offset
+    Appended(uint),
+    /// Message with the same idempotency key was pushed before, contains its offset
+    Duplicate(uint)
+}
 
 /// Push command - stateless
 ///
@@ -8,21 +114,122 @@ pub use river::PeekResult;
 /// ```
 /// john::PushCommand::new().execute("river_name", "message");
 /// ```
-pub struct PushCommand;
+///
+/// All commands use FileStorage when created with `::new()`, use `::with_storage()`
+/// to specify another storage:
+///
+/// ```
+/// john::PushCommand::with_storage(john::MemoryStorage::new()).execute("river_name", "message");
+/// ```
+///
+/// Use `push` to push a message with metadata, e.g. to hold it back from readers for a minute:
+///
+/// ```
+/// john::PushCommand::new().push("river_name", &john::Message {
+///     deliver_at: Some(john::now() + 60000),
+///     ..john::Message::new("remind me")
+/// });
+///
+/// // worthless after 5 minutes
+/// john::PushCommand::new().push("river_name", &john::Message::with_ttl("ping", 300000));
+/// ```
+///
+/// Use `push_once` to make retries of a push safe. The river remembers idempotency keys of
+/// its recent 1000 pushes, and does not append a message with a known key again:
+///
+/// ```
+/// let message = john::Message::new("order 42 created");
+///
+/// // e.g. producer id and its sequence number
+/// john::PushCommand::new().push_once("river_name", "order-service:17", &message);  // => Ok(Appended(3))
+/// john::PushCommand::new().push_once("river_name", "order-service:17", &message);  // => Ok(Duplicate(3))
+/// ```
+///
+/// `push` and `push_once` fail with PushError when the message does not fit into a quota
+/// (see ConfigureQuotaCommand) or a schema (see ConfigureSchemaCommand), `execute` drops such
+/// a message silently.
+pub struct PushCommand < S > {
+    storage: S
+}
 
-impl PushCommand {
+impl PushCommand < FileStorage > {
     /// Constructor ::new()
     ///
     /// Creates new instance of PushCommand
-    pub fn new() -> PushCommand {
-        PushCommand
+    pub fn new() -> PushCommand < FileStorage > {
+        PushCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > PushCommand < S > {
+    /// Creates new instance of PushCommand working with specified storage
+    pub fn with_storage(storage: S) -> PushCommand < S > {
+        PushCommand { storage: storage }
     }
 
     /// Used to execute push command, specifying a river name and message
     /// This can be called multiple times with different arguments
     /// since PushCommand is stateless
     pub fn execute(&self, river: &str, message: &str) {
-        River::new(river).push(message);
+        let _ = self.push(river, &Message::new(message));
+    }
+
+    /// Same as `execute`, but pushes message together with its metadata. Returns offset
+    /// of the message assigned by the river
+    pub fn push(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        let message = try!(Validator::of(&self.storage, river).validate(&self.storage, message));
+        let offset = try!(self.append(river, &message));
+
+        self.route(river, &message, offset);
+        Ok(offset)
+    }
+
+    /// Same as `push`, but does nothing when a message with the same idempotency key was
+    /// recently pushed to the river
+    pub fn push_once(&self, river: &str, key: &str, message: &Message) -> Result < PushResult, PushError > {
+        let message = try!(Validator::of(&self.storage, river).validate(&self.storage, message));
+        let mut result = None;
+
+        // the message stays appended, even when its key failed to be remembered
+        let _ = self.storage.update_state(river, DEDUP_STATE, |state| {
+            let mut dedup = Dedup::load(state);
+
+            result = Some(match dedup.find(key) {
+                Some(offset) => Ok(Duplicate(offset)),
+                None => self.storage.append(river, &message).map(|offset| {
+                    dedup.remember(key, offset);
+                    Appended(offset)
+                })
+            });
+
+            dedup.dump()
+        });
+
+        match result {
+            Some(Ok(Appended(offset))) => self.route(river, &message, offset),
+            _ => {}
+        }
+
+        result.unwrap()
+    }
+
+    /// Appends validated message, storage checks quotas of the river
+    fn append(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        self.storage.append(river, message)
+    }
+
+    /// Pushes copies of the message appended at the offset to targets of matching routing
+    /// rules of the river. Copies rejected by their target are dropped, routing rules of the
+    /// target are not applied to them
+    fn route(&self, river: &str, message: &Message, offset: uint) {
+        let message = Message { offset: offset, ..message.clone() };
+
+        for target in Rules::of(&self.storage, river).targets(&message).iter() {
+            let copy = Message { offset: 0, transaction: None, schema: None, ..message.clone() };
+
+            let _ = Validator::of(&self.storage, target.as_slice()).validate(&self.storage, &copy)
+                .and_then(|copy| self.append(target.as_slice(), &copy));
+        }
     }
 }
 
@@ -44,19 +251,162 @@ impl PushCommand {
 ///
 /// It returns Option < PeekResult >. When it was able to peek a message, the result will contain
 /// peeked message and new offset to specify to peek command (if you want to get next message)
-pub struct PeekCommand;
+///
+/// Use `peek` instead of `execute` to tell a missing message from an expired offset:
+///
+/// ```
+/// match john::PeekCommand::new().peek("river name", Some(2)) {
+///     Ok(result) => println!("{}", result.message),
+///     Err(john::OffsetExpired(earliest_offset)) => println!("continue from {}", earliest_offset),
+///     Err(john::Delayed(deliver_at)) => println!("try again at {}", deliver_at),
+///     Err(john::MessageExpired(_)) | Err(john::RolledBack) => println!("skip it, continue from 3"),
+///     Err(john::NoMessage) => println!("nothing at offset 2"),
+///     Err(john::NoMatchingMessage(_)) => unreachable!()
+/// }
+/// ```
+///
+/// Use `read` to specify offset explicitly and to get message with its metadata. Offset of the
+/// read message is its position, so the next message is at `message.offset + 1`:
+///
+/// ```
+/// john::PeekCommand::new().read("river name", john::Earliest);
+/// john::PeekCommand::new().read("river name", john::At(2));
+/// john::PeekCommand::new().read("river name", john::FromLatest(1));    // one before the latest
+/// ```
+///
+/// Use `peek_matching` and `read_matching` to skip messages not matching a filter (see
+/// Filter). Up to 1000 messages are scanned, when none of them matches, the error tells the
+/// offset to continue from:
+///
+/// ```
+/// let filter = john::Filter::parse(r#"$.type == "order.created""#).unwrap();
+///
+/// match john::PeekCommand::new().read_matching("river name", john::Earliest, &filter) {
+///     Ok(message) => println!("{} at {}", message.payload, message.offset),
+///     Err(john::NoMatchingMessage(offset)) => println!("nothing yet, continue from {}", offset),
+///     Err(_) => println!("try again later")
+/// }
+/// ```
+pub struct PeekCommand < S > {
+    storage: S
+}
 
-impl PeekCommand {
+impl PeekCommand < FileStorage > {
     /// Constructor ::new()
     ///
     /// Creates new instance of PeekCommand
-    pub fn new() -> PeekCommand {
-        PeekCommand
+    pub fn new() -> PeekCommand < FileStorage > {
+        PeekCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > PeekCommand < S > {
+    /// Creates new instance of PeekCommand working with specified storage
+    pub fn with_storage(storage: S) -> PeekCommand < S > {
+        PeekCommand { storage: storage }
     }
 
     /// Used to execute peek command, specifying a river name and optionally offset to peek at
+    /// (see `Offset::from_legacy` for its numbering)
     pub fn execute(&self, river: &str, offset: Option < uint >) -> Option < PeekResult > {
-        River::new(river).peek_at(offset)
+        self.peek(river, offset).ok()
+    }
+
+    /// Same as `execute`, but tells why it was unable to peek a message
+    pub fn peek(&self, river: &str, offset: Option < uint >) -> Result < PeekResult, PeekError > {
+        self.read(river, Offset::from_legacy(offset)).map(|message| PeekResult {
+            message: message.payload,
+            offset: message.offset + 1
+        })
+    }
+
+    /// Same as `peek`, but skips messages not matching the filter, the result tells offset to
+    /// continue from
+    pub fn peek_matching(&self, river: &str, offset: Option < uint >, filter: &Filter) -> Result < PeekResult, PeekError > {
+        self.read_matching(river, Offset::from_legacy(offset), filter).map(|message| PeekResult {
+            message: message.payload,
+            offset: message.offset + 1
+        })
+    }
+
+    /// Reads message at the offset. Offset of the returned message is its position in the river
+    pub fn read(&self, river: &str, offset: Offset) -> Result < Message, PeekError > {
+        let message = try!(self.fetch(river, offset));
+
+        record_read(&self.storage, river, &message);
+        Ok(message)
+    }
+
+    /// Reads the first message matching the filter at the offset or after it. Messages rolled
+    /// back or expired are skipped, scanning stops at a message of a transaction in progress
+    pub fn read_matching(&self, river: &str, offset: Offset, filter: &Filter) -> Result < Message, PeekError > {
+        let mut position = try!(self.position(river, offset));
+        let last = cmp::min(self.storage.latest_offset(river), position + MAX_SCANNED - 1);
+
+        while position <= last {
+            match self.read_at(river, position) {
+                Ok(message) => if filter.matches(&message) {
+                    record_read(&self.storage, river, &message);
+                    return Ok(message)
+                },
+                // held back messages not matching the filter do not hold back the rest
+                Err(Delayed(deliver_at)) => if self.storage.read(river, position).map_or(false, |message| filter.matches(&message)) {
+                    return Err(Delayed(deliver_at))
+                },
+                Err(NoMessage) => break,
+                Err(_) => {}
+            }
+
+            position += 1;
+        }
+
+        Err(NoMatchingMessage(position))
+    }
+
+    fn position(&self, river: &str, offset: Offset) -> Result < uint, PeekError > {
+        let earliest_offset = self.storage.earliest_offset(river);
+        let latest_offset = self.storage.latest_offset(river);
+
+        let resolved = offset.resolve(earliest_offset, latest_offset);
+        match (offset, resolved) {
+            (At(position), _) if position < earliest_offset => Err(OffsetExpired(earliest_offset)),
+            (_, Some(position)) => Ok(position),
+            (_, None) => Err(NoMessage)
+        }
+    }
+
+    /// Same as `read`, but the message is not counted in metrics, as it is read on behalf of
+    /// the storage (e.g. by derived rivers and aggregations) rather than by a client
+    fn fetch(&self, river: &str, offset: Offset) -> Result < Message, PeekError > {
+        let position = try!(self.position(river, offset));
+
+        self.read_at(river, position)
+    }
+
+    fn read_at(&self, river: &str, position: uint) -> Result < Message, PeekError > {
+        match self.storage.read(river, position) {
+            Some(ref message) if message.is_delayed(now()) => Err(Delayed(message.deliver_at.unwrap())),
+            Some(ref message) if message.is_expired(now()) => Err(MessageExpired(message.expires_at.unwrap())),
+            Some(message) => self.committed(river, message),
+            None => Err(NoMessage)
+        }
+    }
+
+    /// Same as `read_at`, but reads delayed and expired messages as well, e.g. to export them
+    fn read_committed(&self, river: &str, position: uint) -> Result < Message, PeekError > {
+        match self.storage.read(river, position) {
+            Some(message) => self.committed(river, message),
+            None => Err(NoMessage)
+        }
+    }
+
+    /// The message of the river, unless its transaction is not committed
+    fn committed(&self, river: &str, message: Message) -> Result < Message, PeekError > {
+        match Transactions::of(&self.storage).status_of(river, &message, now()) {
+            Committed => Ok(message),
+            InProgress => Err(NoMessage),
+            Aborted => Err(RolledBack)
+        }
     }
 }
 
@@ -67,21 +417,1903 @@ impl PeekCommand {
 /// ```
 /// john::ClearCommand::new().execute("river_name");
 /// ```
-pub struct ClearCommand;
+pub struct ClearCommand < S > {
+    storage: S
+}
 
-impl ClearCommand {
+impl ClearCommand < FileStorage > {
     /// Constructor ::new()
     ///
     /// Creates new instance of ClearCommand
-    pub fn new() -> ClearCommand {
-        ClearCommand
+    pub fn new() -> ClearCommand < FileStorage > {
+        ClearCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ClearCommand < S > {
+    /// Creates new instance of ClearCommand working with specified storage
+    pub fn with_storage(storage: S) -> ClearCommand < S > {
+        ClearCommand { storage: storage }
     }
 
     /// Used to execute push command, specifying a river name and message
     /// This can be called multiple times with different arguments
     /// since PushCommand is stateless
     pub fn execute(&self, river: &str) {
-        River::new(river).destroy();
+        let _ = self.storage.destroy(river);
+    }
+}
+
+/// Truncate command - stateless
+///
+/// Used to discard old messages from rivers, keeping offsets of the rest unchanged:
+///
+/// ```
+/// // discard all messages with offsets less than 10
+/// john::TruncateCommand::new().execute("river_name", 10);
+/// ```
+///
+/// Peeking at discarded offsets results in `OffsetExpired` error
+pub struct TruncateCommand < S > {
+    storage: S
+}
+
+impl TruncateCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of TruncateCommand
+    pub fn new() -> TruncateCommand < FileStorage > {
+        TruncateCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > TruncateCommand < S > {
+    /// Creates new instance of TruncateCommand working with specified storage
+    pub fn with_storage(storage: S) -> TruncateCommand < S > {
+        TruncateCommand { storage: storage }
+    }
+
+    /// Used to execute truncate command, specifying a river name and the first offset to keep
+    pub fn execute(&self, river: &str, before: uint) {
+        let _ = self.storage.truncate(river, before);
+    }
+}
+
+/// Handle of purging started in background by PurgeExpiredCommand
+pub struct Purging {
+    stop: Sender < () >
+}
+
+impl Purging {
+    /// Stops purging before its next round
+    pub fn stop(self) {
+        let _ = self.stop.send_opt(());
+    }
+}
+
+/// Purge expired command - stateless
+///
+/// Used to physically remove messages, which outlived their time-to-live or retention of
+/// their river (see ConfigureRiverCommand):
+///
+/// ```
+/// john::PurgeExpiredCommand::new().execute("river_name");
+///
+/// // every river once a minute, until stopped
+/// let purging = john::PurgeExpiredCommand::new().start(60000);
+/// purging.stop();
+/// ```
+///
+/// Offsets of messages never change, so only expired messages at the head of the river
+/// are removed, up to the first message which is still alive. Server purges all its rivers
+/// in background.
+pub struct PurgeExpiredCommand < S > {
+    storage: S
+}
+
+impl PurgeExpiredCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of PurgeExpiredCommand
+    pub fn new() -> PurgeExpiredCommand < FileStorage > {
+        PurgeExpiredCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > PurgeExpiredCommand < S > {
+    /// Creates new instance of PurgeExpiredCommand working with specified storage
+    pub fn with_storage(storage: S) -> PurgeExpiredCommand < S > {
+        PurgeExpiredCommand { storage: storage }
+    }
+
+    /// Used to execute purge expired command, specifying a river name.
+    /// Returns number of removed messages
+    pub fn execute(&self, river: &str) -> uint {
+        let now = now();
+        let retention = load_config(self.storage.state(river, CONFIG_STATE)).retention;
+        let earliest_offset = self.storage.earliest_offset(river);
+        let mut offset = earliest_offset;
+
+        loop {
+            match self.storage.read(river, offset) {
+                Some(ref message) if message.is_expired(now) => offset += 1,
+                Some(ref message) if retention.map_or(false, |retention| message.timestamp + retention <= now) => offset += 1,
+                _ => break
+            }
+        }
+
+        if offset > earliest_offset && self.storage.truncate(river, offset).is_err() {
+            return 0
+        }
+
+        offset - earliest_offset
+    }
+
+    /// Purges every river of the storage, returns number of removed messages
+    pub fn execute_all(&self) -> uint {
+        self.storage.rivers().iter().fold(0, |purged, river| purged + self.execute(river.as_slice()))
+    }
+
+    /// Starts purging every river of the storage in background, once in the specified number
+    /// of milliseconds
+    pub fn start(&self, interval: u64) -> Purging {
+        let (stop, stopped) = channel();
+        let command = PurgeExpiredCommand::with_storage(self.storage.clone());
+
+        spawn(proc() {
+            while stopped.try_recv().is_err() {
+                command.execute_all();
+                timer::sleep(Duration::milliseconds(interval as i64));
+            }
+        });
+
+        Purging { stop: stop }
+    }
+}
+
+/// List rivers command - stateless
+///
+/// Used to discover existing rivers:
+///
+/// ```
+/// for info in john::ListRiversCommand::new().execute().iter() {
+///     println!("{}: {} messages", info.name, info.message_count);
+/// }
+/// ```
+pub struct ListRiversCommand < S > {
+    storage: S
+}
+
+impl ListRiversCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ListRiversCommand
+    pub fn new() -> ListRiversCommand < FileStorage > {
+        ListRiversCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ListRiversCommand < S > {
+    /// Creates new instance of ListRiversCommand working with specified storage
+    pub fn with_storage(storage: S) -> ListRiversCommand < S > {
+        ListRiversCommand { storage: storage }
+    }
+
+    /// Used to execute list rivers command. Returns info of all rivers sorted by name
+    pub fn execute(&self) -> Vec < RiverInfo > {
+        self.storage.rivers().iter()
+            .filter_map(|river| self.storage.info(river.as_slice()))
+            .collect()
+    }
+}
+
+/// River info command - stateless
+///
+/// Used to get info of a single river:
+///
+/// ```
+/// john::RiverInfoCommand::new().execute("river name");
+/// ```
+///
+/// It returns None when the river does not exist
+pub struct RiverInfoCommand < S > {
+    storage: S
+}
+
+impl RiverInfoCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of RiverInfoCommand
+    pub fn new() -> RiverInfoCommand < FileStorage > {
+        RiverInfoCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > RiverInfoCommand < S > {
+    /// Creates new instance of RiverInfoCommand working with specified storage
+    pub fn with_storage(storage: S) -> RiverInfoCommand < S > {
+        RiverInfoCommand { storage: storage }
+    }
+
+    /// Used to execute river info command, specifying a river name
+    pub fn execute(&self, river: &str) -> Option < RiverInfo > {
+        self.storage.info(river)
+    }
+}
+
+#[deriving(Encodable, Decodable, Clone)]
+/// Point-in-time copy of one river, part of the Snapshot
+pub struct RiverSnapshot {
+    /// Name of the river
+    pub name: String,
+    /// Next offset of the river at the moment of snapshot
+    pub offset: uint,
+    /// All messages of the river in order
+    pub messages: Vec < Message >,
+    /// States kept alongside the river by their kind, e.g. configuration and leases of the
+    /// queue. None in snapshots taken before they were included
+    pub states: Option < TreeMap < String, String > >
+}
+
+#[deriving(Encodable, Decodable, Clone)]
+/// Result of SnapshotCommand - point-in-time archive of all rivers
+///
+/// Can be encoded to JSON with `serialize::json::encode` and stored as a file
+pub struct Snapshot {
+    /// Snapshots of rivers sorted by name
+    pub rivers: Vec < RiverSnapshot >,
+    /// Transaction log, which tells whether messages pushed in transactions are visible.
+    /// None in snapshots taken before it was included
+    pub transactions: Option < String >,
+    /// States of the storage as a whole by their river and kind, e.g. the schema registry,
+    /// exchanges and progress of derived rivers. None in snapshots taken before they were included
+    pub states: Option < TreeMap < String, TreeMap < String, String > > >
+}
+
+/// Snapshot command - stateless
+///
+/// Used to take a consistent copy of all rivers while they are being pushed to:
+///
+/// ```
+/// let snapshot = john::SnapshotCommand::new().execute();
+/// ```
+///
+/// Rivers and their states are read while the storage is frozen (see `Storage::freeze`), so
+/// the snapshot contains all of them exactly as they were at that moment. Pushes, truncations
+/// and updates of states wait until it is taken.
+pub struct SnapshotCommand < S > {
+    storage: S
+}
+
+impl SnapshotCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of SnapshotCommand
+    pub fn new() -> SnapshotCommand < FileStorage > {
+        SnapshotCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > SnapshotCommand < S > {
+    /// Creates new instance of SnapshotCommand working with specified storage
+    pub fn with_storage(storage: S) -> SnapshotCommand < S > {
+        SnapshotCommand { storage: storage }
+    }
+
+    /// Used to execute snapshot command
+    pub fn execute(&self) -> Snapshot {
+        self.storage.freeze(|| {
+            let rivers = self.storage.rivers().into_iter().map(|river| {
+                let earliest_offset = self.storage.earliest_offset(river.as_slice());
+                let latest_offset = self.storage.latest_offset(river.as_slice());
+                let messages: Vec < Message > = range(earliest_offset, latest_offset + 1)
+                    .filter_map(|offset| self.storage.read(river.as_slice(), offset))
+                    .collect();
+
+                let mut states = TreeMap::new();
+                for kind in RIVER_STATES.iter() {
+                    match self.storage.state(river.as_slice(), *kind) {
+                        Some(state) => { states.insert(kind.to_string(), state); },
+                        None => {}
+                    }
+                }
+
+                RiverSnapshot {
+                    name: river,
+                    offset: latest_offset + 1,
+                    messages: messages,
+                    states: Some(states)
+                }
+            }).collect();
+
+            let mut states: TreeMap < String, TreeMap < String, String > > = TreeMap::new();
+            for &(river, kind) in STORAGE_STATES.iter() {
+                match self.storage.state(river, kind) {
+                    Some(state) => {
+                        if ! states.contains_key(&river.to_string()) {
+                            states.insert(river.to_string(), TreeMap::new());
+                        }
+                        states.find_mut(&river.to_string()).unwrap().insert(kind.to_string(), state);
+                    },
+                    None => {}
+                }
+            }
+
+            Snapshot {
+                rivers: rivers,
+                transactions: self.storage.state(TRANSACTIONS, TRANSACTIONS_STATE),
+                states: Some(states)
+            }
+        })
+    }
+}
+
+/// Restore command - stateless
+///
+/// Used to rebuild a data directory from a Snapshot:
+///
+/// ```
+/// let snapshot = john::SnapshotCommand::new().execute();
+/// let storage = john::FileStorage::in_root(&Path::new("./tmp/restored_rivers"));
+///
+/// john::RestoreCommand::with_storage(storage).execute(&snapshot).unwrap();
+/// ```
+///
+/// Messages are written at their offsets in the snapshot together with their metadata,
+/// regardless of quotas and schemas of the rivers.
+pub struct RestoreCommand < S > {
+    storage: S
+}
+
+impl RestoreCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of RestoreCommand
+    pub fn new() -> RestoreCommand < FileStorage > {
+        RestoreCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > RestoreCommand < S > {
+    /// Creates new instance of RestoreCommand working with specified storage
+    pub fn with_storage(storage: S) -> RestoreCommand < S > {
+        RestoreCommand { storage: storage }
+    }
+
+    /// Used to execute restore command, specifying a snapshot
+    ///
+    /// All rivers already present in the storage are destroyed. The transaction log and states
+    /// are replaced with the ones of the snapshot, unless the snapshot was taken without them.
+    /// Err tells why restoring stopped, e.g. when a message was not written at its offset
+    pub fn execute(&self, snapshot: &Snapshot) -> Result < (), String > {
+        for river in self.storage.rivers().iter() {
+            try!(self.storage.destroy(river.as_slice()));
+        }
+
+        match snapshot.transactions {
+            Some(ref log) => try!(self.storage.update_state(TRANSACTIONS, TRANSACTIONS_STATE, |_| log.clone())),
+            None => {}
+        }
+
+        match snapshot.states {
+            Some(ref states) => {
+                for &(river, _) in STORAGE_STATES.iter() {
+                    try!(self.storage.destroy(river));
+                }
+                for (river, kinds) in states.iter() {
+                    try!(self.restore_states(river.as_slice(), kinds));
+                }
+            },
+            None => {}
+        }
+
+        for river_snapshot in snapshot.rivers.iter() {
+            let river = river_snapshot.name.as_slice();
+            let earliest_offset = match river_snapshot.messages.as_slice().first() {
+                Some(message) => message.offset,
+                None => river_snapshot.offset
+            };
+
+            try!(self.storage.create(river));
+            try!(self.storage.truncate(river, earliest_offset));
+
+            for message in river_snapshot.messages.iter() {
+                match self.storage.write(river, message) {
+                    Ok(offset) if offset == message.offset => {},
+                    Ok(offset) => return Err(format!("Message {} of river {} was written at offset {}", message.offset, river, offset)),
+                    Err(err) => return Err(format!("Unable to write message {} of river {}: {}", message.offset, river, err))
+                }
+            }
+
+            match river_snapshot.states {
+                Some(ref states) => try!(self.restore_states(river, states)),
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restore_states(&self, river: &str, states: &TreeMap < String, String >) -> Result < (), String > {
+        for (kind, state) in states.iter() {
+            try!(self.storage.update_state(river, kind.as_slice(), |_| state.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Error of ImportCommand
+#[deriving(Show)]
+pub enum ImportError {
+    /// Unable to read the input
+    ReadFailed(io::IoError),
+    /// Line with the specified number (starting from 1) is not a valid JSON message
+    MalformedLine(uint)
+}
+
+/// Export command - stateless
+///
+/// Used to export messages of a river as newline-delimited JSON, one message per line:
+///
+/// ```
+/// // export whole river to stdout
+/// john::ExportCommand::new().execute("river name", None, None, &mut std::io::stdout());
+///
+/// // export messages with offsets from 2 to 5 inclusive
+/// john::ExportCommand::new().execute("river name", Some(2), Some(5), &mut std::io::stdout());
+/// ```
+///
+/// Every line contains offset, timestamp and payload of a message:
+/// `{"offset":2,"timestamp":1414000000000,"payload":"hello world"}`
+///
+/// Messages of committed transactions are exported as if they were pushed without
+/// a transaction, messages of transactions in progress and rolled back are skipped.
+pub struct ExportCommand < S > {
+    storage: S
+}
+
+impl ExportCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ExportCommand
+    pub fn new() -> ExportCommand < FileStorage > {
+        ExportCommand::with_storage(FileStorage::new())
     }
 }
 
+impl < S: Storage > ExportCommand < S > {
+    /// Creates new instance of ExportCommand working with specified storage
+    pub fn with_storage(storage: S) -> ExportCommand < S > {
+        ExportCommand { storage: storage }
+    }
+
+    /// Used to execute export command, specifying a river name, optional first and last offsets
+    /// (inclusive) and a writer. Returns number of exported messages
+    pub fn execute < W: Writer > (&self, river: &str, from: Option < uint >, to: Option < uint >, out: &mut W) -> io::IoResult < uint > {
+        let latest_offset = self.storage.latest_offset(river);
+        let last = match to {
+            Some(to) => cmp::min(to, latest_offset),
+            None => latest_offset
+        };
+        let mut exported = 0u;
+
+        let first = cmp::max(from.unwrap_or(1), self.storage.earliest_offset(river));
+        let peek = PeekCommand::with_storage(self.storage.clone());
+
+        for offset in range(first, last + 1) {
+            match peek.read_committed(river, offset) {
+                Ok(message) => {
+                    try!(out.write_line(json::encode(&Message { transaction: None, ..message }).as_slice()));
+                    exported += 1;
+                },
+                Err(_) => {}
+            }
+        }
+
+        Ok(exported)
+    }
+}
+
+/// Import command - stateless
+///
+/// Used to push messages exported by ExportCommand into a river:
+///
+/// ```
+/// let mut input = std::io::BufferedReader::new(std::io::File::open(&Path::new("river.jsonl")));
+/// john::ImportCommand::new().execute("river name", &mut input);
+/// ```
+///
+/// Messages get new offsets in the river, but keep their original timestamps. They are
+/// visible right away, even when exported with id of a transaction. Messages are pushed by
+/// PushCommand, so they are validated against the schema of the river and routed by its
+/// routing rules.
+pub struct ImportCommand < S > {
+    storage: S
+}
+
+impl ImportCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ImportCommand
+    pub fn new() -> ImportCommand < FileStorage > {
+        ImportCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ImportCommand < S > {
+    /// Creates new instance of ImportCommand working with specified storage
+    pub fn with_storage(storage: S) -> ImportCommand < S > {
+        ImportCommand { storage: storage }
+    }
+
+    /// Used to execute import command, specifying a river name and a buffered reader
+    /// Returns number of imported messages. Nothing is imported when any of lines is malformed,
+    /// messages rejected by the river (e.g. greater than its maximum message size or not
+    /// conforming to its schema) are skipped
+    pub fn execute < B: Buffer > (&self, river: &str, input: &mut B) -> Result < uint, ImportError > {
+        let mut messages = vec![];
+
+        for (number, line) in input.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Err(ReadFailed(err))
+            };
+
+            if line.as_slice().trim().is_empty() {
+                continue
+            }
+
+            match json::decode::< Message >(line.as_slice().trim()) {
+                Ok(message) => messages.push(Message { transaction: None, ..message }),
+                Err(_) => return Err(MalformedLine(number + 1))
+            }
+        }
+
+        let push = PushCommand::with_storage(self.storage.clone());
+
+        Ok(messages.iter().filter(|message| push.push(river, *message).is_ok()).count())
+    }
+}
+
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Result of ReceiveCommand - message leased to the consumer
+pub struct ReceiveResult {
+    /// Contains message
+    pub message: String,
+    /// Offset of the message, to be specified to AckCommand or NackCommand
+    pub offset: uint,
+    /// Identifies this delivery of the message, to be specified to AckCommand or NackCommand
+    pub receipt: u64,
+    /// Number of times the message was delivered, including this one
+    pub attempts: uint
+}
+
+/// Receive command - stateless
+///
+/// Used to consume a river as a work queue shared by many consumers:
+///
+/// ```
+/// // lease next message for 30 seconds
+/// match john::ReceiveCommand::new().execute("jobs", 30000) {
+///     Some(result) => {
+///         println!("processing {}", result.message);
+///         john::AckCommand::new().execute("jobs", result.offset, result.receipt);
+///     },
+///     None => println!("no messages available")
+/// }
+/// ```
+///
+/// Leased message is not delivered to other consumers until the visibility timeout passes.
+/// When it is not acknowledged by then, it is delivered again, before any newer message
+/// (of the same priority, when the river is a priority river, see ConfigurePriorityCommand).
+/// Messages failing too many times are moved to a dead-letter river, see
+/// ConfigureDeadLetterCommand.
+pub struct ReceiveCommand < S > {
+    storage: S
+}
+
+impl ReceiveCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ReceiveCommand
+    pub fn new() -> ReceiveCommand < FileStorage > {
+        ReceiveCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ReceiveCommand < S > {
+    /// Creates new instance of ReceiveCommand working with specified storage
+    pub fn with_storage(storage: S) -> ReceiveCommand < S > {
+        ReceiveCommand { storage: storage }
+    }
+
+    /// Used to execute receive command, specifying a river name and visibility timeout in
+    /// milliseconds. Returns None when every message is either acknowledged or leased
+    pub fn execute(&self, river: &str, visibility_timeout: u64) -> Option < ReceiveResult > {
+        let policy = load_policy(self.storage.state(river, DEAD_LETTER_STATE));
+        let prioritized = load_prioritized(self.storage.state(river, PRIORITY_STATE));
+        let mut transactions = Transactions::of(&self.storage);
+        let mut result = None;
+
+        // the message is not delivered, unless its lease is saved
+        let saved = self.storage.update_state(river, QUEUE_STATE, |state| {
+            let mut queue = Queue::load(state);
+
+            result = queue.receive(&self.storage, river, &policy, prioritized, &mut transactions, visibility_timeout, now())
+                .map(|(lease, message)| {
+                    record_read(&self.storage, river, &message);
+
+                    ReceiveResult {
+                        message: message.payload,
+                        offset: lease.offset,
+                        receipt: lease.receipt,
+                        attempts: lease.attempts
+                    }
+                });
+
+            queue.dump()
+        });
+
+        result.and_then(|result| saved.ok().map(|_| result))
+    }
+}
+
+/// Ack command - stateless
+///
+/// Used to tell that a message received with ReceiveCommand was processed:
+///
+/// ```
+/// john::AckCommand::new().execute("jobs", 3, 17);
+/// ```
+///
+/// Acknowledged message is never delivered again. Returns false when the lease has already
+/// expired and the message was delivered to another consumer (or acknowledged before).
+pub struct AckCommand < S > {
+    storage: S
+}
+
+impl AckCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of AckCommand
+    pub fn new() -> AckCommand < FileStorage > {
+        AckCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > AckCommand < S > {
+    /// Creates new instance of AckCommand working with specified storage
+    pub fn with_storage(storage: S) -> AckCommand < S > {
+        AckCommand { storage: storage }
+    }
+
+    /// Used to execute ack command, specifying a river name, offset and receipt of the delivery
+    pub fn execute(&self, river: &str, offset: uint, receipt: u64) -> bool {
+        let config = load_config(self.storage.state(river, CONFIG_STATE));
+        let mut acked = false;
+
+        let saved = self.storage.update_state(river, QUEUE_STATE, |state| {
+            let mut queue = Queue::load(state);
+            acked = queue.ack(offset, receipt);
+
+            // acked messages, which failed to be discarded, are discarded by the next ack
+            if acked && config.mode == QueueMode {
+                let _ = self.storage.truncate(river, queue.acked_before());
+            }
+
+            queue.dump()
+        });
+
+        acked && saved.is_ok()
+    }
+}
+
+/// Nack command - stateless
+///
+/// Used to give up a message received with ReceiveCommand, so it is delivered again
+/// right away instead of waiting for the visibility timeout:
+///
+/// ```
+/// john::NackCommand::new().execute("jobs", 3, 17);
+/// ```
+///
+/// Returns false when the lease has already expired or was acknowledged.
+pub struct NackCommand < S > {
+    storage: S
+}
+
+impl NackCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of NackCommand
+    pub fn new() -> NackCommand < FileStorage > {
+        NackCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > NackCommand < S > {
+    /// Creates new instance of NackCommand working with specified storage
+    pub fn with_storage(storage: S) -> NackCommand < S > {
+        NackCommand { storage: storage }
+    }
+
+    /// Used to execute nack command, specifying a river name, offset and receipt of the delivery
+    pub fn execute(&self, river: &str, offset: uint, receipt: u64) -> bool {
+        self.reject(river, offset, receipt, "nacked")
+    }
+
+    /// Same as `execute`, but records why processing of the message failed. The reason is kept
+    /// in metadata of the message, when it is moved to the dead-letter river, and is truncated to
+    /// 256 bytes
+    pub fn reject(&self, river: &str, offset: uint, receipt: u64, reason: &str) -> bool {
+        let policy = load_policy(self.storage.state(river, DEAD_LETTER_STATE));
+        let mut nacked = false;
+
+        let saved = self.storage.update_state(river, QUEUE_STATE, |state| {
+            let mut queue = Queue::load(state);
+            nacked = queue.nack(&self.storage, river, &policy, offset, receipt, reason);
+            queue.dump()
+        });
+
+        nacked && saved.is_ok()
+    }
+}
+
+/// Configure dead letter command - stateless
+///
+/// Used to park messages of a queue, which fail to be processed again and again:
+///
+/// ```
+/// // move messages of "jobs" failed 5 times to "jobs.dead"
+/// john::ConfigureDeadLetterCommand::new().execute("jobs", Some(john::DeadLetterPolicy {
+///     river: "jobs.dead".to_string(),
+///     max_attempts: 5
+/// })).unwrap();
+/// ```
+///
+/// Delivery fails when it is nacked or its visibility timeout expires. Moved message keeps
+/// its payload and timestamp, while its origin and the last failure reason are available in
+/// `dead_letter` metadata of the message.
+pub struct ConfigureDeadLetterCommand < S > {
+    storage: S
+}
+
+impl ConfigureDeadLetterCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureDeadLetterCommand
+    pub fn new() -> ConfigureDeadLetterCommand < FileStorage > {
+        ConfigureDeadLetterCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureDeadLetterCommand < S > {
+    /// Creates new instance of ConfigureDeadLetterCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureDeadLetterCommand < S > {
+        ConfigureDeadLetterCommand { storage: storage }
+    }
+
+    /// Used to execute configure dead letter command, specifying a river name and its policy.
+    /// None turns dead-lettering off, so failing messages are redelivered forever. Err tells
+    /// why the policy was not set, e.g. when it allows no deliveries
+    pub fn execute(&self, river: &str, policy: Option < DeadLetterPolicy >) -> Result < (), String > {
+        match policy {
+            Some(ref policy) if policy.max_attempts == 0 => return Err("max_attempts should be positive".to_string()),
+            _ => {}
+        }
+
+        self.storage.update_state(river, DEAD_LETTER_STATE, |_| dump_policy(&policy))
+    }
+}
+
+/// Replay dead letters command - stateless
+///
+/// Used to push messages of a dead-letter river back to the rivers they came from,
+/// once the reason of failures is fixed:
+///
+/// ```
+/// john::ReplayDeadLettersCommand::new().execute("jobs.dead");
+/// ```
+///
+/// Replayed messages get new offsets in their rivers and are discarded from the dead-letter
+/// river. Messages pushed to the dead-letter river directly have nowhere to go back to, they
+/// are kept and skipped by the following replays. Replay stops at a message rejected by its
+/// river (e.g. by a quota), it is replayed again by the next replay.
+///
+/// Offset of the next message to replay is saved after every message, and messages are pushed
+/// back with an idempotency key, so a replay interrupted by a crash continues where it stopped
+/// without pushing messages twice.
+pub struct ReplayDeadLettersCommand < S > {
+    storage: S
+}
+
+impl ReplayDeadLettersCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ReplayDeadLettersCommand
+    pub fn new() -> ReplayDeadLettersCommand < FileStorage > {
+        ReplayDeadLettersCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ReplayDeadLettersCommand < S > {
+    /// Creates new instance of ReplayDeadLettersCommand working with specified storage
+    pub fn with_storage(storage: S) -> ReplayDeadLettersCommand < S > {
+        ReplayDeadLettersCommand { storage: storage }
+    }
+
+    /// Used to execute replay dead letters command, specifying a dead-letter river name.
+    /// Returns number of replayed messages
+    pub fn execute(&self, dead_letter_river: &str) -> uint {
+        let push = PushCommand::with_storage(self.storage.clone());
+        let earliest_offset = self.storage.earliest_offset(dead_letter_river);
+        let latest_offset = self.storage.latest_offset(dead_letter_river);
+        let mut offset = cmp::max(earliest_offset, self.offset(dead_letter_river));
+        let mut replayed = 0u;
+
+        while offset <= latest_offset {
+            match self.storage.read(dead_letter_river, offset) {
+                Some(Message { dead_letter: Some(dead_letter), timestamp, payload, .. }) => {
+                    let message = Message { timestamp: timestamp, ..Message::new(payload.as_slice()) };
+                    let key = format!("replay of {} at {}", dead_letter_river, offset);
+
+                    match push.push_once(dead_letter.river.as_slice(), key.as_slice(), &message) {
+                        Ok(_) => replayed += 1,
+                        Err(_) => break
+                    }
+                },
+                _ => {}
+            }
+
+            offset += 1;
+
+            // replay stops where its progress is not saved, pushes of messages replayed again
+            // are deduplicated by their keys
+            if self.storage.update_state(dead_letter_river, REPLAY_STATE, |_| offset.to_string()).is_err() {
+                break
+            }
+        }
+
+        // replayed messages are discarded up to the first kept one
+        let mut before = earliest_offset;
+        while before < offset && self.storage.read(dead_letter_river, before).map_or(true, |message| message.dead_letter.is_some()) {
+            before += 1;
+        }
+
+        if before > earliest_offset {
+            let _ = self.storage.truncate(dead_letter_river, before);
+        }
+
+        replayed
+    }
+
+    /// Offset of the next message of the dead-letter river to replay
+    fn offset(&self, dead_letter_river: &str) -> uint {
+        self.storage.state(dead_letter_river, REPLAY_STATE)
+            .and_then(|state| from_str::< uint >(state.as_slice()))
+            .unwrap_or(0)
+    }
+}
+
+/// Configure priority command - stateless
+///
+/// Used to turn a river into a priority river:
+///
+/// ```
+/// john::ConfigurePriorityCommand::new().execute("jobs", true);
+///
+/// john::PushCommand::new().push("jobs", &john::Message {
+///     priority: Some(10),
+///     ..john::Message::new("urgent job")
+/// });
+/// ```
+///
+/// ReceiveCommand delivers pending messages of a priority river in order of their priorities,
+/// from the greatest to the least, and in order of offsets within the same priority. Peeks are
+/// not affected. Priority of messages in other rivers is ignored.
+pub struct ConfigurePriorityCommand < S > {
+    storage: S
+}
+
+impl ConfigurePriorityCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigurePriorityCommand
+    pub fn new() -> ConfigurePriorityCommand < FileStorage > {
+        ConfigurePriorityCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigurePriorityCommand < S > {
+    /// Creates new instance of ConfigurePriorityCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigurePriorityCommand < S > {
+        ConfigurePriorityCommand { storage: storage }
+    }
+
+    /// Used to execute configure priority command, specifying a river name and whether it is
+    /// a priority river
+    pub fn execute(&self, river: &str, prioritized: bool) {
+        let _ = self.storage.update_state(river, PRIORITY_STATE, |_| dump_prioritized(prioritized));
+    }
+}
+
+/// Configure quota command - stateless
+///
+/// Used to limit size of a river, or of all rivers together:
+///
+/// ```
+/// // at most 1000 messages in the river, make room for new ones
+/// john::ConfigureQuotaCommand::new().execute("logs", Some(john::Quota {
+///     max_bytes: None,
+///     max_messages: Some(1000),
+///     policy: john::DropOldest
+/// }));
+///
+/// // at most 1GB in all rivers, reject pushes beyond that
+/// john::ConfigureQuotaCommand::new().execute_for_storage(Some(john::Quota {
+///     max_bytes: Some(1 << 30),
+///     max_messages: None,
+///     policy: john::RejectPushes
+/// }));
+///
+/// john::PushCommand::new().push("orders", &john::Message::new("order 42"));  // => Err(StorageQuotaExceeded)
+/// ```
+///
+/// With DropOldest policy the oldest messages of the river pushed to are dropped, until the
+/// new message fits into the quota. Other rivers are never affected, so a push not fitting
+/// into quota of the storage even when its river is empty is rejected. Quotas are checked
+/// by the storage on every append, so they apply to messages moved to dead-letter rivers,
+/// replayed, imported and restored as well.
+pub struct ConfigureQuotaCommand < S > {
+    storage: S
+}
+
+impl ConfigureQuotaCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureQuotaCommand
+    pub fn new() -> ConfigureQuotaCommand < FileStorage > {
+        ConfigureQuotaCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureQuotaCommand < S > {
+    /// Creates new instance of ConfigureQuotaCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureQuotaCommand < S > {
+        ConfigureQuotaCommand { storage: storage }
+    }
+
+    /// Used to execute configure quota command, specifying a river name and its quota,
+    /// None removes the quota
+    pub fn execute(&self, river: &str, quota: Option < Quota >) {
+        let _ = self.storage.update_state(river, QUOTA_STATE, |_| dump_quota(&quota));
+    }
+
+    /// Same as `execute`, but configures quota of all rivers of the storage together
+    pub fn execute_for_storage(&self, quota: Option < Quota >) {
+        let _ = self.storage.update_state(STORAGE, QUOTA_STATE, |_| dump_quota(&quota));
+    }
+}
+
+/// Configure river command - stateless
+///
+/// Used to read and change configuration of a river:
+///
+/// ```
+/// let command = john::ConfigureRiverCommand::new();
+///
+/// let mut config = command.config("jobs");
+/// config.retention = Some(24 * 3600 * 1000);
+/// config.max_message_size = Some(1024);
+/// config.durable = true;
+/// config.mode = john::QueueMode;
+///
+/// command.execute("jobs", &config);
+/// ```
+///
+/// Maximum message size and retention are checked by pushes and commits. Durability and
+/// compression are honored by FileStorage, MemoryStorage keeps nothing on disk. Queue rivers
+/// drop messages as soon as they, and all the messages before them, are acknowledged.
+pub struct ConfigureRiverCommand < S > {
+    storage: S
+}
+
+impl ConfigureRiverCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureRiverCommand
+    pub fn new() -> ConfigureRiverCommand < FileStorage > {
+        ConfigureRiverCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureRiverCommand < S > {
+    /// Creates new instance of ConfigureRiverCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureRiverCommand < S > {
+        ConfigureRiverCommand { storage: storage }
+    }
+
+    /// Used to execute configure river command, specifying a river name and its configuration
+    pub fn execute(&self, river: &str, config: &RiverConfig) {
+        let _ = self.storage.update_state(river, CONFIG_STATE, |_| dump_config(config));
+    }
+
+    /// Reads configuration of the river
+    pub fn config(&self, river: &str) -> RiverConfig {
+        load_config(self.storage.state(river, CONFIG_STATE))
+    }
+}
+
+/// Error of RegisterSchemaCommand
+#[deriving(Show, PartialEq, Clone)]
+pub enum SchemaError {
+    /// Schema is not a JSON object or its name is too long, contains the reason
+    InvalidSchema(String),
+    /// Schema registry failed to be saved, contains the reason
+    SchemaNotSaved(String)
+}
+
+/// Register schema command - stateless
+///
+/// Used to add a new version of a JSON Schema to the schema registry:
+///
+/// ```
+/// let schema = r#"{"type":"object","required":["id"],"properties":{"id":{"type":"integer"}}}"#;
+///
+/// john::RegisterSchemaCommand::new().execute("orders", schema);     // => Ok(1)
+/// john::RegisterSchemaCommand::new().schema("orders", None);        // => Some((1, schema))
+/// ```
+///
+/// Versions of every schema start from 1, registering the same schema as the latest version
+/// does not add a new one. Supported keywords are type, enum, properties, required,
+/// additionalProperties, items, minimum, maximum, minLength, maxLength, minItems and
+/// maxItems, the rest are ignored.
+pub struct RegisterSchemaCommand < S > {
+    storage: S
+}
+
+impl RegisterSchemaCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of RegisterSchemaCommand
+    pub fn new() -> RegisterSchemaCommand < FileStorage > {
+        RegisterSchemaCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > RegisterSchemaCommand < S > {
+    /// Creates new instance of RegisterSchemaCommand working with specified storage
+    pub fn with_storage(storage: S) -> RegisterSchemaCommand < S > {
+        RegisterSchemaCommand { storage: storage }
+    }
+
+    /// Used to execute register schema command, specifying a name of the schema and its
+    /// source. Returns version of the schema. Names of schemas are kept by every message
+    /// validated against them, so they are at most 64 bytes long
+    pub fn execute(&self, name: &str, schema: &str) -> Result < uint, SchemaError > {
+        if name.len() > MAX_SCHEMA_NAME_LENGTH {
+            return Err(InvalidSchema(format!("schema name should be at most {} bytes", MAX_SCHEMA_NAME_LENGTH)))
+        }
+
+        match json::from_str(schema) {
+            Ok(ref parsed) if parsed.is_object() => {},
+            Ok(_) => return Err(InvalidSchema("schema should be an object".to_string())),
+            Err(err) => return Err(InvalidSchema(err.to_string()))
+        }
+
+        let mut version = 0;
+        let saved = self.storage.update_state(SCHEMAS, SCHEMAS_STATE, |state| {
+            let mut registry = Registry::load(state);
+            version = registry.register(name, schema);
+            registry.dump()
+        });
+
+        match saved {
+            Ok(_) => Ok(version),
+            Err(reason) => Err(SchemaNotSaved(reason))
+        }
+    }
+
+    /// Reads version of the schema together with its source, the latest one when version
+    /// is None
+    pub fn schema(&self, name: &str, version: Option < uint >) -> Option < (uint, String) > {
+        Registry::of(&self.storage).find(name, version)
+    }
+}
+
+/// Error of ConfigureRoutingCommand
+#[deriving(Show, PartialEq, Clone)]
+pub enum RoutingError {
+    /// Rule has a malformed filter or routes the river to itself, contains the reason
+    InvalidRule(String),
+    /// Rules failed to be saved, contains the reason
+    RulesNotSaved(String)
+}
+
+/// Configure routing command - stateless
+///
+/// Used to copy messages pushed to a river, which match a filter (see Filter), to other
+/// rivers:
+///
+/// ```
+/// john::ConfigureRoutingCommand::new().execute("payments", vec![john::RoutingRule {
+///     filter: "$.amount > 1000".to_string(),
+///     target: "fraud-review".to_string()
+/// }]);
+///
+/// john::PushCommand::new().execute("payments", r#"{"amount":5000}"#);   // copied to fraud-review
+/// ```
+///
+/// Rules are applied synchronously by pushes, idempotent pushes and commits to the river,
+/// once the message is appended to it. Copies are validated against schema and quotas of
+/// their target river, rejected copies are dropped. Rules of the target river are not
+/// applied to copies, so rules never loop.
+pub struct ConfigureRoutingCommand < S > {
+    storage: S
+}
+
+impl ConfigureRoutingCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureRoutingCommand
+    pub fn new() -> ConfigureRoutingCommand < FileStorage > {
+        ConfigureRoutingCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureRoutingCommand < S > {
+    /// Creates new instance of ConfigureRoutingCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureRoutingCommand < S > {
+        ConfigureRoutingCommand { storage: storage }
+    }
+
+    /// Used to execute configure routing command, specifying a river name and its rules,
+    /// which replace the previous ones. Empty list stops routing
+    pub fn execute(&self, river: &str, rules: Vec < RoutingRule >) -> Result < (), RoutingError > {
+        for rule in rules.iter() {
+            match Filter::parse(rule.filter.as_slice()) {
+                Ok(_) if rule.target.as_slice() == river => return Err(InvalidRule("rule should not route the river to itself".to_string())),
+                Ok(_) => {},
+                Err(reason) => return Err(InvalidRule(format!("{}: {}", rule.filter, reason)))
+            }
+        }
+
+        self.storage.update_state(river, ROUTING_STATE, |_| dump_rules(&rules)).map_err(RulesNotSaved)
+    }
+
+    /// Reads routing rules of the river
+    pub fn rules(&self, river: &str) -> Vec < RoutingRule > {
+        load_rules(self.storage.state(river, ROUTING_STATE))
+    }
+}
+
+/// Configure schema command - stateless
+///
+/// Used to bind a river to a schema from the schema registry:
+///
+/// ```
+/// john::ConfigureSchemaCommand::new().execute("orders", Some(john::SchemaBinding {
+///     schema: "orders".to_string(),
+///     version: None
+/// }));
+///
+/// john::PushCommand::new().push("orders", &john::Message::new(r#"{"id":42}"#));
+/// // => Ok(7), message.schema is Some(SchemaVersion { name: "orders", version: 1 })
+/// john::PushCommand::new().push("orders", &john::Message::new(r#"{"id":"42"}"#));
+/// // => Err(SchemaViolation("$.id should be of type \"integer\""))
+/// ```
+///
+/// Pushes and commits to the river are rejected, unless the payload is JSON conforming to
+/// the schema. Pushed messages record the version of the schema they were validated
+/// against. River bound to the latest version of a schema validates against the latest
+/// version registered at the time of the push.
+pub struct ConfigureSchemaCommand < S > {
+    storage: S
+}
+
+impl ConfigureSchemaCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureSchemaCommand
+    pub fn new() -> ConfigureSchemaCommand < FileStorage > {
+        ConfigureSchemaCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureSchemaCommand < S > {
+    /// Creates new instance of ConfigureSchemaCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureSchemaCommand < S > {
+        ConfigureSchemaCommand { storage: storage }
+    }
+
+    /// Used to execute configure schema command, specifying a river name and its schema,
+    /// None stops validating pushes to the river
+    pub fn execute(&self, river: &str, binding: Option < SchemaBinding >) {
+        let _ = self.storage.update_state(river, SCHEMA_STATE, |_| dump_binding(&binding));
+    }
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Push staged in a Transaction
+pub struct StagedPush {
+    /// River to push the message to
+    pub river: String,
+    /// Message to push
+    pub message: Message
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Pushes to several rivers, which are committed together with CommitCommand
+pub struct Transaction {
+    /// Staged pushes in order of staging
+    pub pushes: Vec < StagedPush >
+}
+
+impl Transaction {
+    /// Creates new transaction without pushes
+    pub fn new() -> Transaction {
+        Transaction { pushes: vec![] }
+    }
+
+    /// Stages push of the message to the river
+    pub fn push(&mut self, river: &str, message: &str) {
+        self.push_message(river, Message::new(message));
+    }
+
+    /// Stages push of the message with metadata to the river
+    pub fn push_message(&mut self, river: &str, message: Message) {
+        self.pushes.push(StagedPush {
+            river: river.to_string(),
+            message: message
+        });
+    }
+}
+
+/// Error of CommitCommand
+#[deriving(Show, PartialEq, Clone)]
+pub enum CommitError {
+    /// Commit took so long, that the transaction is considered aborted and its messages
+    /// are never visible to readers
+    TransactionTimedOut,
+    /// Some message was rejected (e.g. it did not fit into a quota or a schema), the
+    /// transaction was rolled back
+    Rejected(PushError),
+    /// Commit was not saved to the transaction log (e.g. the disk is full), the transaction
+    /// was rolled back
+    CommitNotSaved
+}
+
+/// Commit command - stateless
+///
+/// Used to push messages to several rivers together or not at all:
+///
+/// ```
+/// let mut transaction = john::Transaction::new();
+/// transaction.push("orders", "order 42 created");
+/// transaction.push("audit", "alice created order 42");
+///
+/// john::CommitCommand::new().execute(&transaction);    // => Ok(vec![7, 19])
+/// ```
+///
+/// Messages are appended to their rivers marked with id of the transaction, and become
+/// visible to peeks and queue consumers only once all of them are appended. Until then peeking
+/// at them results in `NoMessage` error. When a commit is interrupted (e.g. the server crashes)
+/// its messages are never visible, peeking at them results in `RolledBack` error. So are
+/// the messages appended before some message was rejected (e.g. did not fit into a quota).
+/// Queue consumers receive the messages following the ones of a transaction in progress,
+/// and those of the transaction once it is committed.
+///
+/// The transaction log keeps committed transactions until its compaction passes all their
+/// messages, while interrupted and rolled back ones are forgotten right away, as messages of
+/// unknown transactions are never visible.
+pub struct CommitCommand < S > {
+    storage: S
+}
+
+impl CommitCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of CommitCommand
+    pub fn new() -> CommitCommand < FileStorage > {
+        CommitCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > CommitCommand < S > {
+    /// Creates new instance of CommitCommand working with specified storage
+    pub fn with_storage(storage: S) -> CommitCommand < S > {
+        CommitCommand { storage: storage }
+    }
+
+    /// Used to execute commit command, specifying a transaction. Returns offsets of the
+    /// messages in order of staging
+    pub fn execute(&self, transaction: &Transaction) -> Result < Vec < uint >, CommitError > {
+        let messages: Result < Vec < Message >, PushError > = transaction.pushes.iter()
+            .map(|push| Validator::of(&self.storage, push.river.as_slice()).validate(&self.storage, &push.message))
+            .collect();
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(err) => return Err(Rejected(err))
+        };
+
+        let id = TransactionLog::new_id();
+
+        let begun = self.storage.update_state(TRANSACTIONS, TRANSACTIONS_STATE, |state| {
+            let mut log = TransactionLog::load(state);
+            log.compact(&self.storage, now());
+            log.begin(id.as_slice(), now());
+            log.dump()
+        });
+
+        if begun.is_err() {
+            return Err(CommitNotSaved)
+        }
+
+        let offsets: Result < Vec < uint >, PushError > = transaction.pushes.iter().zip(messages.iter()).map(|(push, message)| {
+            let message = Message {
+                transaction: Some(id.clone()),
+                ..message.clone()
+            };
+
+            self.storage.append(push.river.as_slice(), &message)
+        }).collect();
+
+        let last_offsets = match offsets {
+            Ok(ref offsets) => CommitCommand::<S>::last_offsets(transaction, offsets.as_slice()),
+            Err(_) => vec![]
+        };
+
+        let mut committed = false;
+        let saved = self.storage.update_state(TRANSACTIONS, TRANSACTIONS_STATE, |state| {
+            let mut log = TransactionLog::load(state);
+
+            committed = match offsets {
+                Ok(_) => log.commit(id.as_slice(), last_offsets.clone(), now()),
+                Err(_) => { log.abort(id.as_slice()); false }
+            };
+
+            log.dump()
+        });
+
+        if committed && (saved.is_err() || ! TransactionLog::of(&self.storage).is_committed(id.as_slice(), last_offsets.as_slice(), now())) {
+            return Err(CommitNotSaved)
+        }
+
+        match offsets {
+            Ok(offsets) => if committed {
+                let push = PushCommand::with_storage(self.storage.clone());
+
+                for ((staged, message), &offset) in transaction.pushes.iter().zip(messages.iter()).zip(offsets.iter()) {
+                    push.route(staged.river.as_slice(), message, offset);
+                }
+
+                Ok(offsets)
+            } else {
+                Err(TransactionTimedOut)
+            },
+            Err(err) => Err(Rejected(err))
+        }
+    }
+
+    /// Greatest offset of the messages of the transaction in every river
+    fn last_offsets(transaction: &Transaction, offsets: &[uint]) -> Vec < (String, uint) > {
+        let mut last_offsets: TreeMap < String, uint > = TreeMap::new();
+
+        for (push, &offset) in transaction.pushes.iter().zip(offsets.iter()) {
+            let last_offset = last_offsets.find(&push.river).map_or(offset, |&last_offset| cmp::max(offset, last_offset));
+            last_offsets.insert(push.river.clone(), last_offset);
+        }
+
+        last_offsets.iter().map(|(river, &offset)| (river.clone(), offset)).collect()
+    }
+}
+
+/// Configure exchange command - stateless
+///
+/// Used to declare exchanges and to bind rivers to them, see PublishCommand:
+///
+/// ```
+/// let command = john::ConfigureExchangeCommand::new();
+///
+/// command.execute("orders", Some(john::Exchange::new(john::TopicExchange)));
+/// command.bind("orders", "eu-orders", "orders.*.eu");
+/// command.bind("orders", "audit", "#");
+///
+/// command.execute("orders", None);  // removes the exchange
+/// ```
+pub struct ConfigureExchangeCommand < S > {
+    storage: S
+}
+
+impl ConfigureExchangeCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of ConfigureExchangeCommand
+    pub fn new() -> ConfigureExchangeCommand < FileStorage > {
+        ConfigureExchangeCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > ConfigureExchangeCommand < S > {
+    /// Creates new instance of ConfigureExchangeCommand working with specified storage
+    pub fn with_storage(storage: S) -> ConfigureExchangeCommand < S > {
+        ConfigureExchangeCommand { storage: storage }
+    }
+
+    /// Used to execute configure exchange command, specifying a name of the exchange and
+    /// the exchange with its bindings, None removes the exchange
+    pub fn execute(&self, name: &str, exchange: Option < Exchange >) {
+        let _ = self.storage.update_state(EXCHANGES, EXCHANGES_STATE, |state| {
+            let mut exchanges = load_exchanges(state);
+
+            match exchange {
+                Some(ref exchange) => { exchanges.insert(name.to_string(), exchange.clone()); },
+                None => { exchanges.remove(&name.to_string()); }
+            }
+
+            dump_exchanges(&exchanges)
+        });
+    }
+
+    /// Exchange with its bindings, None when there is no exchange with the name
+    pub fn exchange(&self, name: &str) -> Option < Exchange > {
+        load_exchanges(self.storage.state(EXCHANGES, EXCHANGES_STATE)).find(&name.to_string()).map(|exchange| exchange.clone())
+    }
+
+    /// Binds the river to the exchange with the routing key or pattern. Returns false when
+    /// there is no exchange with the name
+    pub fn bind(&self, name: &str, river: &str, key: &str) -> bool {
+        self.update(name, |exchange| exchange.bind(river, key))
+    }
+
+    /// Removes binding of the river to the exchange with the routing key or pattern. Returns
+    /// false when there is no exchange with the name
+    pub fn unbind(&self, name: &str, river: &str, key: &str) -> bool {
+        self.update(name, |exchange| exchange.unbind(river, key))
+    }
+
+    fn update(&self, name: &str, update: |&mut Exchange|) -> bool {
+        let mut found = false;
+
+        let saved = self.storage.update_state(EXCHANGES, EXCHANGES_STATE, |state| {
+            let mut exchanges = load_exchanges(state);
+
+            match exchanges.find_mut(&name.to_string()) {
+                Some(exchange) => {
+                    update(exchange);
+                    found = true;
+                },
+                None => {}
+            }
+
+            dump_exchanges(&exchanges)
+        });
+
+        found && saved.is_ok()
+    }
+}
+
+/// Error of PublishCommand
+#[deriving(Show, PartialEq, Clone)]
+pub enum PublishError {
+    /// There is no exchange with this name
+    NoExchange,
+    /// Message was not pushed to the rivers, it is appended to none of them
+    PublishRejected(CommitError)
+}
+
+/// Publish command - stateless
+///
+/// Used to push a message to every river bound to an exchange with a matching routing key
+/// (see ConfigureExchangeCommand):
+///
+/// ```
+/// john::PublishCommand::new().execute("orders", "orders.created.eu", "order 42 created");
+///
+/// john::PublishCommand::new().publish("orders", "orders.created.eu", &john::Message::new("order 42 created"));
+/// // => Ok(vec![("eu-orders".to_string(), 7), ("audit".to_string(), 19)])
+/// ```
+///
+/// Pushes to the rivers are committed together (see CommitCommand), so the message is
+/// appended either to all of them or to none, when some river rejects it. Message routed to
+/// no river is dropped.
+pub struct PublishCommand < S > {
+    storage: S
+}
+
+impl PublishCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of PublishCommand
+    pub fn new() -> PublishCommand < FileStorage > {
+        PublishCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > PublishCommand < S > {
+    /// Creates new instance of PublishCommand working with specified storage
+    pub fn with_storage(storage: S) -> PublishCommand < S > {
+        PublishCommand { storage: storage }
+    }
+
+    /// Used to execute publish command, specifying a name of the exchange, a routing key
+    /// and message
+    pub fn execute(&self, name: &str, key: &str, message: &str) {
+        let _ = self.publish(name, key, &Message::new(message));
+    }
+
+    /// Same as `execute`, but publishes message together with its metadata. Returns the
+    /// rivers the message was pushed to with its offset in each of them
+    pub fn publish(&self, name: &str, key: &str, message: &Message) -> Result < Vec < (String, uint) >, PublishError > {
+        let exchanges = load_exchanges(self.storage.state(EXCHANGES, EXCHANGES_STATE));
+        let rivers = match exchanges.find(&name.to_string()) {
+            Some(exchange) => exchange.route(key),
+            None => return Err(NoExchange)
+        };
+
+        if rivers.is_empty() {
+            return Ok(vec![])
+        }
+
+        let mut transaction = Transaction::new();
+        for river in rivers.iter() {
+            transaction.push_message(river.as_slice(), message.clone());
+        }
+
+        match CommitCommand::with_storage(self.storage.clone()).execute(&transaction) {
+            Ok(offsets) => Ok(rivers.iter().map(|river| river.clone()).zip(offsets.iter().map(|&offset| offset)).collect()),
+            Err(err) => Err(PublishRejected(err))
+        }
+    }
+}
+
+/// Handle of a river derived in background by DeriveRiverCommand
+pub struct DerivedRiver {
+    stop: Sender < () >
+}
+
+impl DerivedRiver {
+    /// Stops deriving the river after the message being processed. Progress is kept, so
+    /// deriving the river again continues where it stopped
+    pub fn stop(self) {
+        let _ = self.stop.send_opt(());
+    }
+}
+
+/// Derive river command - stateless
+///
+/// Used to derive a river from another one by a function, which maps, filters or flat-maps
+/// its messages:
+///
+/// ```
+/// fn is_large(message: &john::Message) -> bool {
+///     message.payload.len() > 100
+/// }
+///
+/// let large_orders = john::DeriveRiverCommand::new().execute("large orders", "orders", "large-orders", john::FilterTransform(is_large));
+///
+/// // later
+/// large_orders.stop();
+/// ```
+///
+/// The river is derived in background until stopped, dropping the handle leaves it running.
+/// The next input offset is kept under the name of the derived river once its outputs are
+/// pushed, so deriving continues after a restart (e.g. after a crash) where it stopped.
+/// The number of outputs pushed is kept too, so an input message processed again after a
+/// rejection pushes only its remaining outputs, the transform should therefore derive the
+/// same outputs every time. Only an output being pushed during a crash may be pushed twice.
+/// Outputs rejected for a while (e.g. by the quota of the output river) are retried until they
+/// are pushed, outputs rejected for good (e.g. by its schema) are moved to the dead-letter
+/// river of the output river, see ConfigureDeadLetterCommand, or dropped when it has none.
+pub struct DeriveRiverCommand < S > {
+    storage: S
+}
+
+impl DeriveRiverCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of DeriveRiverCommand
+    pub fn new() -> DeriveRiverCommand < FileStorage > {
+        DeriveRiverCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > DeriveRiverCommand < S > {
+    /// Creates new instance of DeriveRiverCommand working with specified storage
+    pub fn with_storage(storage: S) -> DeriveRiverCommand < S > {
+        DeriveRiverCommand { storage: storage }
+    }
+
+    /// Used to execute derive river command, specifying a name of the derived river, its
+    /// input and output rivers and the transform. Starts deriving the river in background
+    pub fn execute(&self, name: &str, input: &str, output: &str, transform: Transform) -> DerivedRiver {
+        let (stop, stopped) = channel();
+        let command = DeriveRiverCommand::with_storage(self.storage.clone());
+        let (name, input, output) = (name.to_string(), input.to_string(), output.to_string());
+
+        spawn(proc() {
+            while stopped.try_recv().is_err() {
+                match command.run(name.as_slice(), input.as_slice(), output.as_slice(), &transform) {
+                    Ok(0) | Err(_) => timer::sleep(Duration::milliseconds(POLL_INTERVAL)),
+                    Ok(_) => {}
+                }
+            }
+        });
+
+        DerivedRiver { stop: stop }
+    }
+
+    /// Derives messages from the input messages available now. Returns number of processed
+    /// input messages, Err when an output was rejected, the input message is processed
+    /// again by the next run, skipping its outputs pushed already
+    pub fn run(&self, name: &str, input: &str, output: &str, transform: &Transform) -> Result < uint, PushError > {
+        let push = PushCommand::with_storage(self.storage.clone());
+        let peek = PeekCommand::with_storage(self.storage.clone());
+        let mut offset = self.offset(name).unwrap_or(self.storage.earliest_offset(input));
+        let mut processed = 0;
+
+        loop {
+            match peek.fetch(input, At(offset)) {
+                Ok(message) => {
+                    let skipped = match load_pushed(self.storage.state(TRANSFORMS, PUSHED_STATE)).find(&name.to_string()) {
+                        Some(&(pushed_offset, pushed)) if pushed_offset == offset => pushed,
+                        _ => 0
+                    };
+
+                    for (index, derived) in transform.apply(&message).iter().enumerate().skip(skipped) {
+                        match push.push(output, derived) {
+                            Ok(_) => {},
+                            Err(err) => if rejected_for_good(&err) {
+                                dead_letter_output(&self.storage, output, derived, &err);
+                            } else {
+                                return Err(err)
+                            }
+                        }
+
+                        try!(self.storage.update_state(TRANSFORMS, PUSHED_STATE, |state| {
+                            let mut pushed = load_pushed(state);
+                            pushed.insert(name.to_string(), (offset, index + 1));
+                            dump_pushed(&pushed)
+                        }).map_err(WriteFailed));
+                    }
+
+                    processed += 1;
+                    offset += 1;
+                },
+                Err(OffsetExpired(earliest_offset)) => offset = earliest_offset,
+                Err(MessageExpired(_)) | Err(RolledBack) => offset += 1,
+                // caught up, or the next message is not readable yet
+                Err(_) => return Ok(processed)
+            }
+
+            try!(self.storage.update_state(TRANSFORMS, TRANSFORMS_STATE, |state| {
+                let mut offsets = load_offsets(state);
+                offsets.insert(name.to_string(), offset);
+                dump_offsets(&offsets)
+            }).map_err(WriteFailed));
+        }
+    }
+
+    /// Next input offset of the derived river, None when it was never derived
+    pub fn offset(&self, name: &str) -> Option < uint > {
+        load_offsets(self.storage.state(TRANSFORMS, TRANSFORMS_STATE)).find(&name.to_string()).map(|&offset| offset)
+    }
+}
+
+/// Tells whether the push is rejected every time it is retried
+fn rejected_for_good(err: &PushError) -> bool {
+    match *err {
+        MessageTooLarge | SchemaViolation(_) => true,
+        RiverQuotaExceeded | StorageQuotaExceeded | WriteFailed(_) => false
+    }
+}
+
+/// Moves the output rejected for good to the dead-letter river of the output river, the output
+/// is dropped when the output river has no dead-letter policy
+fn dead_letter_output < S: Storage > (storage: &S, output: &str, message: &Message, err: &PushError) {
+    let policy = match load_policy(storage.state(output, DEAD_LETTER_STATE)) {
+        Some(policy) => policy,
+        None => return
+    };
+
+    let reason = match *err {
+        SchemaViolation(ref reason) => reason.clone(),
+        _ => "message too large".to_string()
+    };
+
+    let _ = storage.append(policy.river.as_slice(), &Message {
+        dead_letter: Some(DeadLetter {
+            river: output.to_string(),
+            offset: 0,
+            reason: reason,
+            attempts: 1
+        }),
+        ..message.clone()
+    });
+}
+
+/// Aggregate command - stateless
+///
+/// Used to count and sum messages of a river per window of their timestamps, pushing
+/// results of closed windows to another river:
+///
+/// ```
+/// // per minute and region, summing totals of orders, counting orders late up to 10 seconds
+/// let aggregation = john::Aggregation::new(john::TumblingWindow(60000), Some("$.region"), Some("$.total"), 10000).unwrap();
+/// let orders_per_minute = john::AggregateCommand::new().execute("orders per minute", "orders", "orders-per-minute", aggregation);
+///
+/// // later
+/// orders_per_minute.stop();
+/// ```
+///
+/// Results are JSON objects like `{"count":3,"end":1414000060000,"key":"eu","start":1414000000000,"sum":127.5}`.
+/// Windows close grace milliseconds after their end, by timestamps of the aggregated messages
+/// or by the time passed since the latest of them when no messages arrive. Messages falling
+/// only into closed windows are dropped. Like derived rivers (see DeriveRiverCommand),
+/// aggregations run in background and keep their progress under their name. Progress is saved
+/// once per batch of input messages together with results of windows closed by the batch,
+/// results are then pushed one by one, so none is pushed twice unless pushed during a crash.
+/// Results rejected for good are moved to the dead-letter river of the output river, or
+/// dropped when it has none.
+pub struct AggregateCommand < S > {
+    storage: S
+}
+
+impl AggregateCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of AggregateCommand
+    pub fn new() -> AggregateCommand < FileStorage > {
+        AggregateCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > AggregateCommand < S > {
+    /// Creates new instance of AggregateCommand working with specified storage
+    pub fn with_storage(storage: S) -> AggregateCommand < S > {
+        AggregateCommand { storage: storage }
+    }
+
+    /// Used to execute aggregate command, specifying a name of the aggregation, its input
+    /// and output rivers and the aggregation. Starts aggregating in background
+    pub fn execute(&self, name: &str, input: &str, output: &str, aggregation: Aggregation) -> DerivedRiver {
+        let (stop, stopped) = channel();
+        let command = AggregateCommand::with_storage(self.storage.clone());
+        let (name, input, output) = (name.to_string(), input.to_string(), output.to_string());
+
+        spawn(proc() {
+            while stopped.try_recv().is_err() {
+                match command.run(name.as_slice(), input.as_slice(), output.as_slice(), &aggregation) {
+                    Ok(0) | Err(_) => timer::sleep(Duration::milliseconds(POLL_INTERVAL)),
+                    Ok(_) => {}
+                }
+            }
+        });
+
+        DerivedRiver { stop: stop }
+    }
+
+    /// Aggregates a batch of the input messages available now and pushes results of windows
+    /// closed since. Returns number of processed input messages, Err when a result was
+    /// rejected, it is pushed again by the next run, or when saved progress is unreadable
+    pub fn run(&self, name: &str, input: &str, output: &str, aggregation: &Aggregation) -> Result < uint, PushError > {
+        let peek = PeekCommand::with_storage(self.storage.clone());
+        let mut progress = try!(load_progress(self.storage.state(AGGREGATIONS, AGGREGATIONS_STATE)).map_err(WriteFailed))
+            .find(&name.to_string())
+            .map(|progress| progress.clone())
+            .unwrap_or(Progress::new());
+        try!(self.flush(name, output, &mut progress));
+
+        let mut offset = progress.offset.unwrap_or(self.storage.earliest_offset(input));
+        let mut processed = 0;
+
+        while processed < AGGREGATION_BATCH {
+            match peek.fetch(input, At(offset)) {
+                Ok(message) => {
+                    progress.tick(now());
+                    progress.add(aggregation, &message);
+                    processed += 1;
+                    offset += 1;
+                },
+                Err(OffsetExpired(earliest_offset)) => offset = earliest_offset,
+                Err(MessageExpired(_)) | Err(RolledBack) => offset += 1,
+                // caught up, or the next message is not readable yet
+                Err(_) => break
+            }
+        }
+
+        progress.offset = Some(offset);
+        progress.tick(now());
+        progress.close(aggregation);
+        try!(self.save(name, &progress));
+        try!(self.flush(name, output, &mut progress));
+
+        Ok(processed)
+    }
+
+    /// Pushes pending results one by one, saving progress after each of them. Results rejected
+    /// for good are moved to the dead-letter river of the output river or dropped
+    fn flush(&self, name: &str, output: &str, progress: &mut Progress) -> Result < (), PushError > {
+        let push = PushCommand::with_storage(self.storage.clone());
+
+        while !progress.pending.is_empty() {
+            let result = Message::new(progress.pending[0].as_slice());
+
+            match push.push(output, &result) {
+                Ok(_) => {},
+                Err(err) => if rejected_for_good(&err) {
+                    dead_letter_output(&self.storage, output, &result, &err);
+                } else {
+                    return Err(err)
+                }
+            }
+
+            progress.pending.remove(0);
+            try!(self.save(name, progress));
+        }
+
+        Ok(())
+    }
+
+    /// Saves progress of the aggregation, unless progress of aggregations became unreadable,
+    /// then it is kept as it was
+    fn save(&self, name: &str, progress: &Progress) -> Result < (), PushError > {
+        let mut loaded = Ok(());
+
+        try!(self.storage.update_state(AGGREGATIONS, AGGREGATIONS_STATE, |state| {
+            match load_progress(state.clone()) {
+                Ok(mut aggregations) => {
+                    aggregations.insert(name.to_string(), progress.clone());
+                    dump_progress(&aggregations)
+                },
+                Err(reason) => {
+                    loaded = Err(reason);
+                    state.unwrap_or(String::new())
+                }
+            }
+        }).map_err(WriteFailed));
+
+        loaded.map_err(WriteFailed)
+    }
+}
+
+/// Metrics command - stateless
+///
+/// Used to collect metrics of the storage and of its rivers in Prometheus text format like
+/// this:
+///
+/// ```
+/// let metrics = john::MetricsCommand::new().execute();
+/// ```
+///
+/// Counters of messages and bytes pushed and read by clients, and of rejected pushes by their
+/// reason are per river and cover all clones of the storage (all file storages with the same
+/// root). Gauges tell the number of messages and bytes, and the earliest and latest offsets of
+/// every river of the storage, they are taken at most once in 10 seconds. Latencies of requests
+/// to the server are histograms per route and method.
+pub struct MetricsCommand < S > {
+    storage: S
+}
+
+impl MetricsCommand < FileStorage > {
+    /// Constructor ::new()
+    ///
+    /// Creates new instance of MetricsCommand
+    pub fn new() -> MetricsCommand < FileStorage > {
+        MetricsCommand::with_storage(FileStorage::new())
+    }
+}
+
+impl < S: Storage > MetricsCommand < S > {
+    /// Creates new instance of MetricsCommand working with specified storage
+    pub fn with_storage(storage: S) -> MetricsCommand < S > {
+        MetricsCommand { storage: storage }
+    }
+
+    /// Used to execute metrics command, returns the metrics in Prometheus text format
+    pub fn execute(&self) -> String {
+        render(&self.storage)
+    }
+}
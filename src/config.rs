@@ -0,0 +1,54 @@
+use serialize::json;
+
+/// Kind of river state keeping configuration of the river
+pub const CONFIG_STATE: &'static str = "config";
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Tells how long messages of a river are kept
+pub enum RiverMode {
+    /// Messages are kept until truncated or dropped by retention or quota, so they can be
+    /// read again at any time
+    LogMode,
+    /// Messages are dropped as soon as they are acknowledged by queue consumers
+    QueueMode
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Configuration of a river, rivers which were never configured use `RiverConfig::new()`
+pub struct RiverConfig {
+    /// Time in milliseconds to keep messages for, older messages are dropped when appending
+    /// to the river and by PurgeExpiredCommand. Messages are kept forever when None
+    pub retention: Option < u64 >,
+    /// Whether every push is flushed to disk before it returns, truncations always are
+    pub durable: bool,
+    /// Maximum size of message payload in bytes
+    pub max_message_size: Option < uint >,
+    /// Whether payloads are compressed on disk
+    pub compressed: bool,
+    /// Whether the river is a log or a queue
+    pub mode: RiverMode
+}
+
+impl RiverConfig {
+    /// Configuration of a river, which was never configured: messages are kept forever,
+    /// in any size, without compression and flushing to disk
+    pub fn new() -> RiverConfig {
+        RiverConfig {
+            retention: None,
+            durable: false,
+            max_message_size: None,
+            compressed: false,
+            mode: LogMode
+        }
+    }
+}
+
+pub fn load_config(state: Option < String >) -> RiverConfig {
+    state
+        .and_then(|state| json::decode::< RiverConfig >(state.as_slice()).ok())
+        .unwrap_or(RiverConfig::new())
+}
+
+pub fn dump_config(config: &RiverConfig) -> String {
+    json::encode(config)
+}
@@ -0,0 +1,51 @@
+use serialize::json;
+
+/// Kind of river state keeping recent idempotency keys
+pub const DEDUP_STATE: &'static str = "dedup";
+
+/// Number of recent idempotency keys remembered per river
+const RECENT_KEYS: uint = 1000;
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Idempotency key of a pushed message together with its offset
+struct Key {
+    key: String,
+    offset: uint
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Idempotency keys of the latest pushes to a river, the oldest first
+pub struct Dedup {
+    keys: Vec < Key >
+}
+
+impl Dedup {
+    pub fn load(state: Option < String >) -> Dedup {
+        state
+            .and_then(|state| json::decode::< Dedup >(state.as_slice()).ok())
+            .unwrap_or(Dedup { keys: vec![] })
+    }
+
+    pub fn dump(&self) -> String {
+        json::encode(self)
+    }
+
+    /// Offset of the message pushed with this key, unless it was forgotten already
+    pub fn find(&self, key: &str) -> Option < uint > {
+        self.keys.iter()
+            .find(|known| known.key.as_slice() == key)
+            .map(|known| known.offset)
+    }
+
+    /// Remembers the key, forgetting the oldest one when there are too many
+    pub fn remember(&mut self, key: &str, offset: uint) {
+        if self.keys.len() >= RECENT_KEYS {
+            self.keys.remove(0);
+        }
+
+        self.keys.push(Key {
+            key: key.to_string(),
+            offset: offset
+        });
+    }
+}
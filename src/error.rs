@@ -0,0 +1,17 @@
+/// Error of PushCommand, when the message was not appended to the river
+#[deriving(Show, PartialEq, Clone)]
+pub enum PushError {
+    /// Quota of the river is exceeded, see ConfigureQuotaCommand
+    RiverQuotaExceeded,
+    /// Quota of all rivers of the storage is exceeded
+    StorageQuotaExceeded,
+    /// Payload is greater than maximum message size of the river, see ConfigureRiverCommand
+    MessageTooLarge,
+    /// Payload does not conform to the schema of the river, contains the reason
+    SchemaViolation(String),
+    /// Storage failed to write the message (e.g. the disk is full), contains the reason
+    WriteFailed(String)
+}
+
+/// Name of PushError before pushes were validated against schemas, kept for compatibility
+pub type QuotaError = PushError;
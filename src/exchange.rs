@@ -0,0 +1,111 @@
+use std::collections::TreeMap;
+
+use serialize::json;
+
+/// Pseudo-river keeping exchanges as its state, hidden from the list of rivers
+pub const EXCHANGES: &'static str = ".exchanges";
+
+/// Kind of state of EXCHANGES pseudo-river keeping every exchange by its name
+pub const EXCHANGES_STATE: &'static str = "exchanges";
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Tells how an exchange picks the rivers a message is pushed to by its routing key
+pub enum ExchangeKind {
+    /// Rivers bound with the very same key as the routing key
+    DirectExchange,
+    /// Every bound river, regardless of the routing key
+    FanoutExchange,
+    /// Rivers bound with a pattern matching the routing key. Both are split into words by
+    /// dots, `*` in the pattern matches exactly one word, `#` matches zero or more words,
+    /// e.g. `orders.*.eu` matches `orders.created.eu`, and `orders.#` matches all of them
+    TopicExchange
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Binding of a river to an exchange
+pub struct ExchangeBinding {
+    /// River to push messages to
+    pub river: String,
+    /// Routing key or pattern (see ExchangeKind), ignored by fanout exchanges
+    pub key: String
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Named router of messages to the rivers bound to it
+pub struct Exchange {
+    /// How the rivers are picked
+    pub kind: ExchangeKind,
+    /// Rivers bound to the exchange
+    pub bindings: Vec < ExchangeBinding >
+}
+
+impl Exchange {
+    /// Creates new exchange without bindings
+    pub fn new(kind: ExchangeKind) -> Exchange {
+        Exchange {
+            kind: kind,
+            bindings: vec![]
+        }
+    }
+
+    /// Binds the river with the key, unless it is bound with it already
+    pub fn bind(&mut self, river: &str, key: &str) {
+        let binding = ExchangeBinding { river: river.to_string(), key: key.to_string() };
+
+        if ! self.bindings.contains(&binding) {
+            self.bindings.push(binding);
+        }
+    }
+
+    /// Removes binding of the river with the key
+    pub fn unbind(&mut self, river: &str, key: &str) {
+        self.bindings.retain(|binding| binding.river.as_slice() != river || binding.key.as_slice() != key);
+    }
+
+    /// Rivers to push a message with the routing key to, in order of binding. River bound
+    /// with several matching keys is listed once
+    pub fn route(&self, key: &str) -> Vec < String > {
+        let words: Vec < &str > = key.split('.').collect();
+        let mut rivers = vec![];
+
+        for binding in self.bindings.iter() {
+            let matches = match self.kind {
+                DirectExchange => binding.key.as_slice() == key,
+                FanoutExchange => true,
+                TopicExchange => {
+                    let pattern: Vec < &str > = binding.key.as_slice().split('.').collect();
+                    matches_topic(pattern.as_slice(), words.as_slice())
+                }
+            };
+
+            if matches && ! rivers.contains(&binding.river) {
+                rivers.push(binding.river.clone());
+            }
+        }
+
+        rivers
+    }
+}
+
+fn matches_topic(pattern: &[&str], words: &[&str]) -> bool {
+    if pattern.is_empty() {
+        return words.is_empty()
+    }
+
+    match pattern[0] {
+        "#" => matches_topic(pattern.slice_from(1), words) ||
+            (! words.is_empty() && matches_topic(pattern, words.slice_from(1))),
+        "*" => ! words.is_empty() && matches_topic(pattern.slice_from(1), words.slice_from(1)),
+        word => ! words.is_empty() && words[0] == word && matches_topic(pattern.slice_from(1), words.slice_from(1))
+    }
+}
+
+pub fn load_exchanges(state: Option < String >) -> TreeMap < String, Exchange > {
+    state
+        .and_then(|state| json::decode::< TreeMap < String, Exchange > >(state.as_slice()).ok())
+        .unwrap_or(TreeMap::new())
+}
+
+pub fn dump_exchanges(exchanges: &TreeMap < String, Exchange >) -> String {
+    json::encode(exchanges)
+}
@@ -0,0 +1,417 @@
+use std::cmp;
+use std::fmt;
+use std::from_str::FromStr;
+
+use serialize::json;
+use serialize::json::Json;
+
+use storage::Message;
+
+/// Maximum number of nested parentheses and negations in a filter
+pub const MAX_DEPTH: uint = 32;
+
+#[deriving(Clone, PartialEq)]
+/// Predicate on messages evaluated by the broker, so readers get only the messages they need
+///
+/// Filter compares fields of a message to JSON literals, e.g. `$.type == "order.created"`.
+/// Fields are `payload`, `offset`, `timestamp`, `priority`, `schema` (name of the schema the
+/// message was validated against) and paths into JSON payload like `$.order.lines[0].sku`.
+/// Operators are `==` (or `=`), `!=`, `<`, `<=`, `>`, `>=` and `contains`, which looks for a
+/// substring of a string or an element of a list. Comparisons are combined with `and`, `or`,
+/// `not` and parentheses:
+///
+/// ```
+/// let filter = john::Filter::parse(r#"$.type == "order.created" and not $.region = "eu""#).unwrap();
+///
+/// assert!(filter.matches(&john::Message::new(r#"{"type":"order.created","region":"us"}"#)));
+/// ```
+///
+/// Comparison of a field the message does not have, e.g. a path into a payload which is not
+/// JSON, is false, unless the operator is `!=`. Parentheses and negations are nested at most
+/// 32 levels deep.
+pub struct Filter {
+    expression: Expression,
+    source: String
+}
+
+#[deriving(Clone, PartialEq)]
+enum Expression {
+    Conjunction(Box < Expression >, Box < Expression >),
+    Disjunction(Box < Expression >, Box < Expression >),
+    Negation(Box < Expression >),
+    Compare(Field, Operator, Json)
+}
+
+#[deriving(Clone, PartialEq)]
+/// Field of a message compared by filters, also used to group and sum messages by aggregations
+pub enum Field {
+    JsonPath(Vec < Segment >),
+    Payload,
+    MessageOffset,
+    Timestamp,
+    Priority,
+    Schema
+}
+
+#[deriving(Clone, PartialEq)]
+/// Step of a path into JSON payload
+pub enum Segment {
+    Key(String),
+    Position(uint)
+}
+
+#[deriving(Clone, PartialEq)]
+enum Operator {
+    IsEqual,
+    IsNotEqual,
+    IsLess,
+    IsLessOrEqual,
+    IsGreater,
+    IsGreaterOrEqual,
+    Contains
+}
+
+impl Filter {
+    /// Parses filter expression, Err tells what is wrong with it
+    pub fn parse(source: &str) -> Result < Filter, String > {
+        let mut parser = Parser::new(source);
+        let expression = try!(parser.or());
+
+        match parser.peek() {
+            None => Ok(Filter { expression: expression, source: source.to_string() }),
+            Some(_) => Err(parser.unexpected())
+        }
+    }
+
+    /// Tells whether the message matches the filter
+    pub fn matches(&self, message: &Message) -> bool {
+        let payload = json::from_str(message.payload.as_slice()).ok();
+
+        self.expression.matches(message, &payload)
+    }
+}
+
+impl FromStr for Filter {
+    fn from_str(source: &str) -> Option < Filter > {
+        Filter::parse(source).ok()
+    }
+}
+
+impl fmt::Show for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Expression {
+    fn matches(&self, message: &Message, payload: &Option < Json >) -> bool {
+        match *self {
+            Conjunction(ref left, ref right) => left.matches(message, payload) && right.matches(message, payload),
+            Disjunction(ref left, ref right) => left.matches(message, payload) || right.matches(message, payload),
+            Negation(ref expression) => ! expression.matches(message, payload),
+            Compare(ref field, ref operator, ref literal) => match field.value(message, payload) {
+                Some(value) => operator.holds(&value, literal),
+                None => *operator == IsNotEqual
+            }
+        }
+    }
+}
+
+/// Parses a field of messages, e.g. `priority` or `$.order.region`
+pub fn parse_field(source: &str) -> Result < Field, String > {
+    let mut parser = Parser::new(source);
+    parser.peek();
+    let field = try!(parser.field());
+
+    match parser.peek() {
+        None => Ok(field),
+        Some(_) => Err(parser.unexpected())
+    }
+}
+
+impl Field {
+    /// Value of the field in the message, None when the message does not have it
+    pub fn value_of(&self, message: &Message) -> Option < Json > {
+        self.value(message, &json::from_str(message.payload.as_slice()).ok())
+    }
+
+    fn value(&self, message: &Message, payload: &Option < Json >) -> Option < Json > {
+        match *self {
+            JsonPath(ref segments) => {
+                let mut value = match *payload {
+                    Some(ref payload) => payload,
+                    None => return None
+                };
+
+                for segment in segments.iter() {
+                    let next = match *segment {
+                        Key(ref key) => value.as_object().and_then(|object| object.find(key)),
+                        Position(index) => value.as_list().and_then(|list| list.as_slice().get(index))
+                    };
+
+                    value = match next {
+                        Some(next) => next,
+                        None => return None
+                    };
+                }
+
+                Some(value.clone())
+            },
+            Payload => Some(json::String(message.payload.clone())),
+            MessageOffset => Some(json::U64(message.offset as u64)),
+            Timestamp => Some(json::U64(message.timestamp)),
+            Priority => message.priority.map(|priority| json::U64(priority as u64)),
+            Schema => message.schema.as_ref().map(|schema| json::String(schema.name.clone()))
+        }
+    }
+}
+
+impl Operator {
+    fn holds(&self, value: &Json, literal: &Json) -> bool {
+        match *self {
+            IsEqual => equals(value, literal),
+            IsNotEqual => ! equals(value, literal),
+            Contains => match (value, literal) {
+                (&json::String(ref value), &json::String(ref part)) => value.as_slice().contains(part.as_slice()),
+                (&json::List(ref values), _) => values.iter().any(|value| equals(value, literal)),
+                _ => false
+            },
+            _ => compare(value, literal).map_or(false, |ordering| match *self {
+                IsLess => ordering == Less,
+                IsLessOrEqual => ordering != Greater,
+                IsGreater => ordering == Greater,
+                _ => ordering != Less
+            })
+        }
+    }
+}
+
+/// Numbers are equal regardless of their representation, e.g. `1` and `1.0`
+pub fn equals(value: &Json, literal: &Json) -> bool {
+    match (value.as_f64(), literal.as_f64()) {
+        (Some(value), Some(literal)) => value == literal,
+        _ => value == literal
+    }
+}
+
+/// Only numbers and strings are ordered
+fn compare(value: &Json, literal: &Json) -> Option < Ordering > {
+    match (value.as_f64(), literal.as_f64(), value.as_string(), literal.as_string()) {
+        (Some(value), Some(literal), _, _) => value.partial_cmp(&literal),
+        (_, _, Some(value), Some(literal)) => Some(value.cmp(&literal)),
+        _ => None
+    }
+}
+
+/// Recursive descent parser of filter expressions, `or` binds weaker than `and`, which binds
+/// weaker than `not`
+struct Parser {
+    chars: Vec < char >,
+    position: uint,
+    depth: uint
+}
+
+impl Parser {
+    fn new(source: &str) -> Parser {
+        Parser {
+            chars: source.chars().collect(),
+            position: 0,
+            depth: 0
+        }
+    }
+
+    fn or(&mut self) -> Result < Expression, String > {
+        let mut expression = try!(self.and());
+
+        while self.keyword("or") {
+            expression = Disjunction(box expression, box try!(self.and()));
+        }
+
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result < Expression, String > {
+        let mut expression = try!(self.not());
+
+        while self.keyword("and") {
+            expression = Conjunction(box expression, box try!(self.not()));
+        }
+
+        Ok(expression)
+    }
+
+    fn not(&mut self) -> Result < Expression, String > {
+        if self.keyword("not") {
+            try!(self.descend());
+            let expression = try!(self.not());
+            self.depth -= 1;
+
+            return Ok(Negation(box expression))
+        }
+
+        if self.peek() == Some('(') {
+            self.position += 1;
+            try!(self.descend());
+            let expression = try!(self.or());
+            self.depth -= 1;
+
+            if self.peek() != Some(')') {
+                return Err(self.unexpected())
+            }
+
+            self.position += 1;
+            return Ok(expression)
+        }
+
+        let field = try!(self.field());
+        let operator = try!(self.operator());
+        let literal = try!(self.literal());
+
+        Ok(Compare(field, operator, literal))
+    }
+
+    /// Enters nested expression, Err when it is nested too deep
+    fn descend(&mut self) -> Result < (), String > {
+        self.depth += 1;
+
+        if self.depth > MAX_DEPTH {
+            Err(format!("filter should be nested at most {} levels deep", MAX_DEPTH))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn field(&mut self) -> Result < Field, String > {
+        if self.peek() != Some('$') {
+            return match self.word().as_slice() {
+                "payload" => Ok(Payload),
+                "offset" => Ok(MessageOffset),
+                "timestamp" => Ok(Timestamp),
+                "priority" => Ok(Priority),
+                "schema" => Ok(Schema),
+                "" => Err(self.unexpected()),
+                word => Err(format!("unknown field {}", word))
+            }
+        }
+
+        self.position += 1;
+        let mut segments = vec![];
+
+        loop {
+            match self.current() {
+                Some('.') => {
+                    self.position += 1;
+
+                    match self.word() {
+                        ref key if key.is_empty() => return Err(self.unexpected()),
+                        key => segments.push(Key(key))
+                    }
+                },
+                Some('[') => {
+                    self.position += 1;
+
+                    match from_str::< uint >(self.word().as_slice()) {
+                        Some(index) if self.current() == Some(']') => {
+                            self.position += 1;
+                            segments.push(Position(index));
+                        },
+                        _ => return Err(self.unexpected())
+                    }
+                },
+                _ => return Ok(JsonPath(segments))
+            }
+        }
+    }
+
+    fn operator(&mut self) -> Result < Operator, String > {
+        let operators = [
+            ("==", IsEqual), ("!=", IsNotEqual), ("<=", IsLessOrEqual), (">=", IsGreaterOrEqual),
+            ("=", IsEqual), ("<", IsLess), (">", IsGreater)
+        ];
+
+        self.peek();
+        for &(symbol, operator) in operators.iter() {
+            if self.follows(symbol) {
+                self.position += symbol.len();
+                return Ok(operator)
+            }
+        }
+
+        if self.keyword("contains") {
+            Ok(Contains)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    /// JSON literal: a string, a number, true, false or null
+    fn literal(&mut self) -> Result < Json, String > {
+        let start = self.position;
+
+        if self.peek() == Some('"') {
+            self.position += 1;
+
+            while self.position < self.chars.len() && self.chars[self.position] != '"' {
+                self.position += if self.chars[self.position] == '\\' { 2 } else { 1 };
+            }
+
+            self.position += 1;
+        } else {
+            while self.current().map_or(false, |c| ! c.is_whitespace() && c != ')') {
+                self.position += 1;
+            }
+        }
+
+        let end = cmp::min(self.position, self.chars.len());
+        let source: String = self.chars.slice(start, end).iter().map(|&c| c).collect();
+
+        json::from_str(source.as_slice().trim()).map_err(|_| format!("{} is not a JSON literal", source.as_slice().trim()))
+    }
+
+    /// Consumes the keyword, when it is the next word
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let start = self.position;
+
+        self.peek();
+        if self.word().as_slice() == keyword {
+            true
+        } else {
+            self.position = start;
+            false
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.position;
+
+        while self.current().map_or(false, |c| c.is_alphanumeric() || c == '_' || c == '-') {
+            self.position += 1;
+        }
+
+        self.chars.slice(start, self.position).iter().map(|&c| c).collect()
+    }
+
+    fn follows(&self, symbol: &str) -> bool {
+        symbol.chars().enumerate().all(|(index, c)| self.chars.as_slice().get(self.position + index) == Some(&c))
+    }
+
+    /// Next character after whitespace, which is skipped
+    fn peek(&mut self) -> Option < char > {
+        while self.current().map_or(false, |c| c.is_whitespace()) {
+            self.position += 1;
+        }
+
+        self.current()
+    }
+
+    fn current(&self) -> Option < char > {
+        self.chars.as_slice().get(self.position).map(|&c| c)
+    }
+
+    fn unexpected(&self) -> String {
+        match self.current() {
+            Some(c) => format!("unexpected {} at {}", c, self.position),
+            None => "unexpected end of filter".to_string()
+        }
+    }
+}
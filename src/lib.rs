@@ -11,12 +11,61 @@
 //!
 //! It can be used as a standalone library or as a messaging queue server.
 
+extern crate flate;
 extern crate serialize;
+extern crate time;
 
 pub use server::Server;
 pub use commands::{ClearCommand, PushCommand, PeekCommand, PeekResult};
+pub use commands::{PushResult, Appended, Duplicate};
+pub use commands::{TruncateCommand, PeekError, NoMessage, OffsetExpired, Delayed, MessageExpired};
+pub use commands::{PurgeExpiredCommand, Purging, RolledBack};
+pub use commands::{Filter, NoMatchingMessage};
+pub use commands::{DeriveRiverCommand, DerivedRiver, Transform, MapTransform, FilterTransform, FlatMapTransform};
+pub use commands::{AggregateCommand, Aggregation, Window, TumblingWindow, SlidingWindow};
+pub use commands::{ConfigureRoutingCommand, RoutingRule, RoutingError, InvalidRule, RulesNotSaved};
+pub use commands::{CommitCommand, Transaction, StagedPush, CommitError, TransactionTimedOut, Rejected, CommitNotSaved};
+pub use commands::{ListRiversCommand, RiverInfoCommand, RiverInfo};
+pub use commands::{SnapshotCommand, RestoreCommand, Snapshot, RiverSnapshot};
+pub use commands::{ExportCommand, ImportCommand, Message, ImportError, ReadFailed, MalformedLine};
+pub use commands::{ReceiveCommand, AckCommand, NackCommand, ReceiveResult};
+pub use commands::{ConfigureDeadLetterCommand, ReplayDeadLettersCommand, DeadLetterPolicy};
+pub use commands::ConfigurePriorityCommand;
+pub use commands::{ConfigureQuotaCommand, Quota, QuotaPolicy, RejectPushes, DropOldest};
+pub use commands::{PushError, RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge, SchemaViolation, WriteFailed};
+pub use commands::QuotaError;
+pub use commands::{RegisterSchemaCommand, ConfigureSchemaCommand, SchemaBinding, SchemaVersion};
+pub use commands::{SchemaError, InvalidSchema, SchemaNotSaved};
+pub use commands::{ConfigureExchangeCommand, Exchange, ExchangeBinding, ExchangeKind};
+pub use commands::{DirectExchange, FanoutExchange, TopicExchange};
+pub use commands::{PublishCommand, PublishError, NoExchange, PublishRejected};
+pub use commands::MetricsCommand;
+pub use commands::{ConfigureRiverCommand, RiverConfig, RiverMode, LogMode, QueueMode};
+pub use offset::{Offset, Earliest, Latest, At, FromEarliest, FromLatest};
+pub use cluster::{Cluster, ClusterStorage, ClusterError, NoLeader, NoQuorum};
+pub use storage::{Storage, FileStorage, DeadLetter, TornWrite, Usage, now};
+pub use memory::MemoryStorage;
+pub use schema::SchemaCache;
+pub use metrics::Metrics;
 
+mod error;
+mod storage;
+mod offset;
+mod memory;
 mod river;
+mod queue;
+mod dedup;
+mod transaction;
+mod quota;
+mod config;
+mod schema;
+mod filter;
+mod routing;
+mod transform;
+mod aggregation;
+mod exchange;
+mod metrics;
 mod server;
 mod commands;
+mod cluster;
 
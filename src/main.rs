@@ -1,8 +1,12 @@
 extern crate john;
+extern crate serialize;
 
-use std::os::getenv;
+use std::io;
+use std::os::{args, getenv};
 use std::io::net::ip::Port;
 
+use serialize::json;
+
 #[cfg(not(test))]
 /// Look up our server port number in PORT, for compatibility with Heroku.
 fn get_server_port() -> Port {
@@ -11,7 +15,97 @@ fn get_server_port() -> Port {
         .unwrap_or(3000)
 }
 
+#[cfg(not(test))]
+/// Writes snapshot of all rivers to the archive file
+fn snapshot(archive: &str) {
+    let snapshot = john::SnapshotCommand::new().execute();
+
+    match io::File::create(&Path::new(archive)).write_str(json::encode(&snapshot).as_slice()) {
+        Ok(_) => {},
+        Err(err) => panic!("Unable to write snapshot to {}: {}", archive, err)
+    }
+}
+
+#[cfg(not(test))]
+/// Rebuilds data directory (./tmp/rivers by default) from the archive file
+fn restore(archive: &str, root: &str) {
+    let contents = match io::File::open(&Path::new(archive)).read_to_string() {
+        Ok(contents) => contents,
+        Err(err) => panic!("Unable to read snapshot from {}: {}", archive, err)
+    };
+
+    match json::decode::< john::Snapshot >(contents.as_slice()) {
+        Ok(snapshot) => {
+            let storage = john::FileStorage::in_root(&Path::new(root));
+
+            match john::RestoreCommand::with_storage(storage).execute(&snapshot) {
+                Ok(_) => {},
+                Err(err) => panic!("Unable to restore snapshot {}: {}", archive, err)
+            }
+        },
+        Err(err) => panic!("Unable to parse snapshot {}: {}", archive, err)
+    }
+}
+
+#[cfg(not(test))]
+/// Writes messages of the river as JSON lines to stdout
+fn export(river: &str, from: Option < &str >, to: Option < &str >) {
+    let from = from.and_then(|from| from_str::< uint >(from));
+    let to = to.and_then(|to| from_str::< uint >(to));
+
+    match john::ExportCommand::new().execute(river, from, to, &mut io::stdout()) {
+        Ok(_) => {},
+        Err(err) => panic!("Unable to export river {}: {}", river, err)
+    }
+}
+
+#[cfg(not(test))]
+/// Pushes messages from JSON lines file (or stdin) to the river
+fn import(river: &str, file: Option < &str >) {
+    let result = match file {
+        Some(file) => john::ImportCommand::new().execute(
+            river,
+            &mut io::BufferedReader::new(io::File::open(&Path::new(file)))
+        ),
+        None => john::ImportCommand::new().execute(river, &mut io::stdin())
+    };
+
+    match result {
+        Ok(count) => println!("Imported {} messages to {}", count, river),
+        Err(err) => panic!("Unable to import river {}: {}", river, err)
+    }
+}
+
+#[cfg(not(test))]
+/// Serves rivers replicated by a local Raft cluster of the nodes
+fn cluster(name: &str, size: &str) {
+    match from_str::< uint >(size) {
+        Some(size) if size % 2 == 1 => {
+            let storage = john::ClusterStorage::new(name, size);
+            john::Server::with_storage(get_server_port(), storage).start()
+        },
+        _ => panic!("Number of nodes should be odd, got {}", size)
+    }
+}
+
 #[cfg(not(test))]
 fn main() {
-    john::Server::new(get_server_port()).start()
+    let args = args();
+    let args: Vec < &str > = args.iter().map(|arg| arg.as_slice()).collect();
+
+    match args.as_slice() {
+        [_, "snapshot", archive] => snapshot(archive),
+        [_, "restore", archive] => restore(archive, "./tmp/rivers"),
+        [_, "restore", archive, root] => restore(archive, root),
+        [_, "export", river] => export(river, None, None),
+        [_, "export", river, from] => export(river, Some(from), None),
+        [_, "export", river, from, to] => export(river, Some(from), Some(to)),
+        [_, "import", river] => import(river, None),
+        [_, "import", river, file] => import(river, Some(file)),
+        [_, "cluster", name, size] => cluster(name, size),
+        [_] => john::Server::new(get_server_port()).start(),
+        _ => println!("Usage: john [snapshot <archive> | restore <archive> [<data directory>] |\n\
+                       export <river> [<from> [<to>]] | import <river> [<file>] |\n\
+                       cluster <name> <number of nodes>]")
+    }
 }
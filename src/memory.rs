@@ -0,0 +1,223 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::iter::AdditiveIterator;
+use std::sync::{Arc, Mutex};
+
+use storage::{Storage, Message, RiverInfo, Usage, now};
+use error::PushError;
+use schema::SchemaCache;
+use metrics::Metrics;
+
+struct MemoryRiver {
+    start: uint,
+    messages: Vec < Message >,
+    bytes: u64,
+    last_write: u64
+}
+
+impl MemoryRiver {
+    fn new() -> MemoryRiver {
+        MemoryRiver {
+            start: 1,
+            messages: vec![],
+            bytes: 0,
+            last_write: now()
+        }
+    }
+}
+
+#[deriving(Clone)]
+/// Storage keeping rivers in memory, useful for tests and embedding
+///
+/// Clones of the storage share the same rivers, nothing survives the process. Usage of the
+/// storage is summed over its rivers on demand:
+///
+/// ```
+/// let storage = john::MemoryStorage::new();
+///
+/// john::PushCommand::with_storage(storage.clone()).execute("river name", "a message");
+/// john::PeekCommand::with_storage(storage.clone()).execute("river name", None);
+/// ```
+pub struct MemoryStorage {
+    rivers: Arc < Mutex < HashMap < String, MemoryRiver > > >,
+    states: Arc < Mutex < HashMap < (String, String), String > > >,
+    state_lock: Arc < Mutex < () > >,
+    append_lock: Arc < Mutex < () > >,
+    write_lock: Arc < Mutex < () > >,
+    schemas: Arc < Mutex < SchemaCache > >,
+    metrics: Arc < Mutex < Metrics > >
+}
+
+impl MemoryStorage {
+    /// Constructor ::new()
+    ///
+    /// Creates new empty memory storage
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            rivers: Arc::new(Mutex::new(HashMap::new())),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            state_lock: Arc::new(Mutex::new(())),
+            append_lock: Arc::new(Mutex::new(())),
+            write_lock: Arc::new(Mutex::new(())),
+            schemas: Arc::new(Mutex::new(SchemaCache::new())),
+            metrics: Arc::new(Mutex::new(Metrics::new()))
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, river: &str) -> Result < (), String > {
+        let mut rivers = self.rivers.lock();
+
+        if ! rivers.contains_key(&river.to_string()) {
+            rivers.insert(river.to_string(), MemoryRiver::new());
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        let _lock = self.write_lock.lock();
+        let _ = self.create(river);
+
+        let mut rivers = self.rivers.lock();
+        let memory_river = rivers.find_mut(&river.to_string()).unwrap();
+        let offset = memory_river.start + memory_river.messages.len();
+
+        let mut stored = message.clone();
+        stored.offset = offset;
+
+        memory_river.messages.push(stored);
+        memory_river.bytes += message.payload.len() as u64;
+        memory_river.last_write = now();
+
+        Ok(offset)
+    }
+
+    fn read(&self, river: &str, offset: uint) -> Option < Message > {
+        match self.rivers.lock().find(&river.to_string()) {
+            Some(memory_river) if offset >= memory_river.start =>
+                memory_river.messages.as_slice().get(offset - memory_river.start).map(|message| message.clone()),
+            _ => None
+        }
+    }
+
+    fn earliest_offset(&self, river: &str) -> uint {
+        match self.rivers.lock().find(&river.to_string()) {
+            Some(memory_river) => memory_river.start,
+            None => 1
+        }
+    }
+
+    fn latest_offset(&self, river: &str) -> uint {
+        match self.rivers.lock().find(&river.to_string()) {
+            Some(memory_river) => memory_river.start + memory_river.messages.len() - 1,
+            None => 0
+        }
+    }
+
+    fn truncate(&self, river: &str, before: uint) -> Result < (), String > {
+        let _lock = self.write_lock.lock();
+        let _ = self.create(river);
+
+        let mut rivers = self.rivers.lock();
+        let memory_river = rivers.find_mut(&river.to_string()).unwrap();
+
+        if before <= memory_river.start {
+            return Ok(())
+        }
+
+        let dropped = cmp::min(before - memory_river.start, memory_river.messages.len());
+
+        memory_river.messages = memory_river.messages.slice_from(dropped).to_vec();
+        memory_river.bytes = memory_river.messages.iter().map(|message| message.payload.len() as u64).sum();
+        memory_river.start = before;
+        memory_river.last_write = now();
+        Ok(())
+    }
+
+    fn info(&self, river: &str) -> Option < RiverInfo > {
+        self.rivers.lock().find(&river.to_string()).map(|memory_river| {
+            let count = memory_river.messages.len();
+            let start = memory_river.start;
+
+            RiverInfo {
+                name: river.to_string(),
+                earliest_offset: if count > 0 { Some(start) } else { None },
+                latest_offset: if count > 0 { Some(start + count - 1) } else { None },
+                message_count: count,
+                bytes: memory_river.bytes,
+                last_write: memory_river.last_write
+            }
+        })
+    }
+
+    fn rivers(&self) -> Vec < String > {
+        let mut names: Vec < String > = self.rivers.lock().keys().map(|name| name.clone()).collect();
+
+        names.sort();
+        names
+    }
+
+    fn message_size(&self, message: &Message) -> u64 {
+        message.payload.len() as u64
+    }
+
+    fn serialize_appends < T > (&self, appends: || -> T) -> T {
+        let _lock = self.append_lock.lock();
+        appends()
+    }
+
+    fn freeze < T > (&self, read: || -> T) -> T {
+        let _state_lock = self.state_lock.lock();
+        let _append_lock = self.append_lock.lock();
+        let _lock = self.write_lock.lock();
+        read()
+    }
+
+    fn usage(&self) -> Usage {
+        let rivers = self.rivers.lock();
+
+        Usage {
+            bytes: rivers.values().map(|memory_river| memory_river.bytes).sum(),
+            messages: rivers.values().map(|memory_river| memory_river.messages.len()).sum()
+        }
+    }
+
+    fn schemas(&self) -> &Mutex < SchemaCache > {
+        &*self.schemas
+    }
+
+    fn metrics(&self) -> &Mutex < Metrics > {
+        &*self.metrics
+    }
+
+    fn destroy(&self, river: &str) -> Result < (), String > {
+        let _lock = self.write_lock.lock();
+        self.rivers.lock().remove(&river.to_string());
+
+        let mut states = self.states.lock();
+        let keys: Vec < (String, String) > = states.keys()
+            .filter(|&&(ref state_river, _)| state_river.as_slice() == river)
+            .map(|key| key.clone())
+            .collect();
+
+        for key in keys.iter() {
+            states.remove(key);
+        }
+
+        Ok(())
+    }
+
+    fn state(&self, river: &str, kind: &str) -> Option < String > {
+        self.states.lock().find(&(river.to_string(), kind.to_string())).map(|state| state.clone())
+    }
+
+    fn update_state(&self, river: &str, kind: &str, update: |Option < String >| -> String) -> Result < (), String > {
+        let _lock = self.state_lock.lock();
+        let state = update(self.state(river, kind));
+
+        self.states.lock().insert((river.to_string(), kind.to_string()), state);
+        Ok(())
+    }
+}
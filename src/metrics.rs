@@ -0,0 +1,223 @@
+use std::collections::TreeMap;
+
+use storage::{Storage, Message, RiverInfo, now};
+use error::{PushError, RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge, SchemaViolation, WriteFailed};
+
+/// Counters with their help texts, in order of rendering
+static COUNTERS: [(&'static str, &'static str), ..5] = [
+    ("john_messages_in_total", "Messages appended to the river"),
+    ("john_bytes_in_total", "Bytes of payloads appended to the river"),
+    ("john_messages_out_total", "Messages read from the river by peeks and queue consumers"),
+    ("john_bytes_out_total", "Bytes of payloads read from the river"),
+    ("john_push_errors_total", "Pushes rejected by the river")
+];
+
+/// Upper bounds of buckets of request latencies in seconds, +Inf bucket is implied
+static LATENCY_BUCKETS: [f64, ..10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+static LATENCY: &'static str = "john_request_duration_seconds";
+
+/// Time in milliseconds, for which gauges of rivers are reused by following scrapes
+const GAUGES_TTL: u64 = 10000;
+
+/// Latencies of requests to a route
+struct Histogram {
+    counts: Vec < u64 >,
+    sum: f64,
+    count: u64
+}
+
+/// Counters and latencies recorded by a storage and its clones, kept by the storage
+///
+/// Gauges of rivers are taken at most once in 10 seconds, scrapes in between get the same
+/// values.
+pub struct Metrics {
+    counters: TreeMap < String, TreeMap < String, u64 > >,
+    latencies: TreeMap < String, Histogram >,
+    gauges: Option < (u64, Vec < RiverInfo >) >
+}
+
+impl Metrics {
+    /// Creates metrics without samples
+    pub fn new() -> Metrics {
+        Metrics {
+            counters: TreeMap::new(),
+            latencies: TreeMap::new(),
+            gauges: None
+        }
+    }
+
+    /// Increments the counter with the labels
+    pub fn count(&mut self, name: &str, labels: &[(&str, &str)], by: u64) {
+        let counters = &mut self.counters;
+        let name = name.to_string();
+
+        if ! counters.contains_key(&name) {
+            counters.insert(name.clone(), TreeMap::new());
+        }
+
+        let samples = counters.find_mut(&name).unwrap();
+        let labels = format_labels(labels);
+        let value = samples.find(&labels).map_or(0, |&value| value);
+
+        samples.insert(labels, value + by);
+    }
+
+    /// Records latency of a request in seconds
+    pub fn observe(&mut self, labels: &[(&str, &str)], seconds: f64) {
+        let latencies = &mut self.latencies;
+        let labels = format_labels(labels);
+
+        if ! latencies.contains_key(&labels) {
+            latencies.insert(labels.clone(), Histogram {
+                counts: Vec::from_elem(LATENCY_BUCKETS.len(), 0),
+                sum: 0.0,
+                count: 0
+            });
+        }
+
+        let histogram = latencies.find_mut(&labels).unwrap();
+        match LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            Some(bucket) => histogram.counts.as_mut_slice()[bucket] += 1,
+            None => {}
+        }
+
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Counters and latencies in Prometheus text format
+    fn render(&self) -> String {
+        let mut output = String::new();
+        let counters = &self.counters;
+
+        for &(name, help) in COUNTERS.iter() {
+            output.push_str(format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name).as_slice());
+
+            match counters.find(&name.to_string()) {
+                Some(samples) => for (labels, value) in samples.iter() {
+                    output.push_str(format!("{}{{{}}} {}\n", name, labels, value).as_slice());
+                },
+                None => {}
+            }
+        }
+
+        let latencies = &self.latencies;
+        output.push_str(format!("# HELP {} Time to handle requests to the route\n# TYPE {} histogram\n", LATENCY, LATENCY).as_slice());
+
+        for (labels, histogram) in latencies.iter() {
+            let mut cumulative = 0;
+
+            for (bound, &count) in LATENCY_BUCKETS.iter().zip(histogram.counts.iter()) {
+                cumulative += count;
+                output.push_str(format!("{}_bucket{{{},le=\"{}\"}} {}\n", LATENCY, labels, bound, cumulative).as_slice());
+            }
+
+            output.push_str(format!("{}_bucket{{{},le=\"+Inf\"}} {}\n", LATENCY, labels, histogram.count).as_slice());
+            output.push_str(format!("{}_sum{{{}}} {}\n", LATENCY, labels, histogram.sum).as_slice());
+            output.push_str(format!("{}_count{{{}}} {}\n", LATENCY, labels, histogram.count).as_slice());
+        }
+
+        output
+    }
+}
+
+/// Counts the message appended to the river
+pub fn record_append < S: Storage > (storage: &S, river: &str, message: &Message) {
+    let mut metrics = storage.metrics().lock();
+
+    metrics.count("john_messages_in_total", &[("river", river)], 1);
+    metrics.count("john_bytes_in_total", &[("river", river)], message.payload.len() as u64);
+}
+
+/// Counts the message read from the river by a client
+pub fn record_read < S: Storage > (storage: &S, river: &str, message: &Message) {
+    let mut metrics = storage.metrics().lock();
+
+    metrics.count("john_messages_out_total", &[("river", river)], 1);
+    metrics.count("john_bytes_out_total", &[("river", river)], message.payload.len() as u64);
+}
+
+/// Counts the push rejected by the river
+pub fn record_rejection < S: Storage > (storage: &S, river: &str, err: &PushError) {
+    let reason = match *err {
+        RiverQuotaExceeded => "river_quota_exceeded",
+        StorageQuotaExceeded => "storage_quota_exceeded",
+        MessageTooLarge => "message_too_large",
+        SchemaViolation(_) => "schema_violation",
+        WriteFailed(_) => "write_failed"
+    };
+
+    storage.metrics().lock().count("john_push_errors_total", &[("river", river), ("reason", reason)], 1);
+}
+
+/// Records latency of a request to the route in seconds
+pub fn record_latency < S: Storage > (storage: &S, route: &str, method: &str, seconds: f64) {
+    storage.metrics().lock().observe(&[("route", route), ("method", method)], seconds);
+}
+
+/// Counters and latencies of the storage together with sizes and offsets of its rivers in
+/// Prometheus text format
+pub fn render < S: Storage > (storage: &S) -> String {
+    let now = now();
+    let (mut output, gauges) = {
+        let metrics = storage.metrics().lock();
+        let gauges = match metrics.gauges {
+            Some((taken_at, ref infos)) if taken_at + GAUGES_TTL > now => Some(infos.clone()),
+            _ => None
+        };
+
+        (metrics.render(), gauges)
+    };
+
+    // rivers are described without holding the metrics, so appends are counted meanwhile
+    let infos = match gauges {
+        Some(infos) => infos,
+        None => {
+            let infos: Vec < RiverInfo > = storage.rivers().iter().filter_map(|river| storage.info(river.as_slice())).collect();
+            storage.metrics().lock().gauges = Some((now, infos.clone()));
+            infos
+        }
+    };
+
+    let gauges = [
+        ("john_river_messages", "Messages kept in the river"),
+        ("john_river_bytes", "Bytes taken by the river"),
+        ("john_river_earliest_offset", "Offset of the earliest message kept in the river"),
+        ("john_river_latest_offset", "Offset of the latest message of the river")
+    ];
+
+    for &(name, help) in gauges.iter() {
+        output.push_str(format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name).as_slice());
+
+        for info in infos.iter() {
+            // offsets of empty rivers are left out
+            let value = match name {
+                "john_river_messages" => Some(info.message_count as u64),
+                "john_river_bytes" => Some(info.bytes),
+                "john_river_earliest_offset" => info.earliest_offset.map(|offset| offset as u64),
+                _ => info.latest_offset.map(|offset| offset as u64)
+            };
+
+            match value {
+                Some(value) => output.push_str(format!("{}{{{}}} {}\n", name, format_labels(&[("river", info.name.as_slice())]), value).as_slice()),
+                None => {}
+            }
+        }
+    }
+
+    output
+}
+
+/// Labels of a sample, e.g. `river="orders",reason="schema_violation"`
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec < String > = labels.iter()
+        .map(|&(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+
+    labels.as_slice().connect(",")
+}
+
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
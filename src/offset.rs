@@ -0,0 +1,73 @@
+use std::fmt;
+use std::from_str::FromStr;
+
+#[deriving(Clone, PartialEq)]
+/// Position of a message in a river
+///
+/// Offset of a message equals its position in the river: the first message ever pushed
+/// has offset 1, the next one has offset 2 and so on. Offsets are kept when the river is
+/// truncated, so the earliest message is not necessarily at offset 1.
+///
+/// Offsets can be parsed from and formatted to strings: `earliest`, `latest`, `42`,
+/// `earliest+2` and `latest-2`
+pub enum Offset {
+    /// The first message kept in the river
+    Earliest,
+    /// The last message in the river
+    Latest,
+    /// Message at the specified position
+    At(uint),
+    /// Message the specified number of positions after the earliest one
+    FromEarliest(uint),
+    /// Message the specified number of positions before the latest one
+    FromLatest(uint)
+}
+
+impl Offset {
+    /// Converts offset used by `PeekCommand::execute` and `GET /peek` to Offset: None is the
+    /// latest message, both 0 and 1 mean the first message, any other number is the position
+    pub fn from_legacy(offset: Option < uint >) -> Offset {
+        match offset {
+            None => Latest,
+            Some(0) => Earliest,
+            Some(offset) => At(offset)
+        }
+    }
+
+    /// Position of the message in a river with the specified earliest and latest offsets,
+    /// None when the offset points before the beginning of the river
+    pub fn resolve(&self, earliest_offset: uint, latest_offset: uint) -> Option < uint > {
+        match *self {
+            Earliest => Some(earliest_offset),
+            Latest => Some(latest_offset),
+            At(offset) => Some(offset),
+            FromEarliest(count) => Some(earliest_offset + count),
+            FromLatest(count) if count <= latest_offset => Some(latest_offset - count),
+            FromLatest(_) => None
+        }
+    }
+}
+
+impl FromStr for Offset {
+    fn from_str(offset: &str) -> Option < Offset > {
+        match offset {
+            "earliest" => Some(Earliest),
+            "latest" => Some(Latest),
+            _ if offset.starts_with("earliest+") => from_str::< uint >(offset.slice_from(9)).map(|count| FromEarliest(count)),
+            _ if offset.starts_with("latest-") => from_str::< uint >(offset.slice_from(7)).map(|count| FromLatest(count)),
+            _ => from_str::< uint >(offset).map(|offset| At(offset))
+        }
+    }
+}
+
+impl fmt::Show for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Earliest => write!(f, "earliest"),
+            Latest => write!(f, "latest"),
+            At(offset) => write!(f, "{}", offset),
+            FromEarliest(count) => write!(f, "earliest+{}", count),
+            FromLatest(count) => write!(f, "latest-{}", count)
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use std::cmp;
+use std::collections::TreeMap;
+
+use serialize::json;
+
+use storage::{Storage, Message, DeadLetter};
+use error::PushError;
+use transaction::{Transactions, InProgress, Committed, Aborted};
+
+/// Kind of river state keeping the queue
+pub const QUEUE_STATE: &'static str = "queue";
+
+/// Kind of river state keeping the dead-letter policy
+pub const DEAD_LETTER_STATE: &'static str = "dead_letter";
+
+/// Kind of state of a dead-letter river keeping the offset of the next message to replay
+pub const REPLAY_STATE: &'static str = "replay";
+
+/// Kind of river state telling whether the river is a priority river
+pub const PRIORITY_STATE: &'static str = "priority";
+
+/// Failure reason of deliveries, which were neither acked nor nacked in time
+const EXPIRED_REASON: &'static str = "visibility timeout expired";
+
+/// Maximum length in bytes of a failure reason kept for a message, longer ones are truncated
+pub const MAX_REASON_LENGTH: uint = 256;
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Tells where to move messages of a queue, which failed to be delivered too many times
+pub struct DeadLetterPolicy {
+    /// Dead-letter river to move failing messages to
+    pub river: String,
+    /// Number of failed deliveries after which the message is moved
+    pub max_attempts: uint
+}
+
+pub fn load_policy(state: Option < String >) -> Option < DeadLetterPolicy > {
+    state.and_then(|state| json::decode::< Option < DeadLetterPolicy > >(state.as_slice()).ok().and_then(|policy| policy))
+}
+
+pub fn dump_policy(policy: &Option < DeadLetterPolicy >) -> String {
+    json::encode(policy)
+}
+
+pub fn load_prioritized(state: Option < String >) -> bool {
+    state.and_then(|state| json::decode::< bool >(state.as_slice()).ok()).unwrap_or(false)
+}
+
+pub fn dump_prioritized(prioritized: bool) -> String {
+    json::encode(&prioritized)
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Message delivered to a consumer, but not acknowledged yet
+///
+/// Delayed messages are held as leases without attempts, which expire at delivery time.
+/// Messages of transactions in progress are held as leases, which keep id of the transaction
+/// until it is over. Expired messages are never delivered
+pub struct Lease {
+    pub offset: uint,
+    pub priority: uint,
+    pub receipt: u64,
+    pub expires_at: u64,
+    pub attempts: uint,
+    pub failure: Option < String >,
+    pub transaction: Option < String >
+}
+
+/// Result of tracking the next message of the river
+enum Tracking {
+    /// Message is tracked, contains its delivery time
+    Tracked(u64),
+    /// Message is rolled back with its transaction and is never delivered
+    Skipped,
+    /// Message belongs to a transaction in progress, it is tracked but not delivered until
+    /// the transaction is committed, while the following messages are
+    Pending
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Consumption state of a river used as a work queue
+///
+/// Messages are delivered in order of offsets starting from `next_offset`. Delivered
+/// messages stay leased until acknowledged, and are delivered again when the lease expires
+/// (or is released), before any message that was never delivered.
+///
+/// Priority river tracks all its messages up to the latest one. Leases are kept only for
+/// delayed and delivered ones, while the ones ready to be delivered make a backlog of runs
+/// of consecutive offsets by priority, so its size depends on how priorities are mixed rather
+/// than on the number of messages. The message with the greatest priority is delivered first,
+/// while messages of the same priority are delivered in order of offsets.
+pub struct Queue {
+    next_offset: uint,
+    next_receipt: u64,
+    leases: Vec < Lease >,
+    backlog: TreeMap < uint, Vec < (uint, uint) > >
+}
+
+impl Queue {
+    pub fn load(state: Option < String >) -> Queue {
+        state
+            .and_then(|state| json::decode::< Queue >(state.as_slice()).ok())
+            .unwrap_or(Queue {
+                next_offset: 1,
+                next_receipt: 1,
+                leases: vec![],
+                backlog: TreeMap::new()
+            })
+    }
+
+    pub fn dump(&self) -> String {
+        json::encode(self)
+    }
+
+    /// Leases the most urgent expired or never delivered message of the river until `now + timeout`
+    ///
+    /// Expired messages, which have failed `policy.max_attempts` times, are moved to the
+    /// dead-letter river instead. Those rejected by the dead-letter river stay leased for
+    /// the timeout and are moved again afterwards.
+    pub fn receive < S: Storage > (&mut self, storage: &S, river: &str, policy: &Option < DeadLetterPolicy >,
+                                   prioritized: bool, transactions: &mut Transactions < S >,
+                                   timeout: u64, now: u64) -> Option < (Lease, Message) > {
+        let earliest_offset = storage.earliest_offset(river);
+        let latest_offset = storage.latest_offset(river);
+
+        self.leases.retain(|lease| lease.offset >= earliest_offset);
+        self.drop_backlog_before(earliest_offset);
+        if self.next_offset < earliest_offset {
+            self.next_offset = earliest_offset;
+        }
+
+        self.settle(river, transactions, now);
+
+        if prioritized {
+            while self.next_offset <= latest_offset {
+                self.track(storage, river, true, transactions, now);
+            }
+        }
+
+        loop {
+            let most_urgent = match (self.most_urgent(now, prioritized), self.backlog_head()) {
+                (Some(index), Some((priority, offset))) if self.precedes(priority, offset, index) => Some(self.lease_backlogged(priority)),
+                (None, Some((priority, _))) => Some(self.lease_backlogged(priority)),
+                (most_urgent, _) => most_urgent
+            };
+            let index = match most_urgent {
+                Some(index) if self.exhausted(index, policy) => {
+                    let reason = self.leases[index].failure.clone().unwrap_or(EXPIRED_REASON.to_string());
+
+                    // the message stays leased, until the dead-letter river accepts it
+                    match Queue::move_to_dead_letter(storage, river, policy, &self.leases[index], reason.as_slice()) {
+                        Ok(_) => { self.leases.remove(index); },
+                        Err(_) => self.leases[index].expires_at = now + cmp::max(timeout, 1)
+                    }
+
+                    continue
+                },
+                Some(index) => index,
+                None if self.next_offset <= latest_offset => match self.track(storage, river, false, transactions, now) {
+                    Tracked(deliver_at) if deliver_at <= now => self.leases.len() - 1,
+                    _ => continue
+                },
+                None => return None
+            };
+
+            let offset = self.leases[index].offset;
+            let message = match storage.read(river, offset) {
+                Some(ref message) if message.is_expired(now) => {
+                    self.leases.remove(index);
+                    continue
+                },
+                Some(message) => message,
+                None => {
+                    // the message is gone, e.g. truncated meanwhile, so it is never delivered
+                    self.leases.remove(index);
+                    continue
+                }
+            };
+
+            let receipt = self.next_receipt;
+            self.next_receipt += 1;
+
+            let lease = &mut self.leases[index];
+            lease.receipt = receipt;
+            lease.expires_at = now + timeout;
+            lease.attempts += 1;
+            lease.failure = None;
+
+            return Some((lease.clone(), message))
+        }
+    }
+
+    /// Completes the lease, the message is never delivered again
+    pub fn ack(&mut self, offset: uint, receipt: u64) -> bool {
+        match self.find(offset, receipt) {
+            Some(index) => { self.leases.remove(index); true },
+            None => false
+        }
+    }
+
+    /// Offset before which all messages were acknowledged, or moved to the dead-letter river
+    pub fn acked_before(&self) -> uint {
+        let leased = self.leases.iter().fold(self.next_offset, |before, lease| cmp::min(before, lease.offset));
+
+        self.backlog.iter().fold(leased, |before, (_, runs)| match runs.as_slice().head() {
+            Some(&(first, _)) => cmp::min(before, first),
+            None => before
+        })
+    }
+
+    /// Releases the lease, so the message is delivered again right away, unless it has
+    /// failed `policy.max_attempts` times and is moved to the dead-letter river
+    pub fn nack < S: Storage > (&mut self, storage: &S, river: &str, policy: &Option < DeadLetterPolicy >,
+                                offset: uint, receipt: u64, reason: &str) -> bool {
+        let reason = truncate_reason(reason);
+        let index = match self.find(offset, receipt) {
+            Some(index) => index,
+            None => return false
+        };
+
+        // the message is released, when the dead-letter river rejects it, and is moved again
+        // by the next receive
+        if self.exhausted(index, policy) && Queue::move_to_dead_letter(storage, river, policy, &self.leases[index], reason).is_ok() {
+            self.leases.remove(index);
+        } else {
+            let lease = &mut self.leases[index];
+            lease.expires_at = 0;
+            lease.failure = Some(reason.to_string());
+        }
+
+        true
+    }
+
+    /// Leases of messages, which wait for their transactions, are released once the transaction
+    /// is committed and are dropped once it is aborted
+    fn settle < S: Storage > (&mut self, river: &str, transactions: &mut Transactions < S >, now: u64) {
+        let mut aborted = vec![];
+
+        for lease in self.leases.iter_mut() {
+            let status = match lease.transaction {
+                Some(ref id) => transactions.status(id.as_slice(), river, lease.offset, now),
+                None => continue
+            };
+
+            match status {
+                Committed => lease.transaction = None,
+                InProgress => {},
+                Aborted => aborted.push(lease.offset)
+            }
+        }
+
+        self.leases.retain(|lease| ! aborted.contains(&lease.offset));
+    }
+
+    /// Starts tracking the message at `next_offset` as a lease without attempts, which expires
+    /// at delivery time of the message, or puts it into the backlog of a priority river when
+    /// it is ready to be delivered
+    fn track < S: Storage > (&mut self, storage: &S, river: &str, backlogged: bool,
+                             transactions: &mut Transactions < S >, now: u64) -> Tracking {
+        let offset = self.next_offset;
+        let (deliver_at, priority, transaction) = match storage.read(river, offset) {
+            Some(message) => {
+                let transaction = match transactions.status_of(river, &message, now) {
+                    Committed => None,
+                    InProgress => message.transaction.clone(),
+                    Aborted => {
+                        self.next_offset += 1;
+                        return Skipped
+                    }
+                };
+
+                (message.deliver_at.unwrap_or(0), message.priority.unwrap_or(0), transaction)
+            },
+            None => (0, 0, None)
+        };
+
+        let pending = transaction.is_some();
+
+        self.next_offset += 1;
+        if backlogged && !pending && deliver_at <= now {
+            self.add_to_backlog(offset, priority);
+            return Tracked(deliver_at)
+        }
+
+        self.leases.push(Lease {
+            offset: offset,
+            priority: priority,
+            receipt: 0,
+            expires_at: deliver_at,
+            attempts: 0,
+            failure: None,
+            transaction: transaction
+        });
+
+        if pending { Pending } else { Tracked(deliver_at) }
+    }
+
+    /// Index of the expired lease with the least offset, among the ones with the greatest
+    /// priority when the river is prioritized
+    fn most_urgent(&self, now: u64, prioritized: bool) -> Option < uint > {
+        let mut most_urgent: Option < uint > = None;
+
+        for (index, lease) in self.leases.iter().enumerate() {
+            let more_urgent = match most_urgent {
+                Some(other) => {
+                    let other = &self.leases[other];
+
+                    match lease.priority.cmp(&other.priority) {
+                        cmp::Greater if prioritized => true,
+                        cmp::Less if prioritized => false,
+                        _ => lease.offset < other.offset
+                    }
+                },
+                None => true
+            };
+
+            if lease.expires_at <= now && lease.transaction.is_none() && more_urgent {
+                most_urgent = Some(index);
+            }
+        }
+
+        most_urgent
+    }
+
+    /// Priority and offset of the first message in the backlog with the greatest priority
+    fn backlog_head(&self) -> Option < (uint, uint) > {
+        self.backlog.rev_iter().next().and_then(|(&priority, runs)| match runs.as_slice().head() {
+            Some(&(first, _)) => Some((priority, first)),
+            None => None
+        })
+    }
+
+    /// Tells whether the message in the backlog is delivered before the message of the lease
+    fn precedes(&self, priority: uint, offset: uint, index: uint) -> bool {
+        let lease = &self.leases[index];
+
+        priority > lease.priority || (priority == lease.priority && offset < lease.offset)
+    }
+
+    fn add_to_backlog(&mut self, offset: uint, priority: uint) {
+        if !self.backlog.contains_key(&priority) {
+            self.backlog.insert(priority, vec![]);
+        }
+
+        let runs = self.backlog.find_mut(&priority).unwrap();
+        let extended = match runs.last_mut() {
+            Some(&(_, ref mut last)) => if *last + 1 == offset { *last = offset; true } else { false },
+            None => false
+        };
+
+        if !extended {
+            runs.push((offset, offset));
+        }
+    }
+
+    /// Takes the first message of the specified priority out of the backlog and tracks it as
+    /// a lease without attempts, returns index of the lease
+    fn lease_backlogged(&mut self, priority: uint) -> uint {
+        let (offset, exhausted) = {
+            let runs = self.backlog.find_mut(&priority).unwrap();
+            let (first, last) = runs[0];
+            if first == last {
+                runs.remove(0);
+            } else {
+                runs[0] = (first + 1, last);
+            }
+
+            (first, runs.is_empty())
+        };
+
+        if exhausted {
+            self.backlog.remove(&priority);
+        }
+
+        self.leases.push(Lease {
+            offset: offset,
+            priority: priority,
+            receipt: 0,
+            expires_at: 0,
+            attempts: 0,
+            failure: None,
+            transaction: None
+        });
+
+        self.leases.len() - 1
+    }
+
+    /// Forgets truncated messages of the backlog
+    fn drop_backlog_before(&mut self, earliest_offset: uint) {
+        let mut exhausted = vec![];
+
+        for (&priority, runs) in self.backlog.iter_mut() {
+            runs.retain(|&(_, last)| last >= earliest_offset);
+            for run in runs.iter_mut() {
+                let (first, last) = *run;
+                *run = (cmp::max(first, earliest_offset), last);
+            }
+
+            if runs.is_empty() {
+                exhausted.push(priority);
+            }
+        }
+
+        for priority in exhausted.iter() {
+            self.backlog.remove(priority);
+        }
+    }
+
+    fn find(&self, offset: uint, receipt: u64) -> Option < uint > {
+        self.leases.iter().position(|lease| lease.offset == offset && lease.receipt == receipt && lease.attempts > 0)
+    }
+
+    fn exhausted(&self, index: uint, policy: &Option < DeadLetterPolicy >) -> bool {
+        match *policy {
+            Some(ref policy) => self.leases[index].attempts >= policy.max_attempts,
+            None => false
+        }
+    }
+
+    /// Appends the leased message to the dead-letter river, Err when the dead-letter river
+    /// rejected it, then the lease should be kept
+    fn move_to_dead_letter < S: Storage > (storage: &S, river: &str, policy: &Option < DeadLetterPolicy >,
+                                           lease: &Lease, reason: &str) -> Result < (), PushError > {
+        let policy = match *policy {
+            Some(ref policy) => policy,
+            None => return Ok(())
+        };
+
+        match storage.read(river, lease.offset) {
+            Some(mut message) => {
+                message.dead_letter = Some(DeadLetter {
+                    river: river.to_string(),
+                    offset: lease.offset,
+                    reason: reason.to_string(),
+                    attempts: lease.attempts
+                });
+                storage.append(policy.river.as_slice(), &message).map(|_| ())
+            },
+            None => Ok(())
+        }
+    }
+}
+
+/// Leading part of the reason, which is at most MAX_REASON_LENGTH bytes long
+fn truncate_reason(reason: &str) -> &str {
+    let mut end = cmp::min(reason.len(), MAX_REASON_LENGTH);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    reason.slice_to(end)
+}
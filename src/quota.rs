@@ -0,0 +1,189 @@
+use serialize::json;
+
+use storage::{Storage, Message, Usage, now};
+use config::{RiverConfig, CONFIG_STATE, load_config};
+use error::{PushError, RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge, WriteFailed};
+use metrics::{record_append, record_rejection};
+
+/// Kind of river state keeping the quota of the river
+pub const QUOTA_STATE: &'static str = "quota";
+
+/// Pseudo-river keeping the quota of the whole storage as its state, hidden from the list
+/// of rivers
+pub const STORAGE: &'static str = ".storage";
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Tells what to do with a push, which does not fit into a quota
+pub enum QuotaPolicy {
+    /// Push is rejected with a PushError
+    RejectPushes,
+    /// Oldest messages of the river pushed to are dropped to make room for the new one
+    DropOldest
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Limits size of a river, or of all rivers of the storage together
+pub struct Quota {
+    /// Maximum number of bytes, as counted by `RiverInfo::bytes`
+    pub max_bytes: Option < u64 >,
+    /// Maximum number of messages
+    pub max_messages: Option < uint >,
+    /// What to do with a push, which does not fit
+    pub policy: QuotaPolicy
+}
+
+impl Quota {
+    fn is_exceeded(&self, usage: &Usage) -> bool {
+        self.max_bytes.map_or(false, |max_bytes| usage.bytes > max_bytes) ||
+            self.max_messages.map_or(false, |max_messages| usage.messages > max_messages)
+    }
+}
+
+pub fn load_quota(state: Option < String >) -> Option < Quota > {
+    state.and_then(|state| json::decode::< Option < Quota > >(state.as_slice()).ok().and_then(|quota| quota))
+}
+
+pub fn dump_quota(quota: &Option < Quota >) -> String {
+    json::encode(quota)
+}
+
+fn usage_of_river < S: Storage > (storage: &S, river: &str) -> Usage {
+    match storage.info(river) {
+        Some(info) => Usage { bytes: info.bytes, messages: info.message_count },
+        None => Usage { bytes: 0, messages: 0 }
+    }
+}
+
+/// Appends the message within quotas and size limits of the river, the way `Storage::append`
+/// does. Appends within quotas are serialized with `Storage::serialize_appends`, so quotas are
+/// not exceeded by concurrent pushes, the rest run right away
+pub fn append < S: Storage > (storage: &S, river: &str, message: &Message) -> Result < uint, PushError > {
+    let quotas = Quotas::of(storage, river);
+
+    if quotas.apply() {
+        storage.serialize_appends(|| quotas.append(storage, river, message))
+    } else {
+        quotas.append(storage, river, message)
+    }
+}
+
+/// Quotas applying to pushes to a river, together with its size limits from configuration
+struct Quotas {
+    river: Option < Quota >,
+    storage: Option < Quota >,
+    config: RiverConfig
+}
+
+impl Quotas {
+    /// Reads quotas of the river and of the whole storage
+    fn of < S: Storage > (storage: &S, river: &str) -> Quotas {
+        Quotas {
+            river: load_quota(storage.state(river, QUOTA_STATE)),
+            storage: load_quota(storage.state(STORAGE, QUOTA_STATE)),
+            config: load_config(storage.state(river, CONFIG_STATE))
+        }
+    }
+
+    /// Tells whether any quota applies
+    fn apply(&self) -> bool {
+        self.river.is_some() || self.storage.is_some()
+    }
+
+    /// Appends the message, unless it does not fit into the quotas. Oldest messages of the
+    /// river are dropped to make room when allowed by the policy of the exceeded quota, and
+    /// when they outlived retention of the river. Appended and rejected messages are counted
+    /// in metrics of the storage
+    ///
+    /// Must be called while appends are serialized, when quotas apply.
+    fn append < S: Storage > (&self, storage: &S, river: &str, message: &Message) -> Result < uint, PushError > {
+        let result = self.append_within(storage, river, message);
+
+        match result {
+            Ok(_) => record_append(storage, river, message),
+            Err(ref err) => record_rejection(storage, river, err)
+        }
+
+        result
+    }
+
+    fn append_within < S: Storage > (&self, storage: &S, river: &str, message: &Message) -> Result < uint, PushError > {
+        match self.config.max_message_size {
+            Some(max_message_size) if message.payload.len() > max_message_size => return Err(MessageTooLarge),
+            _ => {}
+        }
+
+        self.drop_retained(storage, river);
+
+        if ! self.apply() {
+            return storage.write(river, message)
+        }
+
+        let size = storage.message_size(message);
+        let mut river_usage = usage_of_river(storage, river);
+        let mut storage_usage = if self.storage.is_some() { storage.usage() } else { Usage { bytes: 0, messages: 0 } };
+
+        river_usage.bytes += size;
+        river_usage.messages += 1;
+        storage_usage.bytes += size;
+        storage_usage.messages += 1;
+
+        let earliest_offset = storage.earliest_offset(river);
+        let mut dropped = 0u;
+
+        loop {
+            let (error, policy) = match (&self.river, &self.storage) {
+                (&Some(ref quota), _) if quota.is_exceeded(&river_usage) => (RiverQuotaExceeded, quota.policy.clone()),
+                (_, &Some(ref quota)) if quota.is_exceeded(&storage_usage) => (StorageQuotaExceeded, quota.policy.clone()),
+                _ => break
+            };
+
+            let oldest = match policy {
+                DropOldest => storage.read(river, earliest_offset + dropped),
+                RejectPushes => None
+            };
+
+            match oldest {
+                Some(ref oldest) => {
+                    let size = storage.message_size(oldest);
+
+                    river_usage.bytes -= size;
+                    river_usage.messages -= 1;
+                    storage_usage.bytes -= size;
+                    storage_usage.messages -= 1;
+                    dropped += 1;
+                },
+                None => return Err(error)
+            }
+        }
+
+        if dropped > 0 {
+            try!(storage.truncate(river, earliest_offset + dropped).map_err(WriteFailed));
+        }
+
+        storage.write(river, message)
+    }
+
+    fn drop_retained < S: Storage > (&self, storage: &S, river: &str) {
+        let retention = match self.config.retention {
+            Some(retention) => retention,
+            None => return
+        };
+
+        let earliest_offset = storage.earliest_offset(river);
+        let latest_offset = storage.latest_offset(river);
+        let now = now();
+        let mut before = earliest_offset;
+
+        while before <= latest_offset {
+            match storage.read(river, before) {
+                Some(ref message) if message.timestamp + retention <= now => before += 1,
+                _ => break
+            }
+        }
+
+        // retained messages, which failed to be dropped, are dropped by the next append
+        if before > earliest_offset {
+            let _ = storage.truncate(river, before);
+        }
+    }
+}
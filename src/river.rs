@@ -1,198 +1,459 @@
 extern crate std;
 
+use std::cmp;
+use std::collections::TreeMap;
 use std::io;
 use std::io::fs::PathExtensions;
 use std::str;
 
-const MESSAGE_SIZE: uint = 4096;
+use flate;
+use serialize::json;
+use serialize::base64::{ToBase64, FromBase64, STANDARD};
+
+use storage::{Message, RiverInfo, DeadLetter, SchemaVersion};
+use config::RiverConfig;
+use error::{PushError, MessageTooLarge, WriteFailed};
+
+pub const MESSAGE_SIZE: uint = 4096;
 const LINE_END: u8 = '\n' as u8;
+const META_END: u8 = 0;
 
-#[deriving(Encodable, Decodable)]
-/// Result of PeekCommand, when it was successful
-/// Contains message and new offset to specify to peek command
-/// for continuous peeking
-pub struct PeekResult {
-    /// Contains message
-    pub message: String,
-    /// Contains next offset to be specified to read next message from river
-    pub offset: uint
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Metadata stored in the padding of message slot, right after the line end
+struct Meta {
+    timestamp: u64,
+    dead_letter: Option < DeadLetter >,
+    deliver_at: Option < u64 >,
+    expires_at: Option < u64 >,
+    priority: Option < uint >,
+    transaction: Option < String >,
+    schema: Option < SchemaVersion >,
+    compressed: Option < bool >,
+    encoded: Option < bool >
 }
 
-impl Clone for PeekResult {
-    fn clone(&self) -> PeekResult {
-        PeekResult {
-            message: self.message.clone(),
-            offset: self.offset.clone()
+impl Meta {
+    fn of(message: &Message, compressed: bool, encoded: bool) -> Meta {
+        Meta {
+            timestamp: message.timestamp,
+            dead_letter: message.dead_letter.clone(),
+            deliver_at: message.deliver_at,
+            expires_at: message.expires_at,
+            priority: message.priority,
+            transaction: message.transaction.clone(),
+            schema: message.schema.clone(),
+            compressed: if compressed { Some(true) } else { None },
+            encoded: if encoded { Some(true) } else { None }
         }
     }
 
-    fn clone_from(&mut self, source: &PeekResult) {
-        self.message = source.message.clone();
-        self.offset = source.offset.clone();
+    fn unknown() -> Meta {
+        Meta {
+            timestamp: 0,
+            dead_letter: None,
+            deliver_at: None,
+            expires_at: None,
+            priority: None,
+            transaction: None,
+            schema: None,
+            compressed: None,
+            encoded: None
+        }
     }
 }
 
 pub struct River {
+    root: Path,
     path: Path,
     name: String
 }
 
 impl River {
-    pub fn new(river: &str) -> River {
+    pub fn in_root(root: &Path, river: &str) -> River {
         River {
+            root: root.clone(),
             name: river.to_string(),
-            path: Path::new(format!("./tmp/rivers/{}", river))
+            path: root.join(river)
         }
     }
 
-    pub fn push(&self, message: &str) {
-        let mut file = self.get_file_for_append();
-        let bytes = message.as_bytes();
+    /// Appends messages with a single write (and a single flush to disk, when the river is
+    /// durable), returns their offsets. Messages, which do not fit into a slot together with
+    /// their metadata, are rejected with MessageTooLarge and get no offset. When the write
+    /// fails, the river is left as it was and all messages are rejected with WriteFailed
+    pub fn append_all(&self, messages: &[&Message], config: &RiverConfig) -> Vec < Result < uint, PushError > > {
+        let length = self.slots() * MESSAGE_SIZE;
+        let mut offset = self.latest_offset() + 1;
 
-        match file.write(bytes) {
-            Ok(_) => self.write_abundant_bytes(&mut file, bytes.len()),
-            Err(err) => self.error("Unable to push message", &err)
+        let mut slots = Vec::with_capacity(messages.len() * MESSAGE_SIZE);
+        let results: Vec < Result < uint, PushError > > = messages.iter().map(|message| {
+            self.slot(*message, config.compressed).map(|slot| {
+                slots.push_all(slot.as_slice());
+                offset += 1;
+                offset - 1
+            })
+        }).collect();
+
+        if slots.is_empty() {
+            return results
         }
-    }
 
-    pub fn create_unless_exists(&self) {
-        if ! self.path.exists() {
-            match io::File::create(&self.path) {
-                Ok(_) => {},
-                Err(err) => self.error("Unable to create river", &err)
+        let written = self.get_file_for_append().and_then(|mut file| {
+            try!(file.write(slots.as_slice()));
+
+            if config.durable { file.fsync() } else { Ok(()) }
+        });
+
+        match written {
+            Ok(_) => results,
+            Err(err) => {
+                // slots written in part would shift offsets of the messages appended later
+                let _ = io::File::open_mode(&self.path, io::Open, io::ReadWrite)
+                    .and_then(|mut file| file.truncate(length as i64));
+
+                let reason = format!("Unable to push message to river {}: {}", self.name, err);
+                results.into_iter().map(|result| result.and(Err(WriteFailed(reason.clone())))).collect()
             }
         }
     }
 
-    pub fn peek_at(&self, offset: Option < uint >) -> Option < PeekResult > {
-        match self.get_line(offset) {
-            Some((actual_offset, message)) => self.form_peek_result(message, actual_offset, offset),
-            _ => None
-        }
+    pub fn names_in(root: &Path) -> Vec < String > {
+        let mut names: Vec < String > = io::fs::readdir(root).unwrap_or(vec![]).iter()
+            .filter(|path| path.is_file())
+            .filter_map(|path| path.filename_str())
+            .filter(|name| ! name.starts_with("."))
+            .map(|name| name.to_string())
+            .collect();
+
+        names.sort();
+        names
     }
 
-    pub fn destroy(&self) {
-        match io::fs::unlink(&self.path) {
-            Ok(_) => {},
-            Err(err) => self.error(format!("Unable to clear river {}", self.name).as_slice(), &err)
+    pub fn info(&self) -> Option < RiverInfo > {
+        match self.path.stat() {
+            Ok(stat) => {
+                let earliest_offset = self.earliest_offset();
+                let count = self.latest_offset() + 1 - earliest_offset;
+
+                Some(RiverInfo {
+                    name: self.name.clone(),
+                    earliest_offset: if count > 0 { Some(earliest_offset) } else { None },
+                    latest_offset: if count > 0 { Some(earliest_offset + count - 1) } else { None },
+                    message_count: count,
+                    bytes: stat.size,
+                    last_write: stat.modified
+                })
+            },
+            Err(_) => None
         }
     }
 
-    fn form_peek_result(&self, message: String, actual_offset: uint, offset: Option < uint >) -> Option < PeekResult > {
-        match self.are_offsets_match(actual_offset, offset) {
-            true => Some(PeekResult {
-                message: message.replace("\n", ""),
-                offset: actual_offset + 2
-            }),
-            false => None
+    pub fn earliest_offset(&self) -> uint {
+        let (start, _) = self.start();
+        start
+    }
+
+    pub fn latest_offset(&self) -> uint {
+        cmp::max(self.data_start() + self.slots(), self.earliest_offset()) - 1
+    }
+
+    pub fn read_at(&self, offset: uint) -> Option < Message > {
+        if offset < self.earliest_offset() || offset > self.latest_offset() {
+            return None
+        }
+
+        let mut file = self.get_file_for_peek();
+
+        match file.seek(((offset - self.data_start()) * MESSAGE_SIZE) as i64, io::SeekSet) {
+            Ok(_) => self.read_slot(&mut file, offset),
+            Err(err) => { self.error("Unable to read message", &err); None }
         }
     }
 
-    fn get_file_for_append(&self) -> io::IoResult < io::File > {
-        self.create_unless_exists();
-        io::File::open_mode(&self.path, io::Append, io::Write)
+    pub fn create_unless_exists(&self) -> io::IoResult < () > {
+        if self.path.exists() {
+            return Ok(())
+        }
+
+        let _ = io::fs::mkdir_recursive(&self.path.dir_path(), io::USER_RWX);
+        io::File::create(&self.path).map(|_| ())
     }
 
-    fn get_file_for_peek(&self) -> io::IoResult < io::File > {
-        self.create_unless_exists();
-        io::File::open(&self.path)
+    /// Discards messages before the offset. Kept messages are written aside, then the start
+    /// of the river is saved together with the start of the data file, and only then the kept
+    /// messages replace the data file, so a crash at any step leaves the river readable and
+    /// `repair` finishes the truncation
+    pub fn truncate(&self, before: uint) -> io::IoResult < () > {
+        self.finish_truncation().and_then(|_| {
+            let earliest_offset = self.earliest_offset();
+
+            if before <= earliest_offset {
+                return Ok(())
+            }
+
+            let dropped = cmp::min(before - earliest_offset, self.slots());
+            let mut file = self.get_file_for_peek();
+            let kept = try!(file.seek((dropped * MESSAGE_SIZE) as i64, io::SeekSet).and_then(|_| file.read_to_end()));
+
+            let truncating = self.sidecar("truncating");
+            try!(io::File::create(&truncating).and_then(|mut file| {
+                try!(file.write(kept.as_slice()));
+                file.fsync()
+            }));
+
+            try!(self.save_start(before, Some(earliest_offset)));
+            try!(io::fs::rename(&truncating, &self.path));
+            self.save_start(before, None)
+        })
     }
 
-    fn get_line(&self, offset: Option < uint >) -> Option < (uint, String) > {
+    /// Removes incomplete slots from the end of the river, which are left by a crash during
+    /// the write of a batch of messages, returns the number of removed bytes
+    ///
+    /// Interrupted truncation is finished, or rolled back when the kept messages were not
+    /// completely written aside yet. Leftovers of interrupted state saving are removed as well.
+    pub fn repair(&self) -> uint {
+        match self.finish_truncation() {
+            Ok(_) => {},
+            Err(err) => self.error(format!("Unable to finish truncation of river {}", self.name).as_slice(), &err)
+        }
+
+        let _ = io::fs::unlink(&self.sidecar("start.saving"));
+        let _ = io::fs::unlink(&self.sidecar("state.saving"));
+
+        let size = match self.path.stat() {
+            Ok(stat) => stat.size.to_uint().unwrap(),
+            Err(_) => return 0
+        };
+
+        let mut length = size - size % MESSAGE_SIZE;
         let mut file = self.get_file_for_peek();
 
-        match self.seek_and_read_line(&mut file, offset) {
-            Some(string) => Some((self.get_actual_offset(offset).unwrap(), string)),
-            _ => None
+        while length > 0 {
+            let slot = match file.seek((length - MESSAGE_SIZE) as i64, io::SeekSet) {
+                Ok(_) => file.read_exact(MESSAGE_SIZE),
+                Err(err) => Err(err)
+            };
+
+            match slot {
+                Ok(ref slot) if self.is_complete(slot.as_slice()) => break,
+                _ => length -= MESSAGE_SIZE
+            }
+        }
+
+        if length < size {
+            let result = io::File::open_mode(&self.path, io::Open, io::ReadWrite)
+                .and_then(|mut file| file.truncate(length as i64));
+
+            match result {
+                Ok(_) => {},
+                Err(err) => self.error(format!("Unable to repair river {}", self.name).as_slice(), &err)
+            }
         }
+
+        size - length
     }
 
-    fn read_line(&self, file: &mut io::IoResult < io::File >) -> Option < String > {
-        let mut buf: Vec < u8 > = vec![];
+    /// States of all kinds kept alongside the river, by their kind
+    pub fn states(&self) -> TreeMap < String, String > {
+        io::File::open(&self.sidecar("state")).read_to_string().ok()
+            .and_then(|states| json::decode::< TreeMap < String, String > >(states.as_slice()).ok())
+            .unwrap_or(TreeMap::new())
+    }
+
+    pub fn save_states(&self, states: &TreeMap < String, String >) -> io::IoResult < () > {
+        let _ = io::fs::mkdir_recursive(&self.root, io::USER_RWX);
 
-        self.read_to_buf_until_eol(file, &mut buf);
-        self.convert_buf_to_string(buf)
+        let saving = self.sidecar("state.saving");
+        try!(io::File::create(&saving).write_str(json::encode(states).as_slice()));
+        io::fs::rename(&saving, &self.sidecar("state"))
     }
 
-    fn are_offsets_match(&self, actual_offset: uint, offset: Option < uint >) -> bool {
-        match offset {
-            Some(offset) => actual_offset == offset - 1,
-            None => true
+    pub fn destroy(&self) -> io::IoResult < () > {
+        let _ = io::fs::unlink(&self.sidecar("truncating"));
+        let _ = io::fs::unlink(&self.sidecar("start"));
+        let _ = io::fs::unlink(&self.sidecar("state"));
+
+        match self.path.exists() {
+            true => io::fs::unlink(&self.path),
+            false => Ok(())
         }
     }
 
-    fn abundant_bytes(&self, count: uint) -> Vec < u8 > {
-        let mut abundant_bytes: Vec < u8 > = range(0, MESSAGE_SIZE - count).map(|_| { 0 }).collect();
-        abundant_bytes[0] = '\n' as u8;
-        abundant_bytes
-    }
+    /// Offset of the first message kept in the river and, while truncation is not finished,
+    /// offset of the first message of the data file before the truncation
+    fn start(&self) -> (uint, Option < uint >) {
+        let start = io::File::open(&self.sidecar("start")).read_to_string().unwrap_or(String::new());
+        let offsets: Vec < uint > = start.as_slice().words().filter_map(|offset| from_str::< uint >(offset)).collect();
 
-    fn write_abundant_bytes(&self, file: &mut io::IoResult < io::File >, count: uint) {
-        match file.write(self.abundant_bytes(count).as_slice()) {
-            Ok(_) => {},
-            Err(err) => self.error("Unable to push message", &err)
+        match offsets.as_slice() {
+            [start, data_start] => (start, Some(data_start)),
+            [start] => (start, None),
+            _ => (1, None)
         }
     }
 
-    fn size(&self) -> uint {
-        match self.path.stat() {
-            Ok(stat) => (stat.size.to_uint().unwrap() + MESSAGE_SIZE - 1) / MESSAGE_SIZE,
-            Err(err) => { self.error("unable to get size of river", &err); 0 }
+    /// Offset of the first message in the data file, which is not replaced by the kept
+    /// messages yet during truncation
+    fn data_start(&self) -> uint {
+        match self.start() {
+            (_, Some(data_start)) if self.sidecar("truncating").exists() => data_start,
+            (start, _) => start
         }
     }
 
-    fn get_actual_offset(&self, offset: Option < uint >) -> Option < uint > {
-        let size = self.size();
-        let adjusted_offset = match offset {
-            Some(offset) => std::cmp::min(size, offset),
-            None => size
+    /// Saves the start of the river atomically and flushes it to disk
+    fn save_start(&self, start: uint, data_start: Option < uint >) -> io::IoResult < () > {
+        let content = match data_start {
+            Some(data_start) => format!("{} {}", start, data_start),
+            None => start.to_string()
         };
 
-        match adjusted_offset {
-            0 => None,
-            offset => Some(offset - 1)
-        }
+        let saving = self.sidecar("start.saving");
+        try!(io::File::create(&saving).and_then(|mut file| {
+            try!(file.write_str(content.as_slice()));
+            file.fsync()
+        }));
+
+        io::fs::rename(&saving, &self.sidecar("start"))
     }
 
-    fn get_seek_offset(&self, offset: Option < uint >) -> Option < i64 > {
-        match self.get_actual_offset(offset) {
-            Some(offset) => match (offset * MESSAGE_SIZE).to_i64() {
-                Some(seek_offset) => Some(seek_offset),
-                _ => None
+    /// Replaces the data file with the kept messages, when the start of the river was saved
+    /// before the crash, otherwise removes them
+    fn finish_truncation(&self) -> io::IoResult < () > {
+        let truncating = self.sidecar("truncating");
+
+        match self.start() {
+            (start, Some(_)) => {
+                if truncating.exists() {
+                    try!(io::fs::rename(&truncating, &self.path));
+                }
+
+                self.save_start(start, None)
             },
-            _ => None
+            (_, None) if truncating.exists() => io::fs::unlink(&truncating),
+            _ => Ok(())
         }
     }
 
-    fn seek_and_read_line(&self, file: &mut io::IoResult < io::File >, offset: Option < uint >) -> Option < String > {
-        match self.get_seek_offset(offset) {
-            Some(seek_offset) => match file.seek(seek_offset, io::SeekSet) {
-                Ok(_) => self.read_line(file),
-                _ => None
-            },
-            _ => None
+
+    fn slots(&self) -> uint {
+        match self.path.stat() {
+            Ok(stat) => stat.size.to_uint().unwrap() / MESSAGE_SIZE,
+            Err(_) => 0
         }
     }
 
-    fn read_to_buf_until_eol(&self, file: &mut io::IoResult < io::File >, buf: &mut Vec < u8 >) {
-        loop {
-            if ! self.read_one_byte_to_buf(file, buf) {
-                break
+    /// Path of a file kept alongside the river, hidden from the list of rivers
+    fn sidecar(&self, kind: &str) -> Path {
+        self.root.join(format!(".{}.{}", self.name, kind))
+    }
+
+    fn get_file_for_append(&self) -> io::IoResult < io::File > {
+        try!(self.create_unless_exists());
+        io::File::open_mode(&self.path, io::Append, io::Write)
+    }
+
+    fn get_file_for_peek(&self) -> io::IoResult < io::File > {
+        try!(self.create_unless_exists());
+        io::File::open(&self.path)
+    }
+
+    /// Payload of the message followed by the line end, its metadata and padding. Err when
+    /// they do not fit into the slot together with the end of metadata
+    fn slot(&self, message: &Message, compressed: bool) -> Result < Vec < u8 >, PushError > {
+        // compressed payload and payload with line ends are kept in base64, so the first line end
+        // of the slot always ends the payload
+        let encoded = ! compressed && message.payload.as_bytes().contains(&LINE_END);
+        let payload = if compressed {
+            match flate::deflate_bytes(message.payload.as_bytes()) {
+                Some(compressed) => compressed.as_slice().to_base64(STANDARD),
+                None => return Err(WriteFailed(format!("Unable to compress message for river {}", self.name)))
             }
+        } else if encoded {
+            message.payload.as_bytes().to_base64(STANDARD)
+        } else {
+            message.payload.clone()
+        };
+
+        let meta = json::encode(&Meta::of(message, compressed, encoded));
+
+        if payload.len() + meta.len() + 2 > MESSAGE_SIZE {
+            return Err(MessageTooLarge)
         }
+
+        let mut slot = payload.into_bytes();
+        slot.push(LINE_END);
+        slot.push_all(meta.as_bytes());
+
+        let padding = MESSAGE_SIZE - slot.len();
+        slot.push_all(Vec::from_elem(padding, META_END).as_slice());
+
+        Ok(slot)
     }
 
-    fn read_one_byte_to_buf(&self, file: &mut io::IoResult < io::File >, buf: &mut Vec < u8 >) -> bool {
-        match file.read_byte() {
-            Ok(LINE_END) => false,
-            Ok(byte) => {
-                buf.push(byte);
-                true
-            },
-            _ => false
+    fn read_slot(&self, file: &mut io::IoResult < io::File >, offset: uint) -> Option < Message > {
+        match file.read_exact(MESSAGE_SIZE) {
+            Ok(slot) => self.parse_slot(slot.as_slice(), offset),
+            Err(err) => { self.error("Unable to read message", &err); None }
         }
     }
 
+    /// Slot is complete when its payload is followed by the line end and readable metadata,
+    /// torn slots are cut short or filled with zeros by the file system instead
+    fn is_complete(&self, slot: &[u8]) -> bool {
+        let line_end = match slot.iter().position(|&byte| byte == LINE_END) {
+            Some(line_end) => line_end,
+            None => return false
+        };
+
+        let meta = slot.slice_from(line_end + 1);
+        let meta_end = meta.iter().position(|&byte| byte == META_END).unwrap_or(meta.len());
+
+        let payload_is_valid = str::from_utf8(slot.slice_to(line_end)).is_some();
+        let meta_is_valid = meta_end == 0 || str::from_utf8(meta.slice_to(meta_end))
+            .and_then(|meta| json::decode::< Meta >(meta).ok())
+            .is_some();
+
+        payload_is_valid && meta_is_valid
+    }
+
+    fn parse_slot(&self, slot: &[u8], offset: uint) -> Option < Message > {
+        let line_end = slot.iter().position(|&byte| byte == LINE_END).unwrap_or(slot.len());
+        let meta_start = cmp::min(line_end + 1, slot.len());
+        let meta_end = slot.slice_from(meta_start).iter()
+            .position(|&byte| byte == META_END)
+            .map(|position| meta_start + position)
+            .unwrap_or(slot.len());
+
+        let meta = str::from_utf8(slot.slice(meta_start, meta_end))
+            .and_then(|meta| json::decode::< Meta >(meta).ok())
+            .unwrap_or(Meta::unknown());
+
+        let payload = if meta.compressed == Some(true) {
+            str::from_utf8(slot.slice_to(line_end))
+                .and_then(|encoded| encoded.from_base64().ok())
+                .and_then(|compressed| flate::inflate_bytes(compressed.as_slice()))
+                .map(|payload| payload.as_slice().to_vec())
+        } else if meta.encoded == Some(true) {
+            str::from_utf8(slot.slice_to(line_end)).and_then(|encoded| encoded.from_base64().ok())
+        } else {
+            Some(slot.slice_to(line_end).to_vec())
+        };
+
+        payload.and_then(|payload| self.convert_buf_to_string(payload)).map(|payload| Message {
+            offset: offset,
+            timestamp: meta.timestamp,
+            payload: payload,
+            dead_letter: meta.dead_letter.clone(),
+            deliver_at: meta.deliver_at,
+            expires_at: meta.expires_at,
+            priority: meta.priority,
+            transaction: meta.transaction.clone(),
+            schema: meta.schema.clone()
+        })
+    }
+
     fn convert_buf_to_string(&self, buf: Vec < u8 >) -> Option < String > {
         match str::from_utf8(buf.as_slice()) {
             Some(string) => Some(string.to_string()),
@@ -0,0 +1,56 @@
+use serialize::json;
+
+use storage::{Storage, Message};
+use filter::Filter;
+
+/// Kind of river state keeping routing rules of the river
+pub const ROUTING_STATE: &'static str = "routing";
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Rule copying messages pushed to a river, which match the filter, to the target river
+pub struct RoutingRule {
+    /// Filter expression messages should match, see Filter
+    pub filter: String,
+    /// River to push copies of matching messages to
+    pub target: String
+}
+
+pub fn load_rules(state: Option < String >) -> Vec < RoutingRule > {
+    state
+        .and_then(|state| json::decode::< Vec < RoutingRule > >(state.as_slice()).ok())
+        .unwrap_or(vec![])
+}
+
+pub fn dump_rules(rules: &Vec < RoutingRule >) -> String {
+    json::encode(rules)
+}
+
+/// Parsed routing rules of a river
+pub struct Rules {
+    rules: Vec < (Filter, String) >
+}
+
+impl Rules {
+    /// Reads routing rules of the river, rules with malformed filter are never applied
+    pub fn of < S: Storage > (storage: &S, river: &str) -> Rules {
+        let rules = load_rules(storage.state(river, ROUTING_STATE)).iter()
+            .filter_map(|rule| Filter::parse(rule.filter.as_slice()).ok().map(|filter| (filter, rule.target.clone())))
+            .collect();
+
+        Rules { rules: rules }
+    }
+
+    /// Rivers to push copies of the message to, in order of the rules. River targeted by
+    /// several matching rules is listed once
+    pub fn targets(&self, message: &Message) -> Vec < String > {
+        let mut targets = vec![];
+
+        for &(ref filter, ref target) in self.rules.iter() {
+            if ! targets.contains(target) && filter.matches(message) {
+                targets.push(target.clone());
+            }
+        }
+
+        targets
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{TreeMap, HashMap};
+use std::sync::Arc;
+
+use serialize::json;
+use serialize::json::Json;
+
+use storage::{Storage, Message, SchemaVersion};
+use error::{PushError, SchemaViolation};
+use metrics::record_rejection;
+use filter::equals;
+
+/// Pseudo-river keeping the schema registry as its state, hidden from the list of rivers
+pub const SCHEMAS: &'static str = ".schemas";
+
+/// Kind of state of SCHEMAS pseudo-river keeping the schema registry
+pub const SCHEMAS_STATE: &'static str = "registry";
+
+/// Kind of river state keeping the schema the river is bound to
+pub const SCHEMA_STATE: &'static str = "schema";
+
+/// Maximum length in bytes of a schema name, so the name fits into metadata of every message
+/// validated against the schema
+pub const MAX_SCHEMA_NAME_LENGTH: uint = 64;
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Binds a river to a schema from the schema registry, so pushes to the river are validated
+pub struct SchemaBinding {
+    /// Name of the schema
+    pub schema: String,
+    /// Version of the schema, the latest one at the time of the push when None
+    pub version: Option < uint >
+}
+
+pub fn load_binding(state: Option < String >) -> Option < SchemaBinding > {
+    state.and_then(|state| json::decode::< Option < SchemaBinding > >(state.as_slice()).ok().and_then(|binding| binding))
+}
+
+pub fn dump_binding(binding: &Option < SchemaBinding >) -> String {
+    json::encode(binding)
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Versions of every schema, the oldest first
+pub struct Registry {
+    schemas: TreeMap < String, Vec < String > >
+}
+
+impl Registry {
+    pub fn load(state: Option < String >) -> Registry {
+        state
+            .and_then(|state| json::decode::< Registry >(state.as_slice()).ok())
+            .unwrap_or(Registry { schemas: TreeMap::new() })
+    }
+
+    /// Reads the schema registry of the storage
+    pub fn of < S: Storage > (storage: &S) -> Registry {
+        Registry::load(storage.state(SCHEMAS, SCHEMAS_STATE))
+    }
+
+    pub fn dump(&self) -> String {
+        json::encode(self)
+    }
+
+    /// Adds new version of the schema, unless it is the same as the latest one, returns
+    /// the version
+    pub fn register(&mut self, name: &str, schema: &str) -> uint {
+        let name = name.to_string();
+
+        if ! self.schemas.contains_key(&name) {
+            self.schemas.insert(name.clone(), vec![]);
+        }
+
+        let versions = self.schemas.find_mut(&name).unwrap();
+        if versions.last().map_or(true, |latest| latest.as_slice() != schema) {
+            versions.push(schema.to_string());
+        }
+
+        versions.len()
+    }
+
+    /// Version of the schema together with its source, the latest one when version is None
+    pub fn find(&self, name: &str, version: Option < uint >) -> Option < (uint, String) > {
+        self.resolve(name, version).map(|version| {
+            (version, self.schemas.find(&name.to_string()).unwrap()[version - 1].clone())
+        })
+    }
+
+    /// Registered version of the schema, the latest one when version is None
+    fn resolve(&self, name: &str, version: Option < uint >) -> Option < uint > {
+        self.schemas.find(&name.to_string()).and_then(|versions| {
+            let version = version.unwrap_or(versions.len());
+
+            if version >= 1 && version <= versions.len() { Some(version) } else { None }
+        })
+    }
+}
+
+/// Schema registry and schemas compiled for validation, kept by the storage and shared by its
+/// clones
+///
+/// The registry is decoded again only when its state has changed, every version of a schema
+/// is parsed once.
+pub struct SchemaCache {
+    registry: Option < (String, Registry) >,
+    compiled: HashMap < (String, uint), Arc < Json > >
+}
+
+impl SchemaCache {
+    /// Creates empty cache
+    pub fn new() -> SchemaCache {
+        SchemaCache {
+            registry: None,
+            compiled: HashMap::new()
+        }
+    }
+
+    /// Compiled version of the schema the river is bound to, None when it is not registered
+    fn compile < S: Storage > (&mut self, storage: &S, binding: &SchemaBinding) -> Option < (SchemaVersion, Arc < Json >) > {
+        let state = storage.state(SCHEMAS, SCHEMAS_STATE).unwrap_or(String::new());
+        let changed = match self.registry {
+            Some((ref cached, _)) => *cached != state,
+            None => true
+        };
+
+        if changed {
+            let registry = Registry::load(Some(state.clone()));
+            self.registry = Some((state, registry));
+            self.compiled.clear();
+        }
+
+        let registry = match self.registry {
+            Some((_, ref registry)) => registry,
+            None => return None
+        };
+        let name = binding.schema.as_slice();
+        let version = match registry.resolve(name, binding.version) {
+            Some(version) => version,
+            None => return None
+        };
+
+        let key = (name.to_string(), version);
+        if ! self.compiled.contains_key(&key) {
+            match registry.find(name, Some(version)).and_then(|(_, schema)| json::from_str(schema.as_slice()).ok()) {
+                Some(schema) => { self.compiled.insert(key.clone(), Arc::new(schema)); },
+                None => return None
+            }
+        }
+
+        self.compiled.find(&key).map(|schema| (SchemaVersion {
+            name: name.to_string(),
+            version: version
+        }, schema.clone()))
+    }
+}
+
+/// Checks messages pushed to a river against the schema the river is bound to
+pub struct Validator {
+    river: String,
+    binding: Option < SchemaBinding >,
+    schema: Option < (SchemaVersion, Arc < Json >) >
+}
+
+impl Validator {
+    /// Reads the schema the river is bound to, compiled schemas are cached by the storage
+    pub fn of < S: Storage > (storage: &S, river: &str) -> Validator {
+        let binding = load_binding(storage.state(river, SCHEMA_STATE));
+        let schema = binding.as_ref().and_then(|binding| storage.schemas().lock().compile(storage, binding));
+
+        Validator {
+            river: river.to_string(),
+            binding: binding,
+            schema: schema
+        }
+    }
+
+    /// Message with the version of the schema it conforms to, messages of rivers without
+    /// schema are left as they are. Rejections are counted in metrics of the storage
+    pub fn validate < S: Storage > (&self, storage: &S, message: &Message) -> Result < Message, PushError > {
+        let result = self.check(message);
+
+        match result {
+            Err(ref err) => record_rejection(storage, self.river.as_slice(), err),
+            Ok(_) => {}
+        }
+
+        result
+    }
+
+    fn check(&self, message: &Message) -> Result < Message, PushError > {
+        let (version, schema) = match (&self.binding, &self.schema) {
+            (&None, _) => return Ok(message.clone()),
+            (&Some(ref binding), &None) => return Err(SchemaViolation(
+                format!("schema {} version {} is not registered", binding.schema, binding.version.map_or("latest".to_string(), |version| version.to_string()))
+            )),
+            (_, &Some((ref version, ref schema))) => (version, schema)
+        };
+
+        let payload = match json::from_str(message.payload.as_slice()) {
+            Ok(payload) => payload,
+            Err(err) => return Err(SchemaViolation(format!("payload is not JSON: {}", err)))
+        };
+
+        match conforms(&**schema, &payload, "$") {
+            Ok(_) => Ok(Message {
+                schema: Some(version.clone()),
+                ..message.clone()
+            }),
+            Err(reason) => Err(SchemaViolation(reason))
+        }
+    }
+}
+
+/// Checks that the value conforms to JSON Schema. Supported keywords are type, enum,
+/// properties, required, additionalProperties, items, minimum, maximum, minLength,
+/// maxLength, minItems and maxItems, the rest are ignored
+pub fn conforms(schema: &Json, value: &Json, path: &str) -> Result < (), String > {
+    let schema = match schema.as_object() {
+        Some(schema) => schema,
+        None => return Ok(())
+    };
+    let keyword = |name: &str| schema.find(&name.to_string());
+
+    match keyword("type") {
+        Some(types) => {
+            let matches = match types.as_list() {
+                Some(types) => types.iter().any(|kind| kind.as_string().map_or(false, |kind| has_type(value, kind))),
+                None => types.as_string().map_or(true, |kind| has_type(value, kind))
+            };
+
+            if ! matches {
+                return Err(format!("{} should be of type {}", path, types))
+            }
+        },
+        None => {}
+    }
+
+    match keyword("enum").and_then(|values| values.as_list()) {
+        Some(values) if ! values.iter().any(|allowed| equals(value, allowed)) =>
+            return Err(format!("{} should be one of {}", path, json::List(values.clone()))),
+        _ => {}
+    }
+
+    match value.as_f64() {
+        Some(number) => {
+            match keyword("minimum").and_then(|minimum| minimum.as_f64()) {
+                Some(minimum) if number < minimum => return Err(format!("{} should be at least {}", path, minimum)),
+                _ => {}
+            }
+
+            match keyword("maximum").and_then(|maximum| maximum.as_f64()) {
+                Some(maximum) if number > maximum => return Err(format!("{} should be at most {}", path, maximum)),
+                _ => {}
+            }
+        },
+        None => {}
+    }
+
+    match value.as_string() {
+        Some(string) => {
+            let length = string.char_len() as u64;
+
+            match keyword("minLength").and_then(|min_length| min_length.as_u64()) {
+                Some(min_length) if length < min_length => return Err(format!("{} should be at least {} characters long", path, min_length)),
+                _ => {}
+            }
+
+            match keyword("maxLength").and_then(|max_length| max_length.as_u64()) {
+                Some(max_length) if length > max_length => return Err(format!("{} should be at most {} characters long", path, max_length)),
+                _ => {}
+            }
+        },
+        None => {}
+    }
+
+    match value.as_list() {
+        Some(items) => {
+            let length = items.len() as u64;
+
+            match keyword("minItems").and_then(|min_items| min_items.as_u64()) {
+                Some(min_items) if length < min_items => return Err(format!("{} should have at least {} items", path, min_items)),
+                _ => {}
+            }
+
+            match keyword("maxItems").and_then(|max_items| max_items.as_u64()) {
+                Some(max_items) if length > max_items => return Err(format!("{} should have at most {} items", path, max_items)),
+                _ => {}
+            }
+
+            match keyword("items") {
+                Some(items_schema) => for (index, item) in items.iter().enumerate() {
+                    try!(conforms(items_schema, item, format!("{}[{}]", path, index).as_slice()));
+                },
+                None => {}
+            }
+        },
+        None => {}
+    }
+
+    match value.as_object() {
+        Some(object) => {
+            match keyword("required").and_then(|required| required.as_list()) {
+                Some(required) => for name in required.iter().filter_map(|name| name.as_string()) {
+                    if ! object.contains_key(&name.to_string()) {
+                        return Err(format!("{}.{} is required", path, name))
+                    }
+                },
+                None => {}
+            }
+
+            let properties = keyword("properties").and_then(|properties| properties.as_object());
+            let additional_allowed = keyword("additionalProperties").and_then(|allowed| allowed.as_boolean()).unwrap_or(true);
+
+            for (name, property) in object.iter() {
+                let property_path = format!("{}.{}", path, name);
+
+                match properties.and_then(|properties| properties.find(name)) {
+                    Some(property_schema) => try!(conforms(property_schema, property, property_path.as_slice())),
+                    None if ! additional_allowed => return Err(format!("{} is not allowed", property_path)),
+                    None => {}
+                }
+            }
+        },
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Json, kind: &str) -> bool {
+    match kind {
+        "object" => value.is_object(),
+        "array" => value.is_list(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false
+    }
+}
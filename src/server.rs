@@ -3,74 +3,744 @@ extern crate iron;
 extern crate router;
 
 use std::io::net::ip::{Ipv4Addr, Port};
+use std::from_str::FromStr;
+use std::num::from_str_radix;
 use std::str;
+use std::collections::TreeMap;
 
 use self::router::{Router, Params};
-use self::iron::{Iron, Request, Response, IronResult};
+use self::iron::{Iron, Request, Response, IronResult, Handler, Error};
 use self::iron::status;
 
 use serialize::json;
+use time;
 
-use commands::{PeekCommand, PushCommand};
+use commands::{PeekCommand, PushCommand, SnapshotCommand, Appended, Duplicate};
+use commands::{ListRiversCommand, RiverInfoCommand, TruncateCommand, PurgeExpiredCommand};
+use commands::{ReceiveCommand, AckCommand, NackCommand};
+use commands::{ConfigureDeadLetterCommand, ReplayDeadLettersCommand, DeadLetterPolicy};
+use commands::ConfigurePriorityCommand;
+use commands::{CommitCommand, Transaction, TransactionTimedOut, Rejected, CommitNotSaved, RolledBack};
+use commands::{ConfigureQuotaCommand, Quota, PushError, RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge};
+use commands::{RegisterSchemaCommand, ConfigureSchemaCommand, SchemaBinding, SchemaViolation, WriteFailed, InvalidSchema, SchemaNotSaved};
+use commands::{ConfigureRiverCommand, RiverConfig};
+use commands::{ConfigureRoutingCommand, RoutingRule, InvalidRule, RulesNotSaved};
+use commands::{ConfigureExchangeCommand, Exchange, PublishCommand, NoExchange, PublishRejected};
+use commands::{PeekError, NoMessage, OffsetExpired, Delayed, MessageExpired, NoMatchingMessage, Filter};
+use offset::Offset;
+use storage::{Storage, FileStorage, Message, now};
+use metrics::{record_latency, render};
+
+/// Visibility timeout of received messages in milliseconds, unless specified in request
+const DEFAULT_VISIBILITY_TIMEOUT: u64 = 30000;
+
+/// Time in milliseconds between purges of messages, which outlived their time-to-live or
+/// retention of their river
+const PURGE_INTERVAL: u64 = 60000;
+
+/// Routes of the server told apart by the first segment of their path, e.g. "peek" for
+/// /peek/:river/:offset, which label latencies of requests. Other requests are labelled "other"
+static ROUTES: [&'static str, ..22] = [
+    "hello", "peek", "read", "push", "truncate", "purge", "transaction", "receive", "ack", "nack",
+    "dead_letter", "replay", "priority", "quota", "schemas", "schema", "routing", "exchanges",
+    "publish", "metrics", "snapshot", "rivers"
+];
+
+type Action < S > = fn(&S, &mut Request) -> IronResult < Response >;
+
+#[deriving(Decodable)]
+/// Push in the body of transaction request, e.g. `{"river":"orders","message":"order 42 created"}`
+struct TransactionPush {
+    river: String,
+    message: String
+}
+
+/// Tells why rivers can not have the name, rivers starting with `.` would share files with
+/// states of other rivers, names with `/` would point outside of the data directory
+const INVALID_RIVER: &'static str = "river name should not be empty, start with . or contain /";
+
+/// Whether rivers can have the name, see INVALID_RIVER
+fn is_valid_river(river: &str) -> bool {
+    ! river.is_empty() && ! river.starts_with(".") && ! river.contains("/")
+}
+
+/// Percent-decoded value of the parameter in query string of the request, e.g. `?delay=1000`
+/// or `?key=order%2042`, Err tells that the query string is not percent-encoded utf8
+fn query_param(req: &Request, name: &str) -> Result < Option < String >, String > {
+    let query = match req.url.query {
+        Some(ref query) => query,
+        None => return Ok(None)
+    };
+
+    for pair in query.as_slice().split('&') {
+        let mut parts = pair.splitn(1, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue
+        };
+
+        match percent_decode(key) {
+            Some(ref key) if key.as_slice() == name => return percent_decode(value)
+                .map(|value| Some(value))
+                .ok_or(format!("{} should be percent-encoded utf8", name)),
+            Some(_) => {},
+            None => return Err("query string should be percent-encoded utf8".to_string())
+        }
+    }
+
+    Ok(None)
+}
+
+/// Decodes percent-encoded value of a query string parameter, `+` stands for a space
+fn percent_decode(value: &str) -> Option < String > {
+    let mut bytes = vec![];
+    let mut chars = value.bytes();
+
+    loop {
+        match chars.next() {
+            Some(b'%') => {
+                let hex: Vec < u8 > = chars.by_ref().take(2).collect();
+                let byte = str::from_utf8(hex.as_slice()).and_then(|hex| from_str_radix::< u8 >(hex, 16));
+
+                match byte {
+                    Some(byte) if hex.len() == 2 => bytes.push(byte),
+                    _ => return None
+                }
+            },
+            Some(b'+') => bytes.push(b' '),
+            Some(byte) => bytes.push(byte),
+            None => break
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Filter in query string of the request, e.g. `?filter=$.type%20%3D%3D%20%22order.created%22`,
+/// Err tells why it is malformed
+fn filter_query_param(req: &Request) -> Result < Option < Filter >, String > {
+    match try!(query_param(req, "filter")) {
+        Some(filter) => Filter::parse(filter.as_slice()).map(|filter| Some(filter)),
+        None => Ok(None)
+    }
+}
+
+/// Numeric parameter in query string of the request, Err when it is not a number
+fn numeric_query_param < T: FromStr > (req: &Request, name: &str) -> Result < Option < T >, () > {
+    match query_param(req, name) {
+        Ok(Some(value)) => from_str::< T >(value.as_slice()).map(|value| Some(value)).ok_or(()),
+        Ok(None) => Ok(None),
+        Err(_) => Err(())
+    }
+}
+
+/// Response telling why it was unable to peek a message. Delayed message responds with
+/// `425 Too Early` and tells in Retry-After header how many seconds to wait for it
+fn peek_error(err: PeekError) -> IronResult < Response > {
+    match err {
+        OffsetExpired(earliest_offset) => Ok(Response::with(
+                status::Gone,
+                format!("{{\"earliest_offset\":{}}}", earliest_offset)
+                )),
+        MessageExpired(expires_at) => Ok(Response::with(
+                status::Gone,
+                format!("{{\"expired_at\":{}}}", expires_at)
+                )),
+        RolledBack => Ok(Response::with(status::Gone, "{\"rolled_back\":true}")),
+        Delayed(deliver_at) => {
+            let mut response = Response::with(
+                status::UnregisteredStatus(425, "Too Early".to_string()),
+                format!("{{\"deliver_at\":{}}}", deliver_at)
+                );
+            let now = now();
+            let seconds = if deliver_at > now { (deliver_at - now + 999) / 1000 } else { 0 };
+
+            response.headers.extensions.insert("Retry-After".to_string(), seconds.to_string());
+            Ok(response)
+        },
+        NoMessage => Ok(Response::with(status::NotFound, "")),
+        NoMatchingMessage(offset) => Ok(Response::with(
+                status::NotFound,
+                format!("{{\"offset\":{}}}", offset)
+                ))
+    }
+}
+
+/// Response to a rejected push: quota of the river asks the producer to slow down, while
+/// quota of the storage tells it is full
+fn push_error(err: PushError) -> IronResult < Response > {
+    match err {
+        RiverQuotaExceeded => Ok(Response::with(status::TooManyRequests, "quota of the river exceeded")),
+        StorageQuotaExceeded => Ok(Response::with(status::InsufficientStorage, "quota of the storage exceeded")),
+        MessageTooLarge => Ok(Response::with(status::RequestEntityTooLarge, "message is greater than maximum message size of the river")),
+        SchemaViolation(reason) => Ok(Response::with(status::UnprocessableEntity, reason)),
+        WriteFailed(reason) => Ok(Response::with(status::InternalServerError, reason))
+    }
+}
+
+/// Route handler, which calls its action with the storage of the server
+struct Endpoint < S > {
+    storage: S,
+    action: Action < S >
+}
+
+impl < S: Storage > Handler for Endpoint < S > {
+    fn call(&self, req: &mut Request) -> IronResult < Response > {
+        let river = req.extensions.find::< Router, Params >().and_then(|params| params.find("river"));
+
+        match river {
+            Some(river) if ! is_valid_river(river) => Ok(Response::with(status::BadRequest, INVALID_RIVER)),
+            _ => (self.action)(&self.storage, req)
+        }
+    }
+
+    fn catch(&self, _: &mut Request, err: Box < Error >) -> (Response, IronResult < () >) {
+        (Response::with(status::InternalServerError, ""), Err(err))
+    }
+}
+
+/// Router, which records latencies of requests in metrics of the storage
+struct Instrumented < S > {
+    router: Router,
+    storage: S
+}
+
+impl < S: Storage > Handler for Instrumented < S > {
+    fn call(&self, req: &mut Request) -> IronResult < Response > {
+        let started = time::precise_time_ns();
+        let result = self.router.call(req);
+        let seconds = (time::precise_time_ns() - started) as f64 / 1e9;
+
+        let route = match req.url.path.as_slice().head() {
+            Some(segment) => ROUTES.iter().find(|&&route| route == segment.as_slice()).map_or("other", |&route| route),
+            None => "other"
+        };
+        record_latency(&self.storage, route, req.method.to_string().as_slice(), seconds);
+
+        result
+    }
+
+    fn catch(&self, req: &mut Request, err: Box < Error >) -> (Response, IronResult < () >) {
+        self.router.catch(req, err)
+    }
+}
 
 /// Http Server to make pushes, peeks and clears
-pub struct Server {
+///
+/// Server uses FileStorage when created with `::new()`, use `::with_storage()`
+/// to serve rivers from another storage
+pub struct Server < S > {
     port: Port,
+    storage: S
 }
 
-impl Server {
-    /// Creates new instance of server
-    pub fn new(port: Port) -> Server {
+impl Server < FileStorage > {
+    /// Creates new instance of server, repairing rivers torn by a crash
+    pub fn new(port: Port) -> Server < FileStorage > {
+        let storage = FileStorage::new();
+        storage.repair();
+
+        Server::with_storage(port, storage)
+    }
+}
+
+impl < S: Storage > Server < S > {
+    /// Creates new instance of server working with specified storage
+    pub fn with_storage(port: Port, storage: S) -> Server < S > {
         Server {
             port: port,
+            storage: storage
         }
     }
 
-    /// Starts listening server on specified port
+    /// Starts listening server on specified port, purging messages, which outlived their
+    /// time-to-live or retention of their river, once a minute
     pub fn start(&mut self) {
         let mut router = Router::new();
 
-        router.get("/hello/:name", Server::hello);
-        router.get("/peek/:river", Server::peek);
-        router.get("/peek/:river/:offset", Server::peek);
-        router.post("/push/:river", Server::push);
+        router.get("/hello/:name", self.endpoint(Server::hello));
+        router.get("/peek/:river", self.endpoint(Server::peek));
+        router.get("/peek/:river/:offset", self.endpoint(Server::peek));
+        router.get("/read/:river/:offset", self.endpoint(Server::read));
+        router.post("/push/:river", self.endpoint(Server::push));
+        router.post("/truncate/:river/:offset", self.endpoint(Server::truncate));
+        router.post("/purge", self.endpoint(Server::purge));
+        router.post("/purge/:river", self.endpoint(Server::purge));
+        router.post("/transaction", self.endpoint(Server::transaction));
+        router.post("/receive/:river", self.endpoint(Server::receive));
+        router.post("/receive/:river/:timeout", self.endpoint(Server::receive));
+        router.post("/ack/:river/:offset/:receipt", self.endpoint(Server::ack));
+        router.post("/nack/:river/:offset/:receipt", self.endpoint(Server::nack));
+        router.post("/dead_letter/:river", self.endpoint(Server::dead_letter));
+        router.post("/replay/:river", self.endpoint(Server::replay));
+        router.post("/priority/:river", self.endpoint(Server::priority));
+        router.post("/quota", self.endpoint(Server::quota));
+        router.post("/quota/:river", self.endpoint(Server::quota));
+        router.post("/schemas/:name", self.endpoint(Server::register_schema));
+        router.get("/schemas/:name", self.endpoint(Server::schema));
+        router.get("/schemas/:name/:version", self.endpoint(Server::schema));
+        router.post("/schema/:river", self.endpoint(Server::bind_schema));
+        router.get("/routing/:river", self.endpoint(Server::routing));
+        router.post("/routing/:river", self.endpoint(Server::configure_routing));
+        router.post("/exchanges/:exchange", self.endpoint(Server::declare_exchange));
+        router.get("/exchanges/:exchange", self.endpoint(Server::exchange));
+        router.post("/publish/:exchange/:key", self.endpoint(Server::publish));
+        router.get("/metrics", self.endpoint(Server::metrics));
+        router.get("/snapshot", self.endpoint(Server::snapshot));
+        router.get("/rivers", self.endpoint(Server::rivers));
+        router.get("/rivers/:river", self.endpoint(Server::river));
+        router.get("/rivers/:river/config", self.endpoint(Server::config));
+        router.put("/rivers/:river/config", self.endpoint(Server::configure));
+
+        PurgeExpiredCommand::with_storage(self.storage.clone()).start(PURGE_INTERVAL);
+
+        Iron::new(Instrumented {
+            router: router,
+            storage: self.storage.clone()
+        }).listen(Ipv4Addr(0, 0, 0, 0), self.port);
+    }
 
-        Iron::new(router).listen(Ipv4Addr(0, 0, 0, 0), self.port);
+    fn endpoint(&self, action: Action < S >) -> Endpoint < S > {
+        Endpoint {
+            storage: self.storage.clone(),
+            action: action
+        }
     }
 
-    fn hello(req: &mut Request) -> IronResult < Response > {
+    fn hello(_: &S, req: &mut Request) -> IronResult < Response > {
         let params = req.extensions.find::< Router, Params >().unwrap();
         let name = params.find("name").unwrap();
 
         Ok(Response::with(status::Ok, format!("Hello, {}!", name)))
     }
 
-    fn peek(req: &mut Request) -> IronResult < Response > {
+    fn peek(storage: &S, req: &mut Request) -> IronResult < Response > {
         let params = req.extensions.find::< Router, Params >().unwrap();
         let river = params.find("river").unwrap();
         let offset = from_str::< uint >(params.find("offset").unwrap_or(""));
+        let command = PeekCommand::with_storage(storage.clone());
+
+        let result = match filter_query_param(req) {
+            Ok(Some(filter)) => command.peek_matching(river, offset, &filter),
+            Ok(None) => command.peek(river, offset),
+            Err(reason) => return Ok(Response::with(status::BadRequest, reason))
+        };
 
-        match PeekCommand::new().execute(river, offset) {
-            Some(result) => Ok(Response::with(
+        match result {
+            Ok(result) => Ok(Response::with(
                     status::Ok,
                     json::encode(&result)
                     )),
-            _ => Ok(Response::with(status::NotFound, ""))
+            Err(err) => peek_error(err)
+        }
+    }
+
+    fn read(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let command = PeekCommand::with_storage(storage.clone());
+
+        let filter = match filter_query_param(req) {
+            Ok(filter) => filter,
+            Err(reason) => return Ok(Response::with(status::BadRequest, reason))
+        };
+
+        let offset = match from_str::< Offset >(params.find("offset").unwrap()) {
+            Some(offset) => offset,
+            None => return Ok(Response::with(status::BadRequest, "offset should be earliest, latest, earliest+n, latest-n or a number"))
+        };
+
+        let result = match filter {
+            Some(ref filter) => command.read_matching(river, offset, filter),
+            None => command.read(river, offset)
+        };
+
+        match result {
+            Ok(message) => Ok(Response::with(status::Ok, json::encode(&message))),
+            Err(err) => peek_error(err)
         }
     }
 
-    fn push(req: &mut Request) -> IronResult < Response > {
+    fn push(storage: &S, req: &mut Request) -> IronResult < Response > {
         let params = req.extensions.find::< Router, Params >().unwrap();
         let river = params.find("river").unwrap();
         let message = str::from_utf8(req.body.as_slice());
 
-        match message {
-            Some(message) => {
-                PushCommand::new().execute(river, message);
-                Ok(Response::with(status::Created, ""))
+        let attributes = (
+            numeric_query_param::< u64 >(req, "deliver_at"),
+            numeric_query_param::< u64 >(req, "delay"),
+            numeric_query_param::< u64 >(req, "ttl"),
+            numeric_query_param::< uint >(req, "priority")
+        );
+        let (deliver_at, delay, ttl, priority) = match attributes {
+            (Ok(deliver_at), Ok(delay), Ok(ttl), Ok(priority)) => (deliver_at, delay, ttl, priority),
+            _ => return Ok(Response::with(status::BadRequest, "deliver_at, delay, ttl and priority should be numbers"))
+        };
+
+        // keys of producers with `:` in their ids may collide, e.g. `a:b` seq `c` and `a` seq `b:c`
+        let key = match (query_param(req, "key"), query_param(req, "producer"), query_param(req, "seq")) {
+            (Ok(Some(key)), _, _) => Some(key),
+            (Ok(None), Ok(Some(ref producer)), Ok(Some(_))) if producer.as_slice().contains(":") =>
+                return Ok(Response::with(status::BadRequest, "producer should not contain :")),
+            (Ok(None), Ok(Some(producer)), Ok(Some(seq))) => Some(format!("{}:{}", producer, seq)),
+            (Ok(None), Ok(_), Ok(_)) => None,
+            _ => return Ok(Response::with(status::BadRequest, "key, producer and seq should be percent-encoded utf8"))
+        };
+
+        let message = match message {
+            Some(message) => Message {
+                deliver_at: deliver_at.or(delay.map(|delay| now() + delay)),
+                expires_at: ttl.map(|ttl| now() + ttl),
+                priority: priority,
+                ..Message::new(message)
+            },
+            None => return Ok(Response::with(status::BadRequest, "unable to parse response body as utf8"))
+        };
+        let command = PushCommand::with_storage(storage.clone());
+
+        match key {
+            Some(key) => match command.push_once(river, key.as_slice(), &message) {
+                Ok(Appended(offset)) => Ok(Response::with(status::Created, format!("{{\"offset\":{}}}", offset))),
+                Ok(Duplicate(offset)) => Ok(Response::with(status::Ok, format!("{{\"offset\":{}}}", offset))),
+                Err(err) => push_error(err)
+            },
+            None => match command.push(river, &message) {
+                Ok(offset) => Ok(Response::with(status::Created, format!("{{\"offset\":{}}}", offset))),
+                Err(err) => push_error(err)
+            }
+        }
+
+    }
+
+    fn transaction(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let pushes = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Vec < TransactionPush > >(body).ok());
+
+        let mut transaction = Transaction::new();
+        match pushes {
+            Some(ref pushes) if pushes.iter().any(|push| ! is_valid_river(push.river.as_slice())) =>
+                return Ok(Response::with(status::BadRequest, INVALID_RIVER)),
+            Some(pushes) => for push in pushes.iter() {
+                transaction.push(push.river.as_slice(), push.message.as_slice());
+            },
+            None => return Ok(Response::with(status::BadRequest, "body should be a list of pushes"))
+        }
+
+        match CommitCommand::with_storage(storage.clone()).execute(&transaction) {
+            Ok(offsets) => Ok(Response::with(status::Created, format!("{{\"offsets\":{}}}", json::encode(&offsets)))),
+            Err(TransactionTimedOut) => Ok(Response::with(status::Conflict, "transaction timed out and was rolled back")),
+            Err(Rejected(err)) => push_error(err),
+            Err(CommitNotSaved) => Ok(Response::with(status::InternalServerError, "transaction log was not saved, transaction was rolled back"))
+        }
+    }
+
+    fn truncate(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+
+        match from_str::< uint >(params.find("offset").unwrap()) {
+            Some(before) => {
+                TruncateCommand::with_storage(storage.clone()).execute(river, before);
+                Ok(Response::with(status::Ok, ""))
+            },
+            None => Ok(Response::with(status::BadRequest, "offset should be a number"))
+        }
+    }
+
+    fn purge(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let command = PurgeExpiredCommand::with_storage(storage.clone());
+
+        let purged = match params.find("river") {
+            Some(river) => command.execute(river),
+            None => command.execute_all()
+        };
+
+        Ok(Response::with(status::Ok, format!("{{\"purged\":{}}}", purged)))
+    }
+
+    fn receive(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let timeout = match params.find("timeout") {
+            Some(timeout) => match from_str::< u64 >(timeout) {
+                Some(timeout) => timeout,
+                None => return Ok(Response::with(status::BadRequest, "timeout should be a number"))
+            },
+            None => DEFAULT_VISIBILITY_TIMEOUT
+        };
+
+        match ReceiveCommand::with_storage(storage.clone()).execute(river, timeout) {
+            Some(result) => Ok(Response::with(status::Ok, json::encode(&result))),
+            None => Ok(Response::with(status::NotFound, ""))
+        }
+    }
+
+    fn ack(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+
+        match (from_str::< uint >(params.find("offset").unwrap()), from_str::< u64 >(params.find("receipt").unwrap())) {
+            (Some(offset), Some(receipt)) => {
+                if AckCommand::with_storage(storage.clone()).execute(river, offset, receipt) {
+                    Ok(Response::with(status::Ok, ""))
+                } else {
+                    Ok(Response::with(status::Conflict, "lease is expired"))
+                }
+            },
+            _ => Ok(Response::with(status::BadRequest, "offset and receipt should be numbers"))
+        }
+    }
+
+    fn nack(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+
+        let reason = match str::from_utf8(req.body.as_slice()) {
+            Some("") => "nacked",
+            Some(reason) => reason,
+            None => return Ok(Response::with(status::BadRequest, "unable to parse response body as utf8"))
+        };
+
+        match (from_str::< uint >(params.find("offset").unwrap()), from_str::< u64 >(params.find("receipt").unwrap())) {
+            (Some(offset), Some(receipt)) => {
+                if NackCommand::with_storage(storage.clone()).reject(river, offset, receipt, reason) {
+                    Ok(Response::with(status::Ok, ""))
+                } else {
+                    Ok(Response::with(status::Conflict, "lease is expired"))
+                }
+            },
+            _ => Ok(Response::with(status::BadRequest, "offset and receipt should be numbers"))
+        }
+    }
+
+    fn dead_letter(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let policy = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Option < DeadLetterPolicy > >(body).ok());
+
+        match policy {
+            Some(Some(ref policy)) if ! is_valid_river(policy.river.as_slice()) =>
+                Ok(Response::with(status::BadRequest, INVALID_RIVER)),
+            Some(policy) => match ConfigureDeadLetterCommand::with_storage(storage.clone()).execute(river, policy) {
+                Ok(_) => Ok(Response::with(status::Ok, "")),
+                Err(reason) => Ok(Response::with(status::BadRequest, reason))
+            },
+            None => Ok(Response::with(status::BadRequest, "body should be a dead-letter policy or null"))
+        }
+    }
+
+    fn quota(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let river = req.extensions.find::< Router, Params >().and_then(|params| params.find("river"));
+        let quota = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Option < Quota > >(body).ok());
+
+        let command = ConfigureQuotaCommand::with_storage(storage.clone());
+        match (quota, river) {
+            (Some(quota), Some(river)) => command.execute(river, quota),
+            (Some(quota), None) => command.execute_for_storage(quota),
+            (None, _) => return Ok(Response::with(status::BadRequest, "body should be a quota or null"))
+        }
+
+        Ok(Response::with(status::Ok, ""))
+    }
+
+    fn routing(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let rules = ConfigureRoutingCommand::with_storage(storage.clone()).rules(river);
+
+        Ok(Response::with(status::Ok, json::encode(&rules)))
+    }
+
+    fn configure_routing(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let rules = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Vec < RoutingRule > >(body).ok());
+
+        let rules = match rules {
+            Some(ref rules) if rules.iter().any(|rule| ! is_valid_river(rule.target.as_slice())) =>
+                return Ok(Response::with(status::BadRequest, INVALID_RIVER)),
+            Some(rules) => rules,
+            None => return Ok(Response::with(status::BadRequest, "body should be a list of routing rules"))
+        };
+
+        match ConfigureRoutingCommand::with_storage(storage.clone()).execute(river, rules) {
+            Ok(_) => Ok(Response::with(status::Ok, "")),
+            Err(InvalidRule(reason)) => Ok(Response::with(status::BadRequest, reason)),
+            Err(RulesNotSaved(reason)) => Ok(Response::with(status::InternalServerError, reason))
+        }
+    }
+
+    fn declare_exchange(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let name = params.find("exchange").unwrap();
+        let exchange = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Option < Exchange > >(body).ok());
+
+        match exchange {
+            Some(Some(ref exchange)) if exchange.bindings.iter().any(|binding| ! is_valid_river(binding.river.as_slice())) =>
+                Ok(Response::with(status::BadRequest, INVALID_RIVER)),
+            Some(exchange) => {
+                ConfigureExchangeCommand::with_storage(storage.clone()).execute(name, exchange);
+                Ok(Response::with(status::Ok, ""))
+            },
+            None => Ok(Response::with(status::BadRequest, "body should be an exchange or null"))
+        }
+    }
+
+    fn exchange(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let name = params.find("exchange").unwrap();
+
+        match ConfigureExchangeCommand::with_storage(storage.clone()).exchange(name) {
+            Some(exchange) => Ok(Response::with(status::Ok, json::encode(&exchange))),
+            None => Ok(Response::with(status::NotFound, ""))
+        }
+    }
+
+    fn publish(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let name = params.find("exchange").unwrap();
+        let key = params.find("key").unwrap();
+
+        let message = match str::from_utf8(req.body.as_slice()) {
+            Some(message) => Message::new(message),
+            None => return Ok(Response::with(status::BadRequest, "unable to parse request body as utf8"))
+        };
+
+        match PublishCommand::with_storage(storage.clone()).publish(name, key, &message) {
+            Ok(pushes) => {
+                let offsets: TreeMap < String, uint > = pushes.iter().map(|&(ref river, offset)| (river.clone(), offset)).collect();
+                Ok(Response::with(status::Created, format!("{{\"offsets\":{}}}", json::encode(&offsets))))
+            },
+            Err(NoExchange) => Ok(Response::with(status::NotFound, "")),
+            Err(PublishRejected(TransactionTimedOut)) => Ok(Response::with(status::Conflict, "transaction timed out and was rolled back")),
+            Err(PublishRejected(Rejected(err))) => push_error(err),
+            Err(PublishRejected(CommitNotSaved)) => Ok(Response::with(status::InternalServerError, "transaction log was not saved, transaction was rolled back"))
+        }
+    }
+
+    fn register_schema(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let name = params.find("name").unwrap();
+
+        let schema = match str::from_utf8(req.body.as_slice()) {
+            Some(schema) => schema,
+            None => return Ok(Response::with(status::BadRequest, "unable to parse request body as utf8"))
+        };
+
+        match RegisterSchemaCommand::with_storage(storage.clone()).execute(name, schema) {
+            Ok(version) => Ok(Response::with(status::Created, format!("{{\"version\":{}}}", version))),
+            Err(InvalidSchema(reason)) => Ok(Response::with(status::BadRequest, reason)),
+            Err(SchemaNotSaved(reason)) => Ok(Response::with(status::InternalServerError, reason))
+        }
+    }
+
+    fn schema(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let name = params.find("name").unwrap();
+
+        let version = match params.find("version") {
+            Some(version) => match from_str::< uint >(version) {
+                Some(version) => Some(version),
+                None => return Ok(Response::with(status::BadRequest, "version should be a number"))
+            },
+            None => None
+        };
+
+        match RegisterSchemaCommand::with_storage(storage.clone()).schema(name, version) {
+            Some((_, schema)) => Ok(Response::with(status::Ok, schema)),
+            None => Ok(Response::with(status::NotFound, ""))
+        }
+    }
+
+    fn bind_schema(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let binding = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< Option < SchemaBinding > >(body).ok());
+
+        match binding {
+            Some(binding) => {
+                ConfigureSchemaCommand::with_storage(storage.clone()).execute(river, binding);
+                Ok(Response::with(status::Ok, ""))
             },
-            None => Ok(Response::with(status::BadRequest, "unable to parse response body as utf8"))
+            None => Ok(Response::with(status::BadRequest, "body should be a schema binding or null"))
         }
+    }
+
+    fn config(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let config = ConfigureRiverCommand::with_storage(storage.clone()).config(river);
+
+        Ok(Response::with(status::Ok, json::encode(&config)))
+    }
 
+    fn configure(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let config = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< RiverConfig >(body).ok());
+
+        match config {
+            Some(config) => {
+                ConfigureRiverCommand::with_storage(storage.clone()).execute(river, &config);
+                Ok(Response::with(status::Ok, json::encode(&config)))
+            },
+            None => Ok(Response::with(status::BadRequest, "body should be a river configuration"))
+        }
+    }
+
+    fn priority(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let prioritized = str::from_utf8(req.body.as_slice())
+            .and_then(|body| json::decode::< bool >(body).ok());
+
+        match prioritized {
+            Some(prioritized) => {
+                ConfigurePriorityCommand::with_storage(storage.clone()).execute(river, prioritized);
+                Ok(Response::with(status::Ok, ""))
+            },
+            None => Ok(Response::with(status::BadRequest, "body should be true or false"))
+        }
+    }
+
+    fn replay(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+        let replayed = ReplayDeadLettersCommand::with_storage(storage.clone()).execute(river);
+
+        Ok(Response::with(status::Ok, format!("{{\"replayed\":{}}}", replayed)))
+    }
+
+    fn snapshot(storage: &S, _: &mut Request) -> IronResult < Response > {
+        let snapshot = SnapshotCommand::with_storage(storage.clone()).execute();
+
+        Ok(Response::with(status::Ok, json::encode(&snapshot)))
+    }
+
+    fn metrics(storage: &S, _: &mut Request) -> IronResult < Response > {
+        Ok(Response::with(status::Ok, render(storage)))
+    }
+
+    fn rivers(storage: &S, _: &mut Request) -> IronResult < Response > {
+        let rivers = ListRiversCommand::with_storage(storage.clone()).execute();
+
+        Ok(Response::with(status::Ok, json::encode(&rivers)))
+    }
+
+    fn river(storage: &S, req: &mut Request) -> IronResult < Response > {
+        let params = req.extensions.find::< Router, Params >().unwrap();
+        let river = params.find("river").unwrap();
+
+        match RiverInfoCommand::with_storage(storage.clone()).execute(river) {
+            Some(info) => Ok(Response::with(status::Ok, json::encode(&info))),
+            None => Ok(Response::with(status::NotFound, ""))
+        }
     }
 }
@@ -0,0 +1,653 @@
+use std::collections::{HashMap, TreeMap};
+use std::io;
+use std::mem;
+use std::sync::{Arc, Weak, Mutex, Once, ONCE_INIT};
+
+use serialize::{Encodable, Encoder};
+
+use time;
+
+use river::{River, MESSAGE_SIZE};
+use config::{CONFIG_STATE, load_config};
+use error::{PushError, WriteFailed};
+use quota;
+use schema::SchemaCache;
+use metrics::Metrics;
+
+const RIVERS_ROOT: &'static str = "./tmp/rivers";
+
+#[deriving(Decodable, Clone, Show, PartialEq)]
+/// Message of a river together with its offset and metadata
+pub struct Message {
+    /// Offset of the message itself, the one to specify to peek command to read it
+    pub offset: uint,
+    /// Time of the push in milliseconds since Unix epoch, 0 for messages pushed
+    /// before john started to record it
+    pub timestamp: u64,
+    /// Contains message
+    pub payload: String,
+    /// Origin of the message, when it was moved to a dead-letter river
+    pub dead_letter: Option < DeadLetter >,
+    /// Time in milliseconds since Unix epoch, until which the message is held back from
+    /// peeks and queue consumers
+    pub deliver_at: Option < u64 >,
+    /// Time in milliseconds since Unix epoch, after which the message is worthless to readers
+    /// and can be purged
+    pub expires_at: Option < u64 >,
+    /// Priority of the message in a priority river, greater is more urgent, 0 when not specified
+    pub priority: Option < uint >,
+    /// Id of the transaction the message was pushed in, the message is visible to readers
+    /// only when the transaction is committed
+    pub transaction: Option < String >,
+    /// Schema the message was validated against, when its river is bound to a schema
+    pub schema: Option < SchemaVersion >
+}
+
+impl Message {
+    /// Creates new message pushed right now. Its offset is assigned by storage on append
+    pub fn new(payload: &str) -> Message {
+        Message {
+            offset: 0,
+            timestamp: now(),
+            payload: payload.to_string(),
+            dead_letter: None,
+            deliver_at: None,
+            expires_at: None,
+            priority: None,
+            transaction: None,
+            schema: None
+        }
+    }
+
+    /// Creates new message pushed right now, which expires after `ttl` milliseconds
+    pub fn with_ttl(payload: &str, ttl: u64) -> Message {
+        let message = Message::new(payload);
+
+        Message {
+            expires_at: Some(message.timestamp + ttl),
+            ..message
+        }
+    }
+
+    /// Tells whether the message is held back from readers at the specified time
+    pub fn is_delayed(&self, now: u64) -> bool {
+        match self.deliver_at {
+            Some(deliver_at) => deliver_at > now,
+            None => false
+        }
+    }
+
+    /// Tells whether the message is expired at the specified time
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false
+        }
+    }
+}
+
+/// Metadata missing from the message is omitted, so messages without it are encoded
+/// the same way as before the metadata was introduced
+impl < E, S: Encoder < E > > Encodable < S, E > for Message {
+    fn encode(&self, s: &mut S) -> Result < (), E > {
+        s.emit_struct("Message", 9, |s| {
+            try!(s.emit_struct_field("offset", 0, |s| self.offset.encode(s)));
+            try!(s.emit_struct_field("timestamp", 1, |s| self.timestamp.encode(s)));
+            try!(s.emit_struct_field("payload", 2, |s| self.payload.encode(s)));
+
+            match self.dead_letter {
+                Some(ref dead_letter) => try!(s.emit_struct_field("dead_letter", 3, |s| dead_letter.encode(s))),
+                None => {}
+            }
+
+            match self.deliver_at {
+                Some(deliver_at) => try!(s.emit_struct_field("deliver_at", 4, |s| deliver_at.encode(s))),
+                None => {}
+            }
+
+            match self.expires_at {
+                Some(expires_at) => try!(s.emit_struct_field("expires_at", 5, |s| expires_at.encode(s))),
+                None => {}
+            }
+
+            match self.priority {
+                Some(priority) => try!(s.emit_struct_field("priority", 6, |s| priority.encode(s))),
+                None => {}
+            }
+
+            match self.transaction {
+                Some(ref transaction) => try!(s.emit_struct_field("transaction", 7, |s| transaction.encode(s))),
+                None => {}
+            }
+
+            match self.schema {
+                Some(ref schema) => s.emit_struct_field("schema", 8, |s| schema.encode(s)),
+                None => Ok(())
+            }
+        })
+    }
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Metadata of a message moved to a dead-letter river after failed deliveries
+pub struct DeadLetter {
+    /// River the message was received from
+    pub river: String,
+    /// Offset of the message in that river, 0 for an output of a derived or aggregated river,
+    /// which was never appended to it
+    pub offset: uint,
+    /// Why the last delivery failed
+    pub reason: String,
+    /// Number of failed deliveries
+    pub attempts: uint
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Version of a schema from the schema registry
+pub struct SchemaVersion {
+    /// Name of the schema
+    pub name: String,
+    /// Version of the schema, versions of every schema start from 1
+    pub version: uint
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Result of ListRiversCommand and RiverInfoCommand, describes a river
+pub struct RiverInfo {
+    /// Name of the river
+    pub name: String,
+    /// Offset of the first message, None when river is empty
+    pub earliest_offset: Option < uint >,
+    /// Offset of the last message, None when river is empty
+    pub latest_offset: Option < uint >,
+    /// Number of messages in the river
+    pub message_count: uint,
+    /// Size of the river in bytes
+    pub bytes: u64,
+    /// Time of the last write to the river in milliseconds since Unix epoch
+    pub last_write: u64
+}
+
+#[deriving(Clone, Show, PartialEq)]
+/// Bytes and messages taken by all rivers of a storage together, as counted by RiverInfo
+pub struct Usage {
+    /// Number of bytes
+    pub bytes: u64,
+    /// Number of messages
+    pub messages: uint
+}
+
+#[deriving(Clone, Show, PartialEq)]
+/// Incomplete message removed from the end of a river by `FileStorage::repair()`
+pub struct TornWrite {
+    /// Name of the river
+    pub river: String,
+    /// Offset the incomplete message would have had
+    pub offset: uint,
+    /// Number of bytes removed from the river
+    pub bytes: uint
+}
+
+/// Current time in milliseconds since Unix epoch
+pub fn now() -> u64 {
+    let now = time::get_time();
+
+    now.sec as u64 * 1000 + now.nsec as u64 / 1000000
+}
+
+/// Storage of rivers, all commands and Server are generic over it
+///
+/// Offsets of messages in a river start from 1 and grow by 1 with each append.
+/// Truncating a river discards its oldest messages, but does not change offsets
+/// of the rest. Storage is cloned for every server thread, so clones should share
+/// the rivers.
+pub trait Storage: Clone + Send + Sync {
+    /// Creates empty river unless it exists, Err tells why it was not created
+    fn create(&self, river: &str) -> Result < (), String >;
+
+    /// Appends message to the river, creating the river when it does not exist. Offset of the
+    /// message is assigned by storage and returned
+    ///
+    /// The message is rejected when it does not fit into quotas or maximum message size of the
+    /// river (see ConfigureQuotaCommand and ConfigureRiverCommand). Oldest messages of the river
+    /// are dropped, when they outlived its retention or a quota tells to drop them.
+    fn append(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        quota::append(self, river, message)
+    }
+
+    /// Appends message to the river as is, regardless of quotas and configuration of the river,
+    /// `append` uses it once the message fits. Err(MessageTooLarge) when the message does not
+    /// fit into the storage together with its metadata
+    fn write(&self, river: &str, message: &Message) -> Result < uint, PushError >;
+
+    /// Runs `appends` serialized with other appends run by this method, so concurrent appends
+    /// do not exceed quotas. Appends may be run while updating a state, but not the other way
+    fn serialize_appends < T > (&self, appends: || -> T) -> T;
+
+    /// Runs `read` while appends, truncations and updates of states wait, so it sees all rivers
+    /// and their states as they were at one moment. `read` should only read the storage
+    fn freeze < T > (&self, read: || -> T) -> T;
+
+    /// Bytes and messages taken by all rivers together
+    fn usage(&self) -> Usage;
+
+    /// Schema registry and schemas compiled for validating pushes, shared by clones
+    fn schemas(&self) -> &Mutex < SchemaCache >;
+
+    /// Metrics recorded by commands and servers working with the storage, shared by clones
+    fn metrics(&self) -> &Mutex < Metrics >;
+
+    /// Reads message at offset, returns None when there is no such message
+    fn read(&self, river: &str, offset: uint) -> Option < Message >;
+
+    /// Offset of the first message kept in the river, `latest_offset + 1` when river is empty
+    fn earliest_offset(&self, river: &str) -> uint;
+
+    /// Offset of the last message in the river, `earliest_offset - 1` when river is empty
+    fn latest_offset(&self, river: &str) -> uint;
+
+    /// Discards all messages with offsets less than `before`. When `before` is greater than
+    /// `latest_offset + 1` the river becomes empty and its next message gets offset `before`.
+    /// Err tells why the river was left as it was
+    fn truncate(&self, river: &str, before: uint) -> Result < (), String >;
+
+    /// Describes the river, returns None when river does not exist
+    fn info(&self, river: &str) -> Option < RiverInfo >;
+
+    /// Names of all rivers sorted by name
+    fn rivers(&self) -> Vec < String >;
+
+    /// Number of bytes the message would take in a river, as counted by `RiverInfo::bytes`
+    fn message_size(&self, message: &Message) -> u64;
+
+    /// Removes the river with all its messages and states, Err tells why it was not removed
+    fn destroy(&self, river: &str) -> Result < (), String >;
+
+    /// Reads state of the specified kind kept alongside the river (e.g. leases of the queue)
+    fn state(&self, river: &str, kind: &str) -> Option < String >;
+
+    /// Atomically replaces state of the specified kind with the result of `update`, which gets
+    /// current state. Updates of states are serialized, so `update` should not update states.
+    /// When the new state fails to be saved, the state stays as it was and Err tells why
+    fn update_state(&self, river: &str, kind: &str, update: |Option < String >| -> String) -> Result < (), String >;
+}
+
+#[deriving(Clone)]
+/// Storage keeping every river in a file of fixed-size message slots
+///
+/// Writes are serialized between all storages with the same root in the process, whether they
+/// are clones or created separately, so the server should be the only process writing to its
+/// rivers. Messages may be appended while updating a state, so state lock is always taken
+/// before the lock of serialized appends, which is taken before write lock. Usage of the
+/// storage is counted once and then kept up to date by writes, states of rivers (including
+/// their configuration) are read once and then kept up to date by their updates.
+///
+/// Every river has a background writer, which appends messages pushed concurrently by
+/// storages with the same root with a single write (and a single flush to disk, when the river
+/// is durable). Appends return once their batch is written, the writer stops with the last
+/// storage in the root. A writer, which stopped unexpectedly, is replaced with the next append.
+///
+/// ```
+/// // rivers in ./tmp/rivers, the default for all commands and Server
+/// let storage = john::FileStorage::new();
+///
+/// // rivers in another directory
+/// let storage = john::FileStorage::in_root(&Path::new("./tmp/other_rivers"));
+/// ```
+pub struct FileStorage {
+    root: Path,
+    shared: Arc < Shared >
+}
+
+/// Locks and background writers shared by all storages with the same root
+struct Shared {
+    write_lock: Arc < Mutex < () > >,
+    state_lock: Mutex < () >,
+    append_lock: Mutex < () >,
+    writers: Mutex < HashMap < String, Sender < Append > > >,
+    usage: Arc < Mutex < Option < Usage > > >,
+    states: States,
+    schemas: Mutex < SchemaCache >,
+    metrics: Arc < Mutex < Metrics > >
+}
+
+/// States of rivers read so far, by the river
+type States = Arc < Mutex < HashMap < String, TreeMap < String, String > > > >;
+
+/// State of the river from the cache, the states of the river are read unless they are cached
+fn cached_state(states: &States, river: &River, name: &str, kind: &str) -> Option < String > {
+    let mut states = states.lock();
+
+    if ! states.contains_key(&name.to_string()) {
+        states.insert(name.to_string(), river.states());
+    }
+
+    states.find(&name.to_string()).unwrap().find(&kind.to_string()).map(|state| state.clone())
+}
+
+/// Shared state of every root having storages, by the root path
+type Registry = Mutex < HashMap < String, Weak < Shared > > >;
+
+static mut REGISTRY: *const Registry = 0 as *const Registry;
+static REGISTRY_INIT: Once = ONCE_INIT;
+
+/// Message waiting for the background writer of its river
+struct Append {
+    message: Message,
+    offset: Sender < Result < uint, PushError > >
+}
+
+impl FileStorage {
+    /// Constructor ::new()
+    ///
+    /// Creates file storage with rivers in `./tmp/rivers`
+    pub fn new() -> FileStorage {
+        FileStorage::in_root(&Path::new(RIVERS_ROOT))
+    }
+
+    /// Creates file storage with rivers in specified directory
+    pub fn in_root(root: &Path) -> FileStorage {
+        FileStorage {
+            root: root.clone(),
+            shared: Shared::in_root(root)
+        }
+    }
+
+    /// Removes incomplete messages from the end of every river, which are left by a crash in
+    /// the middle of a push, and reports them to stderr
+    ///
+    /// Server repairs its storage when created with `::new()`. Rivers should not be written
+    /// while repairing, otherwise a push in progress may be taken for a torn one.
+    pub fn repair(&self) -> Vec < TornWrite > {
+        let _lock = self.shared.write_lock.lock();
+        let mut torn_writes = vec![];
+
+        for name in River::names_in(&self.root).iter() {
+            let river = self.river(name.as_slice());
+            let bytes = river.repair();
+
+            if bytes > 0 {
+                torn_writes.push(TornWrite {
+                    river: name.clone(),
+                    offset: river.latest_offset() + 1,
+                    bytes: bytes
+                });
+            }
+        }
+
+        *self.shared.usage.lock() = None;
+
+        let mut stderr = io::stderr();
+        for torn_write in torn_writes.iter() {
+            let _ = writeln!(stderr, "Removed {} bytes of incomplete message at offset {} of river {}",
+                             torn_write.bytes, torn_write.offset, torn_write.river);
+        }
+
+        torn_writes
+    }
+
+    fn river(&self, river: &str) -> River {
+        River::in_root(&self.root, river)
+    }
+
+    /// Changes usage of the storage, if it was counted, by the change of the river made
+    /// by `write`. Must be called while writes are locked
+    fn track_usage < T > (&self, river: &str, write: || -> T) -> T {
+        let before = self.river(river).info();
+        let result = write();
+        let after = self.river(river).info();
+
+        match *self.shared.usage.lock() {
+            Some(ref mut usage) => {
+                for info in before.iter() {
+                    usage.bytes -= info.bytes;
+                    usage.messages -= info.message_count;
+                }
+
+                for info in after.iter() {
+                    usage.bytes += info.bytes;
+                    usage.messages += info.message_count;
+                }
+            },
+            None => {}
+        }
+
+        result
+    }
+
+    /// Forgets the writer of the river, which stopped, so the next append starts a new one
+    fn evict_writer(&self, river: &str) {
+        self.shared.writers.lock().remove(&river.to_string());
+    }
+
+    /// Background writer of the river, started with the first append to it
+    fn writer(&self, river: &str) -> Sender < Append > {
+        let mut writers = self.shared.writers.lock();
+
+        if ! writers.contains_key(&river.to_string()) {
+            let (sender, receiver) = channel();
+            let target = self.river(river);
+            let name = river.to_string();
+            let write_lock = self.shared.write_lock.clone();
+            let usage = self.shared.usage.clone();
+            let states = self.shared.states.clone();
+
+            spawn(proc() FileStorage::write_batches(target, name, write_lock, usage, states, receiver));
+            writers.insert(river.to_string(), sender);
+        }
+
+        writers.find(&river.to_string()).unwrap().clone()
+    }
+
+    /// Appends all messages waiting for the writer at once, until every sender is gone
+    fn write_batches(river: River, name: String, write_lock: Arc < Mutex < () > >, usage: Arc < Mutex < Option < Usage > > >,
+                     states: States, appends: Receiver < Append >) {
+        for append in appends.iter() {
+            let mut batch = vec![append];
+            loop {
+                match appends.try_recv() {
+                    Ok(append) => batch.push(append),
+                    Err(_) => break
+                }
+            }
+
+            let offsets = {
+                let _lock = write_lock.lock();
+                let messages: Vec < &Message > = batch.iter().map(|append| &append.message).collect();
+                let config = load_config(cached_state(&states, &river, name.as_slice(), CONFIG_STATE));
+                let offsets = river.append_all(messages.as_slice(), &config);
+
+                match *usage.lock() {
+                    Some(ref mut usage) => {
+                        let appended = offsets.iter().filter(|offset| offset.is_ok()).count();
+                        usage.bytes += (appended * MESSAGE_SIZE) as u64;
+                        usage.messages += appended;
+                    },
+                    None => {}
+                }
+
+                offsets
+            };
+
+            for (append, offset) in batch.iter().zip(offsets.iter()) {
+                let _ = append.offset.send_opt(offset.clone());
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// State shared by storages in the root, created unless another storage in the root is alive.
+    /// The registry of roots is the only global of john, so that commands created with `::new()`
+    /// write to the default root in turn
+    fn in_root(root: &Path) -> Arc < Shared > {
+        let registry = unsafe {
+            REGISTRY_INIT.doit(|| {
+                let registry: Box < Registry > = box Mutex::new(HashMap::new());
+                REGISTRY = &*registry as *const Registry;
+                mem::forget(registry);
+            });
+
+            &*REGISTRY
+        };
+
+        let key = root.display().to_string();
+        let mut roots = registry.lock();
+
+        match roots.find(&key).and_then(|shared| shared.upgrade()) {
+            Some(shared) => return shared,
+            None => {}
+        }
+
+        let shared = Arc::new(Shared {
+            write_lock: Arc::new(Mutex::new(())),
+            state_lock: Mutex::new(()),
+            append_lock: Mutex::new(()),
+            writers: Mutex::new(HashMap::new()),
+            usage: Arc::new(Mutex::new(None)),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            schemas: Mutex::new(SchemaCache::new()),
+            metrics: Arc::new(Mutex::new(Metrics::new()))
+        });
+
+        roots.insert(key, shared.downgrade());
+        shared
+    }
+}
+
+impl Storage for FileStorage {
+    fn create(&self, river: &str) -> Result < (), String > {
+        self.river(river).create_unless_exists()
+            .map_err(|err| format!("Unable to create river {}: {}", river, err))
+    }
+
+    fn write(&self, river: &str, message: &Message) -> Result < uint, PushError > {
+        let (sender, receiver) = channel();
+        let mut append = Append {
+            message: message.clone(),
+            offset: sender
+        };
+
+        // the message has not reached a writer, which stopped before it was sent
+        for _ in range(0u, 2) {
+            match self.writer(river).send_opt(append) {
+                Ok(_) => return match receiver.recv_opt() {
+                    Ok(offset) => offset,
+                    Err(_) => {
+                        self.evict_writer(river);
+                        Err(WriteFailed(format!("Writer of river {} stopped", river)))
+                    }
+                },
+                Err(unsent) => {
+                    self.evict_writer(river);
+                    append = unsent;
+                }
+            }
+        }
+
+        Err(WriteFailed(format!("Writer of river {} stopped", river)))
+    }
+
+    fn read(&self, river: &str, offset: uint) -> Option < Message > {
+        self.river(river).read_at(offset)
+    }
+
+    fn earliest_offset(&self, river: &str) -> uint {
+        self.river(river).earliest_offset()
+    }
+
+    fn latest_offset(&self, river: &str) -> uint {
+        self.river(river).latest_offset()
+    }
+
+    fn truncate(&self, river: &str, before: uint) -> Result < (), String > {
+        let _lock = self.shared.write_lock.lock();
+        self.track_usage(river, || self.river(river).truncate(before))
+            .map_err(|err| format!("Unable to truncate river {}: {}", river, err))
+    }
+
+    fn info(&self, river: &str) -> Option < RiverInfo > {
+        self.river(river).info()
+    }
+
+    fn rivers(&self) -> Vec < String > {
+        River::names_in(&self.root)
+    }
+
+    fn message_size(&self, _: &Message) -> u64 {
+        MESSAGE_SIZE as u64
+    }
+
+    fn serialize_appends < T > (&self, appends: || -> T) -> T {
+        let _lock = self.shared.append_lock.lock();
+        appends()
+    }
+
+    fn freeze < T > (&self, read: || -> T) -> T {
+        let _state_lock = self.shared.state_lock.lock();
+        let _append_lock = self.shared.append_lock.lock();
+        let _lock = self.shared.write_lock.lock();
+        read()
+    }
+
+    fn usage(&self) -> Usage {
+        let _lock = self.shared.write_lock.lock();
+        let mut usage = self.shared.usage.lock();
+
+        if usage.is_none() {
+            let infos: Vec < RiverInfo > = self.rivers().iter().filter_map(|river| self.info(river.as_slice())).collect();
+
+            *usage = Some(Usage {
+                bytes: infos.iter().fold(0, |bytes, info| bytes + info.bytes),
+                messages: infos.iter().fold(0, |messages, info| messages + info.message_count)
+            });
+        }
+
+        usage.clone().unwrap()
+    }
+
+    fn schemas(&self) -> &Mutex < SchemaCache > {
+        &self.shared.schemas
+    }
+
+    fn metrics(&self) -> &Mutex < Metrics > {
+        &*self.shared.metrics
+    }
+
+    fn destroy(&self, river: &str) -> Result < (), String > {
+        let _state_lock = self.shared.state_lock.lock();
+        let _lock = self.shared.write_lock.lock();
+        self.shared.states.lock().remove(&river.to_string());
+        self.track_usage(river, || self.river(river).destroy())
+            .map_err(|err| format!("Unable to clear river {}: {}", river, err))
+    }
+
+    fn state(&self, river: &str, kind: &str) -> Option < String > {
+        cached_state(&self.shared.states, &self.river(river), river, kind)
+    }
+
+    fn update_state(&self, river: &str, kind: &str, update: |Option < String >| -> String) -> Result < (), String > {
+        let _lock = self.shared.state_lock.lock();
+        let state = update(self.state(river, kind));
+
+        let target = self.river(river);
+        let key = river.to_string();
+        let mut states = self.shared.states.lock();
+
+        if ! states.contains_key(&key) {
+            states.insert(key.clone(), target.states());
+        }
+
+        let mut river_states = states.find(&key).unwrap().clone();
+        river_states.insert(kind.to_string(), state);
+
+        // states, which failed to be saved, are read from disk again
+        match target.save_states(&river_states) {
+            Ok(_) => { states.insert(key, river_states); Ok(()) },
+            Err(err) => {
+                states.remove(&key);
+                Err(format!("Unable to save state {} of river {}: {}", kind, river, err))
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::cmp;
+use std::collections::TreeMap;
+use std::rand;
+
+use serialize::json;
+
+use storage::{Storage, Message};
+
+/// Pseudo-river keeping the transaction log as its state, hidden from the list of rivers
+pub const TRANSACTIONS: &'static str = ".transactions";
+
+/// Kind of state of TRANSACTIONS pseudo-river keeping the transaction log
+pub const TRANSACTIONS_STATE: &'static str = "log";
+
+/// Time in milliseconds, after which transaction in progress is considered aborted,
+/// e.g. when the server crashed in the middle of its commit
+const TRANSACTION_TIMEOUT: u64 = 60000;
+
+#[deriving(Show, PartialEq, Clone)]
+/// Tells whether messages of a transaction are visible to readers
+pub enum TransactionStatus {
+    InProgress,
+    Committed,
+    Aborted
+}
+
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+/// Transactions in progress and committed ones, shared by all rivers of the storage
+///
+/// Messages of unknown transactions are never visible, so aborted transactions are simply
+/// forgotten. Committed transactions are kept together with the greatest offset of their
+/// messages in every river, until compaction passes all their messages.
+///
+/// Compaction reads messages of every river from its compacted offset on, until it meets
+/// a message of a transaction in progress. Messages before the compacted offset are visible,
+/// unless they are remembered as rolled back, so committed transactions are forgotten once
+/// compacted offsets of all their rivers are past their messages.
+pub struct TransactionLog {
+    in_progress: TreeMap < String, u64 >,
+    committed: TreeMap < String, Vec < (String, uint) > >,
+    compacted: TreeMap < String, uint >,
+    rolled_back: TreeMap < String, Vec < uint > >
+}
+
+#[deriving(Decodable)]
+/// Transaction log saved before it was compacted
+struct UncompactedLog {
+    in_progress: TreeMap < String, u64 >,
+    committed: TreeMap < String, Vec < (String, uint) > >
+}
+
+impl TransactionLog {
+    pub fn load(state: Option < String >) -> TransactionLog {
+        let empty = TransactionLog {
+            in_progress: TreeMap::new(),
+            committed: TreeMap::new(),
+            compacted: TreeMap::new(),
+            rolled_back: TreeMap::new()
+        };
+
+        let state = match state {
+            Some(state) => state,
+            None => return empty
+        };
+
+        match json::decode::< TransactionLog >(state.as_slice()) {
+            Ok(log) => log,
+            Err(_) => match json::decode::< UncompactedLog >(state.as_slice()) {
+                Ok(log) => TransactionLog { in_progress: log.in_progress, committed: log.committed, ..empty },
+                Err(_) => empty
+            }
+        }
+    }
+
+    /// Reads the transaction log of the storage
+    pub fn of < S: Storage > (storage: &S) -> TransactionLog {
+        TransactionLog::load(storage.state(TRANSACTIONS, TRANSACTIONS_STATE))
+    }
+
+    pub fn dump(&self) -> String {
+        json::encode(self)
+    }
+
+    /// Generates id of a new transaction
+    pub fn new_id() -> String {
+        format!("{:016x}", rand::random::< u64 >())
+    }
+
+    pub fn begin(&mut self, id: &str, now: u64) {
+        self.in_progress.insert(id.to_string(), now);
+    }
+
+    /// Commits the transaction, which appended messages up to the specified offsets of the
+    /// rivers, unless it has timed out and is considered aborted by now
+    pub fn commit(&mut self, id: &str, offsets: Vec < (String, uint) >, now: u64) -> bool {
+        let committed = self.status(id, now) == InProgress;
+
+        self.in_progress.remove(&id.to_string());
+        if committed {
+            self.committed.insert(id.to_string(), offsets);
+        }
+
+        committed
+    }
+
+    pub fn abort(&mut self, id: &str) {
+        self.in_progress.remove(&id.to_string());
+    }
+
+    /// Forgets timed out transactions, and committed ones, all messages of which are passed
+    /// by compaction
+    pub fn compact < S: Storage > (&mut self, storage: &S, now: u64) {
+        let timed_out: Vec < String > = self.in_progress.iter()
+            .filter(|&(_, &started_at)| started_at + TRANSACTION_TIMEOUT < now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in timed_out.iter() {
+            self.in_progress.remove(id);
+        }
+
+        let mut last_offsets: TreeMap < String, uint > = TreeMap::new();
+        for offsets in self.committed.values() {
+            for &(ref river, offset) in offsets.iter() {
+                let last_offset = last_offsets.find(river).map_or(offset, |&last_offset| cmp::max(offset, last_offset));
+                last_offsets.insert(river.clone(), last_offset);
+            }
+        }
+
+        for (river, &last_offset) in last_offsets.iter() {
+            self.compact_river(storage, river.as_slice(), last_offset, now);
+        }
+
+        let compacted: Vec < String > = self.committed.iter()
+            .filter(|&(_, offsets)| offsets.iter().all(|&(ref river, offset)| offset < self.compacted_offset(river.as_slice())))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in compacted.iter() {
+            self.committed.remove(id);
+        }
+    }
+
+    /// Moves compacted offset of the river past the messages of known transactions up to
+    /// `last_offset`, remembering the rolled back ones. A river, which became shorter than its
+    /// compacted offset, was cleared, so it is compacted from the start again
+    fn compact_river < S: Storage > (&mut self, storage: &S, river: &str, last_offset: uint, now: u64) {
+        let earliest_offset = storage.earliest_offset(river);
+        let mut offset = self.compacted_offset(river);
+
+        if offset > storage.latest_offset(river) + 1 {
+            offset = 0;
+            self.rolled_back.remove(&river.to_string());
+        }
+
+        let mut rolled_back: Vec < uint > = self.rolled_back.find(&river.to_string())
+            .map_or(vec![], |offsets| offsets.iter().filter(|&&offset| offset >= earliest_offset).map(|&offset| offset).collect());
+
+        offset = cmp::max(offset, earliest_offset);
+        while offset <= last_offset {
+            let status = match storage.read(river, offset) {
+                Some(Message { transaction: Some(ref id), .. }) => self.status(id.as_slice(), now),
+                Some(_) => Committed,
+                None => break
+            };
+
+            match status {
+                Committed => {},
+                InProgress => break,
+                Aborted => rolled_back.push(offset)
+            }
+
+            offset += 1;
+        }
+
+        self.compacted.insert(river.to_string(), offset);
+        if rolled_back.is_empty() {
+            self.rolled_back.remove(&river.to_string());
+        } else {
+            self.rolled_back.insert(river.to_string(), rolled_back);
+        }
+    }
+
+    fn compacted_offset(&self, river: &str) -> uint {
+        self.compacted.find(&river.to_string()).map_or(0, |&offset| offset)
+    }
+
+    /// Status of the transaction, which appended the message at the offset of the river
+    pub fn status_at(&self, id: &str, river: &str, offset: uint, now: u64) -> TransactionStatus {
+        if self.rolled_back.find(&river.to_string()).map_or(false, |offsets| offsets.contains(&offset)) {
+            return Aborted
+        }
+
+        if offset < self.compacted_offset(river) {
+            return Committed
+        }
+
+        self.status(id, now)
+    }
+
+    /// Whether the transaction, which appended messages up to the offsets of the rivers, is
+    /// committed, even when compaction has forgotten it
+    pub fn is_committed(&self, id: &str, offsets: &[(String, uint)], now: u64) -> bool {
+        match offsets.head() {
+            Some(&(ref river, offset)) => self.status_at(id, river.as_slice(), offset, now) == Committed,
+            None => self.status(id, now) == Committed
+        }
+    }
+
+    /// Status of the transaction by its id, Aborted once it is forgotten by compaction
+    pub fn status(&self, id: &str, now: u64) -> TransactionStatus {
+        if self.committed.contains_key(&id.to_string()) {
+            return Committed
+        }
+
+        match self.in_progress.find(&id.to_string()) {
+            Some(&started_at) if started_at + TRANSACTION_TIMEOUT >= now => InProgress,
+            _ => Aborted
+        }
+    }
+}
+
+/// Transaction log of the storage, which is read once status of a transaction is needed,
+/// so reading messages pushed without transactions does not read the log
+pub struct Transactions < 'a, S: 'a > {
+    storage: &'a S,
+    log: Option < TransactionLog >
+}
+
+impl < 'a, S: Storage > Transactions < 'a, S > {
+    pub fn of(storage: &'a S) -> Transactions < 'a, S > {
+        Transactions {
+            storage: storage,
+            log: None
+        }
+    }
+
+    /// Status of the transaction, which appended the message at the offset of the river
+    pub fn status(&mut self, id: &str, river: &str, offset: uint, now: u64) -> TransactionStatus {
+        if self.log.is_none() {
+            self.log = Some(TransactionLog::of(self.storage));
+        }
+
+        self.log.as_ref().unwrap().status_at(id, river, offset, now)
+    }
+
+    /// Status of the transaction the message of the river was pushed in, Committed when it
+    /// was pushed without transaction
+    pub fn status_of(&mut self, river: &str, message: &Message, now: u64) -> TransactionStatus {
+        match message.transaction {
+            Some(ref id) => self.status(id.as_slice(), river, message.offset, now),
+            None => Committed
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::TreeMap;
+
+use serialize::json;
+
+use storage::Message;
+
+/// Pseudo-river keeping progress of derived rivers as its state, hidden from the list of rivers
+pub const TRANSFORMS: &'static str = ".transforms";
+
+/// Kind of state of TRANSFORMS pseudo-river keeping the next input offset of every derived river
+pub const TRANSFORMS_STATE: &'static str = "offsets";
+
+/// Kind of state of TRANSFORMS pseudo-river keeping, for every derived river, the input offset
+/// being processed and the number of its outputs already pushed
+pub const PUSHED_STATE: &'static str = "pushed";
+
+/// Function deriving messages of one river from messages of another, see DeriveRiverCommand
+pub enum Transform {
+    /// Pushes one message for every input message
+    MapTransform(fn(&Message) -> Message),
+    /// Pushes input messages, for which the function returns true, as they are
+    FilterTransform(fn(&Message) -> bool),
+    /// Pushes any number of messages for every input message
+    FlatMapTransform(fn(&Message) -> Vec < Message >)
+}
+
+impl Transform {
+    /// Messages to push for the input message, they keep metadata set by the function except
+    /// offset, transaction and schema
+    pub fn apply(&self, message: &Message) -> Vec < Message > {
+        let outputs = match *self {
+            MapTransform(map) => vec![map(message)],
+            FilterTransform(filter) => if filter(message) { vec![message.clone()] } else { vec![] },
+            FlatMapTransform(flat_map) => flat_map(message)
+        };
+
+        outputs.iter().map(|output| Message {
+            offset: 0,
+            transaction: None,
+            schema: None,
+            ..output.clone()
+        }).collect()
+    }
+}
+
+pub fn load_offsets(state: Option < String >) -> TreeMap < String, uint > {
+    state
+        .and_then(|state| json::decode::< TreeMap < String, uint > >(state.as_slice()).ok())
+        .unwrap_or(TreeMap::new())
+}
+
+pub fn dump_offsets(offsets: &TreeMap < String, uint >) -> String {
+    json::encode(offsets)
+}
+
+pub fn load_pushed(state: Option < String >) -> TreeMap < String, (uint, uint) > {
+    state
+        .and_then(|state| json::decode::< TreeMap < String, (uint, uint) > >(state.as_slice()).ok())
+        .unwrap_or(TreeMap::new())
+}
+
+pub fn dump_pushed(pushed: &TreeMap < String, (uint, uint) >) -> String {
+    json::encode(pushed)
+}
@@ -0,0 +1,208 @@
+extern crate john;
+
+use std::io;
+
+use john::{Cluster, ClusterStorage, PeekResult, NoLeader, NoQuorum};
+use john::{PushCommand, PeekCommand, ConfigureQuotaCommand, Quota, RejectPushes};
+use john::{Message, Storage, FileStorage};
+
+/// Removes rivers and Raft state left by the previous run of the test
+fn clear(name: &str) {
+    let _ = io::fs::rmdir_recursive(&Path::new("./tmp/clusters").join(name));
+}
+
+fn new_cluster(name: &str, size: uint) -> Cluster {
+    clear(name);
+    Cluster::new(name, size)
+}
+
+#[test]
+fn pushing_to_cluster_replicates_message_to_every_node() {
+    let mut cluster = new_cluster("replication", 3);
+
+    assert_eq!(Ok(()), cluster.push("a river", "hello world"));
+
+    for node in range(0u, 3) {
+        match cluster.peek_on(node, "a river", None) {
+            Some(PeekResult { message, offset }) => {
+                assert_eq!("hello world", message.as_slice());
+                assert_eq!(2, offset);
+            },
+            _ => panic!("Message should have been replicated to node {}", node)
+        }
+    }
+}
+
+#[test]
+fn acknowledged_push_survives_loss_of_leader() {
+    let mut cluster = new_cluster("leader loss", 5);
+
+    cluster.push("a river", "message 1").unwrap();
+    cluster.push("a river", "message 2").unwrap();
+
+    let old_leader = cluster.leader().unwrap();
+    cluster.stop(old_leader);
+
+    match cluster.peek("a river", None) {
+        Some(PeekResult { message, .. }) => assert_eq!("message 2", message.as_slice()),
+        _ => panic!("New leader should have the message")
+    }
+
+    assert!(cluster.leader() != Some(old_leader));
+}
+
+#[test]
+fn pushing_without_majority_fails() {
+    let mut cluster = new_cluster("no majority", 3);
+
+    cluster.push("a river", "message 1").unwrap();
+
+    let leader = cluster.leader().unwrap();
+    cluster.stop((leader + 1) % 3);
+    cluster.stop((leader + 2) % 3);
+
+    assert_eq!(Err(NoQuorum), cluster.push("a river", "message 2"));
+
+    cluster.stop(leader);
+
+    assert_eq!(Err(NoLeader), cluster.push("a river", "message 3"));
+}
+
+#[test]
+fn push_without_majority_is_never_applied() {
+    let mut cluster = new_cluster("retracted push", 3);
+
+    cluster.push("a river", "message 1").unwrap();
+
+    let leader = cluster.leader().unwrap();
+    cluster.stop((leader + 1) % 3);
+    cluster.stop((leader + 2) % 3);
+
+    assert_eq!(Err(NoQuorum), cluster.push("a river", "lost"));
+
+    cluster.start((leader + 1) % 3);
+    cluster.start((leader + 2) % 3);
+
+    assert_eq!(Ok(()), cluster.push("a river", "message 2"));
+
+    for node in range(0u, 3) {
+        match cluster.peek_on(node, "a river", Some(2)) {
+            Some(PeekResult { message, offset }) => {
+                assert_eq!("message 2", message.as_slice());
+                assert_eq!(3, offset);
+            },
+            _ => panic!("Node {} should have the second message at offset 2", node)
+        }
+    }
+}
+
+#[test]
+fn restarted_node_catches_up_with_leader() {
+    let mut cluster = new_cluster("catch up", 3);
+
+    cluster.push("a river", "message 1").unwrap();
+
+    let lagging = (cluster.leader().unwrap() + 1) % 3;
+    cluster.stop(lagging);
+
+    cluster.push("a river", "message 2").unwrap();
+    cluster.push("a river", "message 3").unwrap();
+
+    cluster.start(lagging);
+
+    match cluster.peek_on(lagging, "a river", None) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 3", message.as_slice());
+            assert_eq!(4, offset);
+        },
+        _ => panic!("Restarted node should have caught up")
+    }
+}
+
+#[test]
+fn node_lagging_behind_snapshot_receives_it() {
+    let mut cluster = new_cluster("snapshot", 3);
+
+    cluster.push("a river", "message 0").unwrap();
+
+    let lagging = (cluster.leader().unwrap() + 1) % 3;
+    cluster.stop(lagging);
+
+    for i in range(1u, 200) {
+        cluster.push("a river", format!("message {}", i).as_slice()).unwrap();
+    }
+
+    cluster.start(lagging);
+
+    match cluster.peek_on(lagging, "a river", Some(150)) {
+        Some(PeekResult { message, .. }) => assert_eq!("message 149", message.as_slice()),
+        _ => panic!("Restarted node should have received a snapshot")
+    }
+}
+
+#[test]
+fn restarted_cluster_resumes_where_it_stopped() {
+    {
+        let mut cluster = new_cluster("restart", 3);
+        cluster.push("a river", "message 1").unwrap();
+        cluster.push("a river", "message 2").unwrap();
+    }
+
+    let mut cluster = Cluster::new("restart", 3);
+
+    assert_eq!(Ok(()), cluster.push("a river", "message 3"));
+
+    for node in range(0u, 3) {
+        match cluster.peek_on(node, "a river", Some(2)) {
+            Some(PeekResult { message, offset }) => {
+                assert_eq!("message 2", message.as_slice());
+                assert_eq!(3, offset);
+            },
+            _ => panic!("Node {} should have kept its messages", node)
+        }
+    }
+
+    match cluster.peek("a river", None) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 3", message.as_slice());
+            assert_eq!(4, offset);
+        },
+        _ => panic!("Restarted cluster should have the new message")
+    }
+}
+
+#[test]
+fn pushing_through_cluster_storage() {
+    clear("storage");
+    let storage = ClusterStorage::new("storage", 3);
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("a river", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(1),
+        policy: RejectPushes
+    }));
+    assert_eq!(Ok(1), PushCommand::with_storage(storage.clone()).push("a river", &Message::new("hello")));
+    assert!(PushCommand::with_storage(storage.clone()).push("a river", &Message::new("rejected")).is_err());
+
+    for node in range(0u, 3) {
+        let node_storage = FileStorage::in_root(&Path::new(format!("./tmp/clusters/storage/node-{}/rivers", node)));
+        assert_eq!(1, node_storage.latest_offset("a river"));
+        assert!(node_storage.state("a river", "quota").is_some());
+    }
+
+    let leader = storage.cluster().lock().leader().unwrap();
+    storage.cluster().lock().stop(leader);
+
+    match PeekCommand::with_storage(storage.clone()).execute("a river", None) {
+        Some(PeekResult { message, .. }) => assert_eq!("hello", message.as_slice()),
+        _ => panic!("New leader should serve the message")
+    }
+
+    let leader = storage.cluster().lock().leader().unwrap();
+    storage.cluster().lock().stop(leader);
+
+    assert!(PushCommand::with_storage(storage.clone()).push("another river", &Message::new("lost")).is_err());
+    assert!(storage.update_state("a river", "quota", |_| "null".to_string()).is_err());
+    assert!(storage.truncate("a river", 2).is_err());
+    assert!(PeekCommand::with_storage(storage.clone()).execute("a river", None).is_none());
+}
@@ -1,7 +1,36 @@
 extern crate john;
 extern crate test;
+extern crate serialize;
+
+use std::io;
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+
+use serialize::json;
 
 use john::{PushCommand, PeekCommand, PeekResult, ClearCommand};
+use john::{SnapshotCommand, RestoreCommand, Snapshot, RiverSnapshot};
+use john::{ExportCommand, ImportCommand, Message, MalformedLine};
+use john::{ListRiversCommand, RiverInfoCommand, RiverInfo};
+use john::{FileStorage, MemoryStorage, TornWrite, Usage};
+use john::{TruncateCommand, NoMessage, OffsetExpired, Delayed, MessageExpired, PurgeExpiredCommand};
+use john::{ReceiveCommand, AckCommand, NackCommand};
+use john::{ConfigureDeadLetterCommand, ReplayDeadLettersCommand, DeadLetterPolicy, DeadLetter, Storage};
+use john::ConfigurePriorityCommand;
+use john::{Appended, Duplicate};
+use john::{CommitCommand, Transaction, RolledBack};
+use john::{Offset, Earliest, Latest, At, FromEarliest, FromLatest};
+use john::{ConfigureQuotaCommand, Quota, RejectPushes, DropOldest};
+use john::{RiverQuotaExceeded, StorageQuotaExceeded, MessageTooLarge, WriteFailed, Rejected};
+use john::{ConfigureRiverCommand, RiverConfig, QueueMode};
+use john::{Filter, NoMatchingMessage};
+use john::{ConfigureExchangeCommand, PublishCommand, Exchange, DirectExchange, FanoutExchange, TopicExchange};
+use john::{NoExchange, PublishRejected};
+use john::{ConfigureRoutingCommand, RoutingRule, InvalidRule};
+use john::{AggregateCommand, Aggregation, TumblingWindow, SlidingWindow};
+use john::MetricsCommand;
+use john::{DeriveRiverCommand, MapTransform, FilterTransform, FlatMapTransform};
+use john::{RegisterSchemaCommand, ConfigureSchemaCommand, SchemaBinding, SchemaVersion, SchemaViolation, InvalidSchema};
 
 const RIVER_SIZE: int = 100i;
 
@@ -93,6 +122,1875 @@ fn clearing_empty_river() {
     }
 }
 
+#[test]
+fn taking_a_snapshot_of_rivers() {
+    ClearCommand::new().execute("a river 6");
+    PushCommand::new().execute("a river 6", "message 1");
+    PushCommand::new().execute("a river 6", "message 2");
+
+    let snapshot = SnapshotCommand::new().execute();
+
+    match snapshot.rivers.iter().find(|river| river.name.as_slice() == "a river 6") {
+        Some(river) => {
+            let payloads: Vec < &str > = river.messages.iter().map(|m| m.payload.as_slice()).collect();
+
+            assert_eq!(vec!["message 1", "message 2"], payloads);
+            assert_eq!(vec![1, 2], river.messages.iter().map(|m| m.offset).collect::< Vec < uint > >());
+            assert_eq!(3, river.offset);
+        },
+        None => panic!("Snapshot should contain the river")
+    }
+}
+
+#[test]
+fn restoring_rivers_from_a_snapshot() {
+    let root = Path::new("./tmp/restored_rivers");
+    let snapshot = Snapshot {
+        rivers: vec![RiverSnapshot {
+            name: "restored river".to_string(),
+            offset: 3,
+            messages: vec![
+                Message { offset: 1, timestamp: 1414000000000, ..Message::new("message 1") },
+                Message { offset: 2, timestamp: 1414000001000, ..Message::new("message 2") }
+            ],
+            states: None
+        }],
+        transactions: None,
+        states: None
+    };
+
+    RestoreCommand::with_storage(FileStorage::in_root(&root)).execute(&snapshot).unwrap();
+    RestoreCommand::with_storage(FileStorage::in_root(&root)).execute(&snapshot).unwrap();
+
+    let path = root.join("restored river");
+    assert_eq!(2 * 4096, path.stat().unwrap().size);
+
+    let contents = io::File::open(&path).read_to_end().unwrap();
+    assert_eq!(b"message 1\n".as_slice(), contents.slice(0, 10));
+    assert_eq!(b"message 2\n".as_slice(), contents.slice(4096, 4106));
+}
+
+#[test]
+fn exporting_a_river_as_json_lines() {
+    ClearCommand::new().execute("a river 7");
+    PushCommand::new().execute("a river 7", "message 1");
+    PushCommand::new().execute("a river 7", "message \"2\"");
+    PushCommand::new().execute("a river 7", "message 3");
+
+    let mut out = io::MemWriter::new();
+    assert_eq!(2, ExportCommand::new().execute("a river 7", Some(2), None, &mut out).unwrap());
+
+    let exported = String::from_utf8(out.unwrap()).unwrap();
+    let lines: Vec < &str > = exported.as_slice().lines().collect();
+
+    assert_eq!(2, lines.len());
+    assert!(lines[0].starts_with("{\"offset\":2,\"timestamp\":"));
+    assert!(lines[0].ends_with(",\"payload\":\"message \\\"2\\\"\"}"));
+    assert!(lines[1].starts_with("{\"offset\":3,"));
+}
+
+#[test]
+fn importing_json_lines_into_a_river() {
+    ClearCommand::new().execute("a river 8");
+    PushCommand::new().execute("a river 8", "message 0");
+
+    let input = "{\"offset\":5,\"timestamp\":1414000000000,\"payload\":\"message 1\"}\n\
+                 {\"offset\":6,\"timestamp\":1414000001000,\"payload\":\"message 2\"}\n";
+    let result = ImportCommand::new().execute("a river 8", &mut io::BufReader::new(input.as_bytes()));
+
+    assert_eq!(2, result.unwrap());
+
+    match PeekCommand::new().execute("a river 8", None) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 2", message.as_slice());
+            assert_eq!(4, offset);
+        },
+        _ => panic!("Messages should have been imported")
+    }
+
+    let mut out = io::MemWriter::new();
+    ExportCommand::new().execute("a river 8", Some(2), Some(2), &mut out).unwrap();
+    assert_eq!(
+        "{\"offset\":2,\"timestamp\":1414000000000,\"payload\":\"message 1\"}\n",
+        String::from_utf8(out.unwrap()).unwrap().as_slice()
+    );
+}
+
+#[test]
+fn importing_malformed_json_lines() {
+    ClearCommand::new().execute("a river 9");
+
+    let input = "{\"offset\":1,\"timestamp\":0,\"payload\":\"message 1\"}\nnot a json\n";
+
+    match ImportCommand::new().execute("a river 9", &mut io::BufReader::new(input.as_bytes())) {
+        Err(MalformedLine(2)) => {},
+        _ => panic!("Second line should have been reported as malformed")
+    }
+
+    assert!(PeekCommand::new().execute("a river 9", None).is_none());
+}
+
+#[test]
+fn listing_rivers() {
+    ClearCommand::new().execute("a river 10");
+    PushCommand::new().execute("a river 10", "message 1");
+    PushCommand::new().execute("a river 10", "message 2");
+
+    let rivers = ListRiversCommand::new().execute();
+
+    match rivers.iter().find(|info| info.name.as_slice() == "a river 10") {
+        Some(&RiverInfo { earliest_offset, latest_offset, message_count, bytes, last_write, .. }) => {
+            assert_eq!(Some(1), earliest_offset);
+            assert_eq!(Some(2), latest_offset);
+            assert_eq!(2, message_count);
+            assert_eq!(2 * 4096, bytes);
+            assert!(last_write > 0);
+        },
+        None => panic!("River should have been listed")
+    }
+
+    assert!(rivers.iter().all(|info| ! info.name.as_slice().starts_with(".")));
+}
+
+#[test]
+fn getting_info_of_missing_river() {
+    ClearCommand::new().execute("a river 11");
+
+    assert_eq!(None, RiverInfoCommand::new().execute("a river 11"));
+}
+
+#[test]
+fn pushing_and_peeking_with_memory_storage() {
+    let storage = MemoryStorage::new();
+
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 1");
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 2");
+
+    match PeekCommand::with_storage(storage.clone()).execute("a river", Some(1)) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 1", message.as_slice());
+            assert_eq!(2, offset);
+        },
+        _ => panic!("Message should have been pushed to memory storage")
+    }
+
+    assert!(PeekCommand::with_storage(storage.clone()).execute("a river", Some(3)).is_none());
+    assert!(PeekCommand::with_storage(MemoryStorage::new()).execute("a river", None).is_none());
+}
+
+#[test]
+fn clearing_and_listing_rivers_with_memory_storage() {
+    let storage = MemoryStorage::new();
+
+    PushCommand::with_storage(storage.clone()).execute("river b", "message 1");
+    PushCommand::with_storage(storage.clone()).execute("river a", "message 1");
+    PushCommand::with_storage(storage.clone()).execute("river a", "message 2");
+    ClearCommand::with_storage(storage.clone()).execute("river b");
+
+    let rivers = ListRiversCommand::with_storage(storage.clone()).execute();
+
+    assert_eq!(1, rivers.len());
+    assert_eq!("river a", rivers[0].name.as_slice());
+    assert_eq!(Some(2), rivers[0].latest_offset);
+    assert_eq!(2, rivers[0].message_count);
+}
+
+#[test]
+fn truncating_a_river() {
+    ClearCommand::new().execute("a river 12");
+    PushCommand::new().execute("a river 12", "message 1");
+    PushCommand::new().execute("a river 12", "message 2");
+    PushCommand::new().execute("a river 12", "message 3");
+
+    TruncateCommand::new().execute("a river 12", 3);
+
+    assert_eq!(Err(OffsetExpired(3)), PeekCommand::new().peek("a river 12", Some(2)).map(|r| r.message));
+    assert_eq!(Ok("message 3".to_string()), PeekCommand::new().peek("a river 12", Some(3)).map(|r| r.message));
+    assert_eq!(Err(NoMessage), PeekCommand::new().peek("a river 12", Some(4)).map(|r| r.message));
+
+    PushCommand::new().execute("a river 12", "message 4");
+
+    match PeekCommand::new().execute("a river 12", None) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 4", message.as_slice());
+            assert_eq!(5, offset);
+        },
+        _ => panic!("Offsets should have been kept after truncation")
+    }
+
+    let info = RiverInfoCommand::new().execute("a river 12").unwrap();
+    assert_eq!(Some(3), info.earliest_offset);
+    assert_eq!(Some(4), info.latest_offset);
+    assert_eq!(2, info.message_count);
+    assert_eq!(2 * 4096, info.bytes);
+}
+
+#[test]
+fn truncating_a_river_past_its_end() {
+    let storage = MemoryStorage::new();
+
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 1");
+    TruncateCommand::with_storage(storage.clone()).execute("a river", 10);
+
+    assert!(PeekCommand::with_storage(storage.clone()).execute("a river", None).is_none());
+    assert_eq!(Err(OffsetExpired(10)), PeekCommand::with_storage(storage.clone()).peek("a river", Some(1)).map(|r| r.offset));
+
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 10");
+
+    match PeekCommand::with_storage(storage.clone()).execute("a river", Some(10)) {
+        Some(PeekResult { message, offset }) => {
+            assert_eq!("message 10", message.as_slice());
+            assert_eq!(11, offset);
+        },
+        _ => panic!("Next message should have got the offset truncated before")
+    }
+}
+
+#[test]
+fn clearing_a_truncated_river_resets_offsets() {
+    PushCommand::new().execute("a river 13", "message 1");
+    TruncateCommand::new().execute("a river 13", 100);
+    ClearCommand::new().execute("a river 13");
+    PushCommand::new().execute("a river 13", "message 1");
+
+    assert_eq!(Ok(2), PeekCommand::new().peek("a river 13", Some(1)).map(|r| r.offset));
+}
+
+#[test]
+fn receiving_and_acking_messages() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 1");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+
+    let first = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    let second = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+
+    assert_eq!(("job 1".to_string(), 1, 1), (first.message, first.offset, first.attempts));
+    assert_eq!(("job 2".to_string(), 2, 1), (second.message, second.offset, second.attempts));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000));
+
+    assert!(AckCommand::with_storage(storage.clone()).execute("jobs", first.offset, first.receipt));
+    assert!(! AckCommand::with_storage(storage.clone()).execute("jobs", first.offset, first.receipt));
+}
+
+#[test]
+fn redelivering_messages_after_visibility_timeout() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 1");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+
+    let expired = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 0).unwrap();
+    let redelivered = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+
+    assert_eq!(("job 1".to_string(), 2), (redelivered.message, redelivered.attempts));
+    assert!(! AckCommand::with_storage(storage.clone()).execute("jobs", expired.offset, expired.receipt));
+    assert!(AckCommand::with_storage(storage.clone()).execute("jobs", redelivered.offset, redelivered.receipt));
+}
+
+#[test]
+fn nacking_a_message_redelivers_it() {
+    ClearCommand::new().execute("a river 14");
+    PushCommand::new().execute("a river 14", "job 1");
+    PushCommand::new().execute("a river 14", "job 2");
+
+    let received = ReceiveCommand::new().execute("a river 14", 60000).unwrap();
+    assert!(NackCommand::new().execute("a river 14", received.offset, received.receipt));
+
+    let redelivered = ReceiveCommand::new().execute("a river 14", 60000).unwrap();
+    assert_eq!((1, 2), (redelivered.offset, redelivered.attempts));
+    assert_eq!(Some(2), ReceiveCommand::new().execute("a river 14", 60000).map(|r| r.offset));
+}
+
+#[test]
+fn moving_failing_messages_to_dead_letter_river() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "poison");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 2
+    })).unwrap();
+
+    let first = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    assert!(NackCommand::with_storage(storage.clone()).reject("jobs", first.offset, first.receipt, "boom"));
+    let second = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    assert!(NackCommand::with_storage(storage.clone()).reject("jobs", second.offset, second.receipt, "boom again"));
+
+    assert_eq!(Some("job 2".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+
+    let dead = storage.read("jobs.dead", 1).unwrap();
+    assert_eq!("poison", dead.payload.as_slice());
+    assert_eq!(Some(DeadLetter {
+        river: "jobs".to_string(),
+        offset: 1,
+        reason: "boom again".to_string(),
+        attempts: 2
+    }), dead.dead_letter);
+}
+
+#[test]
+fn truncating_long_failure_reasons() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "poison");
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 1
+    })).unwrap();
+
+    let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    let reason = String::from_char(10000, 'é');
+    assert!(NackCommand::with_storage(storage.clone()).reject("jobs", received.offset, received.receipt, reason.as_slice()));
+
+    let dead = storage.read("jobs.dead", 1).unwrap().dead_letter.unwrap();
+    assert_eq!(String::from_char(128, 'é'), dead.reason);
+}
+
+#[test]
+fn moving_expired_messages_to_dead_letter_river() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "slow job");
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 1
+    })).unwrap();
+
+    assert!(ReceiveCommand::with_storage(storage.clone()).execute("jobs", 0).is_some());
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 0));
+
+    let dead = storage.read("jobs.dead", 1).unwrap();
+    assert_eq!("visibility timeout expired", dead.dead_letter.unwrap().reason.as_slice());
+}
+
+#[test]
+fn skipping_unreadable_messages_on_receive() {
+    let root = Path::new("./tmp/unreadable_rivers");
+    let storage = FileStorage::in_root(&root);
+    ClearCommand::with_storage(storage.clone()).execute("jobs");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 1");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+
+    io::File::open_mode(&root.join("jobs"), io::Open, io::Write).write(b"\xff\xfe").unwrap();
+
+    let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    assert_eq!(("job 2".to_string(), 2), (received.message, received.offset));
+}
+
+#[test]
+fn keeping_messages_rejected_by_dead_letter_river() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs.dead", "parked");
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("jobs.dead", Some(Quota { max_bytes: None, max_messages: Some(1), policy: RejectPushes }));
+    PushCommand::with_storage(storage.clone()).execute("jobs", "poison");
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 1
+    })).unwrap();
+
+    let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    assert!(NackCommand::with_storage(storage.clone()).reject("jobs", received.offset, received.receipt, "boom"));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 0));
+    assert!(storage.read("jobs.dead", 2).is_none());
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("jobs.dead", None);
+    io::timer::sleep(Duration::milliseconds(20));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000));
+
+    let dead = storage.read("jobs.dead", 2).unwrap();
+    assert_eq!(("poison".to_string(), "boom".to_string()), (dead.payload, dead.dead_letter.unwrap().reason));
+}
+
+#[test]
+fn rejecting_dead_letter_policy_without_attempts() {
+    let storage = MemoryStorage::new();
+    assert!(ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 0
+    })).is_err());
+    assert!(storage.state("jobs", "dead_letter").is_none());
+}
+
+#[test]
+fn replaying_dead_letters() {
+    ClearCommand::new().execute("a river 15");
+    ClearCommand::new().execute("a river 15.dead");
+    PushCommand::new().execute("a river 15", "job 1");
+    ConfigureDeadLetterCommand::new().execute("a river 15", Some(DeadLetterPolicy {
+        river: "a river 15.dead".to_string(),
+        max_attempts: 1
+    })).unwrap();
+
+    let received = ReceiveCommand::new().execute("a river 15", 60000).unwrap();
+    NackCommand::new().execute("a river 15", received.offset, received.receipt);
+    assert_eq!(Some("job 1".to_string()), PeekCommand::new().execute("a river 15.dead", Some(1)).map(|r| r.message));
+
+    assert_eq!(1, ReplayDeadLettersCommand::new().execute("a river 15.dead"));
+    assert!(PeekCommand::new().execute("a river 15.dead", None).is_none());
+
+    let replayed = ReceiveCommand::new().execute("a river 15", 60000).unwrap();
+    assert_eq!(("job 1".to_string(), 2, 1), (replayed.message, replayed.offset, replayed.attempts));
+}
+
+#[test]
+fn keeping_messages_without_origin_while_replaying() {
+    let storage = MemoryStorage::new();
+    let dead_letter = |river: &str, payload: &str| Message {
+        dead_letter: Some(DeadLetter { river: river.to_string(), offset: 1, reason: "failed".to_string(), attempts: 1 }),
+        ..Message::new(payload)
+    };
+
+    storage.append("jobs.dead", &dead_letter("jobs", "job 1")).unwrap();
+    storage.append("jobs.dead", &Message::new("pushed directly")).unwrap();
+    storage.append("jobs.dead", &dead_letter("jobs", "job 2")).unwrap();
+
+    assert_eq!(2, ReplayDeadLettersCommand::with_storage(storage.clone()).execute("jobs.dead"));
+    assert_eq!(0, ReplayDeadLettersCommand::with_storage(storage.clone()).execute("jobs.dead"));
+    assert_eq!(2, storage.latest_offset("jobs"));
+    assert_eq!(Some("pushed directly".to_string()), PeekCommand::with_storage(storage.clone()).execute("jobs.dead", None).map(|r| r.message));
+}
+
+#[test]
+fn resuming_interrupted_replay() {
+    let storage = MemoryStorage::new();
+    let dead_letter = |payload: &str| Message {
+        dead_letter: Some(DeadLetter { river: "jobs".to_string(), offset: 1, reason: "failed".to_string(), attempts: 1 }),
+        ..Message::new(payload)
+    };
+
+    storage.append("jobs.dead", &dead_letter("job 1")).unwrap();
+    storage.append("jobs.dead", &dead_letter("job 2")).unwrap();
+    // crashed after job 1 was replayed
+    storage.update_state("jobs.dead", "replay", |_| "2".to_string()).unwrap();
+
+    assert_eq!(1, ReplayDeadLettersCommand::with_storage(storage.clone()).execute("jobs.dead"));
+    assert_eq!(Some("job 2".to_string()), PeekCommand::with_storage(storage.clone()).execute("jobs", None).map(|r| r.message));
+    assert_eq!(3, storage.earliest_offset("jobs.dead"));
+}
+
+#[test]
+fn stopping_replay_at_rejected_messages() {
+    let storage = MemoryStorage::new();
+    let dead_letter = |payload: &str| Message {
+        dead_letter: Some(DeadLetter { river: "jobs".to_string(), offset: 1, reason: "failed".to_string(), attempts: 1 }),
+        ..Message::new(payload)
+    };
+
+    storage.append("jobs.dead", &dead_letter("job 1")).unwrap();
+    storage.append("jobs.dead", &dead_letter("job 2")).unwrap();
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("jobs", Some(Quota { max_bytes: None, max_messages: Some(1), policy: RejectPushes }));
+
+    assert_eq!(1, ReplayDeadLettersCommand::with_storage(storage.clone()).execute("jobs.dead"));
+    assert_eq!(2, storage.earliest_offset("jobs.dead"));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("jobs", None);
+    assert_eq!(1, ReplayDeadLettersCommand::with_storage(storage.clone()).execute("jobs.dead"));
+    assert_eq!(3, storage.earliest_offset("jobs.dead"));
+}
+
+#[test]
+fn holding_back_delayed_messages_from_peeks() {
+    let in_a_minute = john::now() + 60000;
+
+    ClearCommand::new().execute("a river 16");
+    PushCommand::new().push("a river 16", &Message { deliver_at: Some(in_a_minute), ..Message::new("reminder") }).unwrap();
+    PushCommand::new().push("a river 16", &Message { deliver_at: Some(1414000000000), ..Message::new("overdue reminder") }).unwrap();
+
+    assert_eq!(Err(Delayed(in_a_minute)), PeekCommand::new().peek("a river 16", Some(1)).map(|r| r.message));
+    assert_eq!(Ok("overdue reminder".to_string()), PeekCommand::new().peek("a river 16", Some(2)).map(|r| r.message));
+
+    // delivery time is kept in the river, so it is still held back after restart
+    let restarted = FileStorage::new();
+    assert_eq!(Err(Delayed(in_a_minute)), PeekCommand::with_storage(restarted).peek("a river 16", Some(1)).map(|r| r.message));
+}
+
+#[test]
+fn receiving_delayed_messages_when_they_are_due() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { deliver_at: Some(john::now() + 200), ..Message::new("retry") }).unwrap();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+
+    assert_eq!(Some(2), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.offset));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000));
+
+    std::io::timer::sleep(std::time::Duration::milliseconds(300));
+
+    let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    assert_eq!(("retry".to_string(), 1, 1), (received.message, received.offset, received.attempts));
+}
+
+#[test]
+fn peeking_expired_messages() {
+    ClearCommand::new().execute("a river 17");
+    let ping = Message::with_ttl("ping", 0);
+    PushCommand::new().push("a river 17", &ping).unwrap();
+    PushCommand::new().push("a river 17", &Message::with_ttl("fresh ping", 60000)).unwrap();
+
+    assert_eq!(Err(MessageExpired(ping.timestamp)), PeekCommand::new().peek("a river 17", Some(1)).map(|r| r.message));
+    assert_eq!(Ok("fresh ping".to_string()), PeekCommand::new().peek("a river 17", Some(2)).map(|r| r.message));
+    assert_eq!(Err(NoMessage), PeekCommand::new().peek("a river 17", Some(3)).map(|r| r.message));
+}
+
+#[test]
+fn purging_expired_messages() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::with_ttl("ping 1", 0)).unwrap();
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::with_ttl("ping 2", 0)).unwrap();
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::with_ttl("ping 3", 60000)).unwrap();
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::with_ttl("ping 4", 0)).unwrap();
+
+    assert_eq!(2, PurgeExpiredCommand::with_storage(storage.clone()).execute("pings"));
+    assert_eq!(0, PurgeExpiredCommand::with_storage(storage.clone()).execute("pings"));
+    assert_eq!(Err(OffsetExpired(3)), PeekCommand::with_storage(storage.clone()).peek("pings", Some(2)).map(|r| r.message));
+    assert_eq!(2, RiverInfoCommand::with_storage(storage.clone()).execute("pings").unwrap().message_count);
+}
+
+#[test]
+fn purging_messages_outlived_retention() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::new("ping 1")).unwrap();
+    PushCommand::with_storage(storage.clone()).push("pongs", &Message::with_ttl("pong 1", 0)).unwrap();
+    ConfigureRiverCommand::with_storage(storage.clone()).execute("pings", &RiverConfig { retention: Some(0), ..RiverConfig::new() });
+
+    assert_eq!(2, PurgeExpiredCommand::with_storage(storage.clone()).execute_all());
+    assert_eq!(0, RiverInfoCommand::with_storage(storage.clone()).execute("pings").unwrap().message_count);
+    assert_eq!(0, RiverInfoCommand::with_storage(storage.clone()).execute("pongs").unwrap().message_count);
+}
+
+#[test]
+fn purging_in_background() {
+    let storage = MemoryStorage::new();
+    let purging = PurgeExpiredCommand::with_storage(storage.clone()).start(20);
+    PushCommand::with_storage(storage.clone()).push("pings", &Message::with_ttl("ping 1", 0)).unwrap();
+
+    for _ in range(0u, 100) {
+        if storage.earliest_offset("pings") > 1 {
+            break
+        }
+
+        io::timer::sleep(Duration::milliseconds(20));
+    }
+
+    purging.stop();
+    assert_eq!(2, storage.earliest_offset("pings"));
+}
+
+#[test]
+fn skipping_expired_messages_in_queues() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message::with_ttl("stale job", 0)).unwrap();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+
+    assert_eq!(Some("job 2".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000));
+}
+
+#[test]
+fn receiving_from_a_priority_river() {
+    ClearCommand::new().execute("a river 18");
+    ConfigurePriorityCommand::new().execute("a river 18", true);
+
+    for &(payload, priority) in [("low 1", 1u), ("high 1", 5), ("low 2", 1), ("high 2", 5), ("none", 0)].iter() {
+        PushCommand::new().push("a river 18", &Message { priority: Some(priority), ..Message::new(payload) }).unwrap();
+    }
+
+    let received: Vec < String > = range(0u, 5)
+        .filter_map(|_| ReceiveCommand::new().execute("a river 18", 60000))
+        .map(|result| result.message)
+        .collect();
+
+    assert_eq!(vec!["high 1", "high 2", "low 1", "low 2", "none"], received.iter().map(|m| m.as_slice()).collect::< Vec < &str > >());
+}
+
+#[test]
+fn ignoring_priority_in_ordinary_rivers() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(1), ..Message::new("job 1") }).unwrap();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(9), ..Message::new("job 2") }).unwrap();
+
+    assert_eq!(Some("job 1".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+}
+
+#[test]
+fn redelivering_to_a_priority_river() {
+    let storage = MemoryStorage::new();
+    ConfigurePriorityCommand::with_storage(storage.clone()).execute("jobs", true);
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(1), ..Message::new("low") }).unwrap();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(5), ..Message::new("high") }).unwrap();
+
+    let high = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    NackCommand::with_storage(storage.clone()).execute("jobs", high.offset, high.receipt);
+
+    assert_eq!(Some(("high".to_string(), 2)), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| (r.message, r.attempts)));
+    assert_eq!(Some("low".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+}
+
+#[test]
+fn redelivering_messages_with_the_least_offset_first() {
+    let storage = MemoryStorage::new();
+    ConfigurePriorityCommand::with_storage(storage.clone()).execute("jobs", true);
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(1), ..Message::new("low") }).unwrap();
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(5), ..Message::new("high") }).unwrap();
+
+    let high = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    let low = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    ConfigurePriorityCommand::with_storage(storage.clone()).execute("jobs", false);
+    NackCommand::with_storage(storage.clone()).execute("jobs", high.offset, high.receipt);
+    NackCommand::with_storage(storage.clone()).execute("jobs", low.offset, low.receipt);
+
+    assert_eq!(Some("low".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+}
+
+#[test]
+fn leasing_only_delivered_messages_of_a_priority_river() {
+    let storage = MemoryStorage::new();
+    ConfigurePriorityCommand::with_storage(storage.clone()).execute("jobs", true);
+    for index in range(0u, 1000) {
+        PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(1), ..Message::new(format!("low {}", index).as_slice()) }).unwrap();
+    }
+    PushCommand::with_storage(storage.clone()).push("jobs", &Message { priority: Some(5), ..Message::new("high") }).unwrap();
+
+    assert_eq!(Some("high".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+    assert_eq!(Some("low 0".to_string()), ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).map(|r| r.message));
+    assert!(storage.state("jobs", "queue").unwrap().len() < 512);
+}
+
+#[test]
+fn pushing_with_idempotency_key() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    assert_eq!(Ok(Appended(1)), command.push_once("orders", "producer 1:1", &Message::new("order 1")));
+    assert_eq!(Ok(Appended(2)), command.push_once("orders", "producer 1:2", &Message::new("order 2")));
+    assert_eq!(Ok(Duplicate(1)), command.push_once("orders", "producer 1:1", &Message::new("order 1")));
+    assert_eq!(2, RiverInfoCommand::with_storage(storage.clone()).execute("orders").unwrap().message_count);
+
+    ClearCommand::with_storage(storage.clone()).execute("orders");
+    assert_eq!(Ok(Appended(1)), command.push_once("orders", "producer 1:1", &Message::new("order 1")));
+}
+
+#[test]
+fn forgetting_old_idempotency_keys() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    for seq in range(0u, 1001) {
+        command.push_once("orders", seq.to_string().as_slice(), &Message::new("an order")).unwrap();
+    }
+
+    assert_eq!(Ok(Duplicate(1001)), command.push_once("orders", "1000", &Message::new("an order")));
+    assert_eq!(Ok(Appended(1002)), command.push_once("orders", "0", &Message::new("an order")));
+}
+
+#[test]
+fn committing_a_transaction() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("audit", "an entry");
+
+    let mut transaction = Transaction::new();
+    transaction.push("orders", "order 42");
+    transaction.push("audit", "alice created order 42");
+
+    assert_eq!(Ok(vec![1, 2]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+    assert_eq!(Ok("order 42".to_string()), PeekCommand::with_storage(storage.clone()).peek("orders", Some(1)).map(|r| r.message));
+    assert_eq!(Ok("alice created order 42".to_string()), PeekCommand::with_storage(storage.clone()).peek("audit", Some(2)).map(|r| r.message));
+}
+
+#[test]
+fn hiding_messages_of_transactions_in_progress_and_aborted() {
+    let storage = MemoryStorage::new();
+    let log = format!("{{\"in_progress\":{{\"in progress\":{},\"crashed\":1}},\"committed\":{{}}}}", john::now());
+    storage.update_state(".transactions", "log", |_| log.clone()).unwrap();
+
+    PushCommand::with_storage(storage.clone()).push("orders", &Message { transaction: Some("in progress".to_string()), ..Message::new("order 1") }).unwrap();
+    PushCommand::with_storage(storage.clone()).push("orders", &Message { transaction: Some("crashed".to_string()), ..Message::new("order 2") }).unwrap();
+    PushCommand::with_storage(storage.clone()).push("orders", &Message { transaction: Some("unknown".to_string()), ..Message::new("order 3") }).unwrap();
+    PushCommand::with_storage(storage.clone()).execute("orders", "order 4");
+
+    assert_eq!(Err(NoMessage), PeekCommand::with_storage(storage.clone()).peek("orders", Some(1)).map(|r| r.message));
+    assert_eq!(Err(RolledBack), PeekCommand::with_storage(storage.clone()).peek("orders", Some(2)).map(|r| r.message));
+    assert_eq!(Err(RolledBack), PeekCommand::with_storage(storage.clone()).peek("orders", Some(3)).map(|r| r.message));
+    assert_eq!(Ok("order 4".to_string()), PeekCommand::with_storage(storage.clone()).peek("orders", Some(4)).map(|r| r.message));
+
+    // queue consumers get the following messages, while the transaction is in progress
+    assert_eq!(Some(4), ReceiveCommand::with_storage(storage.clone()).execute("orders", 60000).map(|r| r.offset));
+    assert_eq!(None, ReceiveCommand::with_storage(storage.clone()).execute("orders", 60000));
+
+    storage.update_state(".transactions", "log", |_| "{\"in_progress\":{},\"committed\":{\"in progress\":[[\"orders\",1]]}}".to_string()).unwrap();
+    assert_eq!(Some(1), ReceiveCommand::with_storage(storage.clone()).execute("orders", 60000).map(|r| r.offset));
+}
+
+#[test]
+fn compacting_transaction_log() {
+    let storage = MemoryStorage::new();
+    storage.update_state(".transactions", "log", |_| "{\"in_progress\":{\"crashed\":1},\"committed\":{}}".to_string()).unwrap();
+
+    let mut transaction = Transaction::new();
+    transaction.push("orders", "order 1");
+    assert_eq!(Ok(vec![1]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+
+    let log = storage.state(".transactions", "log").unwrap();
+    assert!(! log.as_slice().contains("crashed"));
+    assert!(log.as_slice().contains("[\"orders\",1]"));
+
+    TruncateCommand::with_storage(storage.clone()).execute("orders", 2);
+    assert_eq!(Ok(vec![2]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+
+    let log = storage.state(".transactions", "log").unwrap();
+    assert!(! log.as_slice().contains("[\"orders\",1]"));
+    assert!(log.as_slice().contains("[\"orders\",2]"));
+}
+
+#[test]
+fn compacting_transaction_log_without_truncation() {
+    let storage = MemoryStorage::new();
+    storage.update_state(".transactions", "log", |_| "{\"in_progress\":{\"crashed\":1},\"committed\":{}}".to_string()).unwrap();
+    PushCommand::with_storage(storage.clone()).push("orders", &Message { transaction: Some("crashed".to_string()), ..Message::new("order 1") }).unwrap();
+
+    for i in range(2u, 5) {
+        let mut transaction = Transaction::new();
+        transaction.push("orders", format!("order {}", i).as_slice());
+        assert_eq!(Ok(vec![i]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+    }
+
+    let log = storage.state(".transactions", "log").unwrap();
+    assert!(! log.as_slice().contains("[\"orders\",2]"));
+    assert!(! log.as_slice().contains("[\"orders\",3]"));
+
+    assert_eq!(Err(RolledBack), PeekCommand::with_storage(storage.clone()).peek("orders", Some(1)).map(|r| r.message));
+    for i in range(2u, 5) {
+        assert_eq!(Ok(format!("order {}", i)), PeekCommand::with_storage(storage.clone()).peek("orders", Some(i)).map(|r| r.message));
+    }
+    assert_eq!(Some(2), ReceiveCommand::with_storage(storage.clone()).execute("orders", 60000).map(|r| r.offset));
+}
+
+#[test]
+fn restoring_committed_transactions_from_a_snapshot() {
+    let storage = MemoryStorage::new();
+    let mut transaction = Transaction::new();
+    transaction.push("orders", "order 42");
+    CommitCommand::with_storage(storage.clone()).execute(&transaction).unwrap();
+
+    let restored = MemoryStorage::new();
+    RestoreCommand::with_storage(restored.clone()).execute(&SnapshotCommand::with_storage(storage.clone()).execute()).unwrap();
+    assert_eq!(Ok("order 42".to_string()), PeekCommand::with_storage(restored.clone()).peek("orders", Some(1)).map(|r| r.message));
+
+    let mut exported = io::MemWriter::new();
+    ExportCommand::with_storage(storage.clone()).execute("orders", None, None, &mut exported).unwrap();
+    assert!(! String::from_utf8(exported.unwrap()).unwrap().as_slice().contains("transaction"));
+}
+
+#[test]
+fn restoring_states_and_offsets_from_a_snapshot() {
+    let storage = MemoryStorage::new();
+    for i in range(1u, 4) {
+        PushCommand::with_storage(storage.clone()).execute("jobs", format!("job {}", i).as_slice());
+    }
+    TruncateCommand::with_storage(storage.clone()).execute("jobs", 3);
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 2
+    })).unwrap();
+    let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+
+    let restored = MemoryStorage::new();
+    RestoreCommand::with_storage(restored.clone()).execute(&SnapshotCommand::with_storage(storage.clone()).execute()).unwrap();
+
+    assert_eq!((3, 3), (restored.earliest_offset("jobs"), restored.latest_offset("jobs")));
+    assert_eq!(storage.state("jobs", "dead_letter"), restored.state("jobs", "dead_letter"));
+    assert_eq!(storage.state("jobs", "queue"), restored.state("jobs", "queue"));
+    assert!(NackCommand::with_storage(restored.clone()).execute("jobs", received.offset, received.receipt));
+}
+
+#[test]
+fn stopping_restore_at_a_missing_offset() {
+    let snapshot = Snapshot {
+        rivers: vec![RiverSnapshot {
+            name: "orders".to_string(),
+            offset: 4,
+            messages: vec![
+                Message { offset: 1, ..Message::new("order 1") },
+                Message { offset: 3, ..Message::new("order 3") }
+            ],
+            states: None
+        }],
+        transactions: None,
+        states: None
+    };
+
+    let restored = MemoryStorage::new();
+    assert!(RestoreCommand::with_storage(restored.clone()).execute(&snapshot).is_err());
+    assert_eq!(1, restored.latest_offset("orders"));
+}
+
+#[test]
+fn reading_messages_at_offsets() {
+    ClearCommand::new().execute("a river 19");
+    for i in range(1u, 6) {
+        PushCommand::new().execute("a river 19", format!("message {}", i).as_slice());
+    }
+    TruncateCommand::new().execute("a river 19", 2);
+
+    let read = |offset: Offset| PeekCommand::new().read("a river 19", offset).map(|m| (m.offset, m.payload));
+
+    assert_eq!(Ok((2, "message 2".to_string())), read(Earliest));
+    assert_eq!(Ok((5, "message 5".to_string())), read(Latest));
+    assert_eq!(Ok((3, "message 3".to_string())), read(At(3)));
+    assert_eq!(Ok((4, "message 4".to_string())), read(FromEarliest(2)));
+    assert_eq!(Ok((4, "message 4".to_string())), read(FromLatest(1)));
+    assert_eq!(Err(OffsetExpired(2)), read(At(1)));
+    assert_eq!(Err(NoMessage), read(At(6)));
+    assert_eq!(Err(NoMessage), read(FromLatest(10)));
+}
+
+#[test]
+fn peeking_with_legacy_offsets() {
+    assert_eq!(Latest, Offset::from_legacy(None));
+    assert_eq!(Earliest, Offset::from_legacy(Some(0)));
+    assert_eq!(At(1), Offset::from_legacy(Some(1)));
+
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 1");
+    PushCommand::with_storage(storage.clone()).execute("a river", "message 2");
+
+    // peek result contains the next offset to peek at, unlike message read at an offset
+    assert_eq!(Ok(("message 1".to_string(), 2)), PeekCommand::with_storage(storage.clone()).peek("a river", Some(0)).map(|r| (r.message, r.offset)));
+    assert_eq!(Ok(1), PeekCommand::with_storage(storage.clone()).read("a river", Earliest).map(|m| m.offset));
+}
+
+#[test]
+fn parsing_and_formatting_offsets() {
+    for &(string, offset) in [("earliest", Earliest), ("latest", Latest), ("42", At(42)),
+                              ("earliest+2", FromEarliest(2)), ("latest-3", FromLatest(3))].iter() {
+        assert_eq!(Some(offset), from_str::< Offset >(string));
+        assert_eq!(string.to_string(), offset.to_string());
+    }
+
+    assert_eq!(None, from_str::< Offset >("latest+1"));
+    assert_eq!(None, from_str::< Offset >("soon"));
+}
+
+#[test]
+fn rejecting_pushes_over_river_quota() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("orders", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(2),
+        policy: RejectPushes
+    }));
+
+    assert_eq!(Ok(1), command.push("orders", &Message::new("order 1")));
+    assert_eq!(Ok(Appended(2)), command.push_once("orders", "order 2", &Message::new("order 2")));
+    assert_eq!(Err(RiverQuotaExceeded), command.push("orders", &Message::new("order 3")));
+    assert_eq!(Err(RiverQuotaExceeded), command.push_once("orders", "order 3", &Message::new("order 3")));
+
+    command.execute("orders", "order 3");
+    command.execute("other orders", "order 3");
+    assert_eq!(2, storage.latest_offset("orders"));
+    assert_eq!(1, storage.latest_offset("other orders"));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("orders", None);
+    assert_eq!(Ok(3), command.push("orders", &Message::new("order 3")));
+}
+
+#[test]
+fn dropping_oldest_messages_over_river_quota() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    // memory storage counts bytes of payloads
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("logs", Some(Quota {
+        max_bytes: Some(21),
+        max_messages: Some(3),
+        policy: DropOldest
+    }));
+
+    for i in range(1u, 6) {
+        assert_eq!(Ok(i), command.push("logs", &Message::new(format!("entry {}", i).as_slice())));
+    }
+    assert_eq!(3, storage.earliest_offset("logs"));
+    assert_eq!(5, storage.latest_offset("logs"));
+
+    assert_eq!(Ok(6), command.push("logs", &Message::new("a long entry 6")));
+    assert_eq!(5, storage.earliest_offset("logs"));
+
+    assert_eq!(Err(RiverQuotaExceeded), command.push("logs", &Message::new("an entry too long to fit")));
+    assert_eq!(5, storage.earliest_offset("logs"));
+}
+
+#[test]
+fn keeping_quota_of_the_storage() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+    let quota = |policy| Some(Quota { max_bytes: Some(25), max_messages: None, policy: policy });
+
+    command.execute("orders", "order 1");
+    command.execute("logs", "entry 1");
+    command.execute("logs", "entry 2");
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute_for_storage(quota(RejectPushes));
+    assert_eq!(Err(StorageQuotaExceeded), command.push("logs", &Message::new("entry 3")));
+
+    // oldest messages are dropped only from the river pushed to
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute_for_storage(quota(DropOldest));
+    assert_eq!(Ok(3), command.push("logs", &Message::new("entry 3")));
+    assert_eq!(2, storage.earliest_offset("logs"));
+    assert_eq!(1, storage.earliest_offset("orders"));
+
+    assert_eq!(Err(StorageQuotaExceeded), command.push("audit", &Message::new("an entry")));
+}
+
+#[test]
+fn rolling_back_transactions_over_quota() {
+    let storage = MemoryStorage::new();
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("audit", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(0),
+        policy: RejectPushes
+    }));
+
+    let mut transaction = Transaction::new();
+    transaction.push("orders", "order 42");
+    transaction.push("audit", "alice created order 42");
+
+    assert_eq!(Err(QuotaExceeded(RiverQuotaExceeded)), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+    assert_eq!(Err(RolledBack), PeekCommand::with_storage(storage.clone()).peek("orders", Some(1)).map(|r| r.message));
+    assert_eq!(0, storage.latest_offset("audit"));
+}
+
+#[test]
+fn configuring_a_river() {
+    let storage = MemoryStorage::new();
+    let command = ConfigureRiverCommand::with_storage(storage.clone());
+    assert_eq!(RiverConfig::new(), command.config("orders"));
+
+    let config = RiverConfig { max_message_size: Some(7), ..RiverConfig::new() };
+    command.execute("orders", &config);
+    assert_eq!(config, command.config("orders"));
+    assert_eq!(RiverConfig::new(), command.config("other orders"));
+
+    assert_eq!(Ok(1), PushCommand::with_storage(storage.clone()).push("orders", &Message::new("order 1")));
+    assert_eq!(Err(MessageTooLarge), PushCommand::with_storage(storage.clone()).push("orders", &Message::new("order 42")));
+}
+
+#[test]
+fn dropping_messages_after_retention() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    command.push("logs", &Message { timestamp: 1414000000000, ..Message::new("old entry") }).unwrap();
+    command.push("logs", &Message::new("fresh entry")).unwrap();
+
+    ConfigureRiverCommand::with_storage(storage.clone())
+        .execute("logs", &RiverConfig { retention: Some(60000), ..RiverConfig::new() });
+
+    assert_eq!(Ok(3), command.push("logs", &Message::new("new entry")));
+    assert_eq!(2, storage.earliest_offset("logs"));
+}
+
+#[test]
+fn dropping_acknowledged_messages_of_queue_rivers() {
+    let storage = MemoryStorage::new();
+    ConfigureRiverCommand::with_storage(storage.clone())
+        .execute("jobs", &RiverConfig { mode: QueueMode, ..RiverConfig::new() });
+
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 1");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 2");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "job 3");
+
+    let first = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+    let second = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+
+    assert!(AckCommand::with_storage(storage.clone()).execute("jobs", second.offset, second.receipt));
+    assert_eq!(1, storage.earliest_offset("jobs"));
+
+    assert!(AckCommand::with_storage(storage.clone()).execute("jobs", first.offset, first.receipt));
+    assert_eq!(3, storage.earliest_offset("jobs"));
+}
+
+#[test]
+fn compressing_messages_on_disk() {
+    let root = Path::new("./tmp/compressed_rivers");
+    let storage = FileStorage::in_root(&root);
+    let long_message = String::from_char(10000, 'a');
+
+    ClearCommand::with_storage(storage.clone()).execute("logs");
+    PushCommand::with_storage(storage.clone()).execute("logs", "plain entry");
+
+    ConfigureRiverCommand::with_storage(storage.clone())
+        .execute("logs", &RiverConfig { compressed: true, durable: true, ..RiverConfig::new() });
+    PushCommand::with_storage(storage.clone()).execute("logs", long_message.as_slice());
+
+    assert_eq!(2 * 4096, root.join("logs").stat().unwrap().size);
+    assert_eq!(Ok("plain entry".to_string()), PeekCommand::with_storage(storage.clone()).peek("logs", Some(1)).map(|r| r.message));
+    assert_eq!(Ok(long_message), PeekCommand::with_storage(storage.clone()).peek("logs", Some(2)).map(|r| r.message));
+}
+
+#[test]
+fn rejecting_incompressible_messages() {
+    let storage = FileStorage::in_root(&Path::new("./tmp/compressed_rivers"));
+    let mut seed = 42u;
+    let noise: String = range(0u, 3800).map(|_| {
+        seed = (seed * 1103515245 + 12345) % 2147483648;
+        (33 + seed / 65536 % 94) as u8 as char
+    }).collect();
+
+    ClearCommand::with_storage(storage.clone()).execute("noise");
+    ClearCommand::with_storage(storage.clone()).execute("compressed noise");
+    ConfigureRiverCommand::with_storage(storage.clone())
+        .execute("compressed noise", &RiverConfig { compressed: true, ..RiverConfig::new() });
+
+    assert_eq!(Ok(1), PushCommand::with_storage(storage.clone()).push("noise", &Message::new(noise.as_slice())));
+    assert_eq!(Err(MessageTooLarge), PushCommand::with_storage(storage.clone()).push("compressed noise", &Message::new(noise.as_slice())));
+}
+
+#[test]
+fn applying_limits_of_rivers_to_imported_messages() {
+    let storage = MemoryStorage::new();
+    ConfigureRiverCommand::with_storage(storage.clone())
+        .execute("orders", &RiverConfig { max_message_size: Some(7), ..RiverConfig::new() });
+
+    let input = "{\"offset\":1,\"timestamp\":1414000000000,\"payload\":\"order 1\"}\n\
+                 {\"offset\":2,\"timestamp\":1414000000000,\"payload\":\"order 42\"}\n";
+
+    assert_eq!(Ok(1), ImportCommand::with_storage(storage.clone()).execute("orders", &mut io::BufReader::new(input.as_bytes())).map_err(|_| ()));
+    assert_eq!((1, 1), (storage.earliest_offset("orders"), storage.latest_offset("orders")));
+}
+
+#[test]
+fn validating_imported_messages_against_schema() {
+    let storage = MemoryStorage::new();
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("orders", "{\"type\":\"object\"}").unwrap();
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("orders", Some(SchemaBinding {
+        schema: "orders".to_string(),
+        version: None
+    }));
+
+    let input = "{\"offset\":1,\"timestamp\":1414000000000,\"payload\":\"{\\\"id\\\":1}\"}\n\
+                 {\"offset\":2,\"timestamp\":1414000000000,\"payload\":\"order 2\"}\n";
+
+    assert_eq!(Ok(1), ImportCommand::with_storage(storage.clone()).execute("orders", &mut io::BufReader::new(input.as_bytes())).map_err(|_| ()));
+    assert_eq!(Some(Some(SchemaVersion { name: "orders".to_string(), version: 1 })), storage.read("orders", 1).map(|message| message.schema));
+    assert_eq!(1, storage.latest_offset("orders"));
+}
+
+#[test]
+fn keeping_usage_of_file_storage() {
+    let storage = FileStorage::in_root(&Path::new("./tmp/usage_rivers"));
+    for river in storage.rivers().iter() {
+        ClearCommand::with_storage(storage.clone()).execute(river.as_slice());
+    }
+
+    assert_eq!(Usage { bytes: 0, messages: 0 }, storage.usage());
+
+    for message in ["message 1", "message 2", "message 3"].iter() {
+        PushCommand::with_storage(storage.clone()).execute("logs", *message);
+        PushCommand::with_storage(storage.clone()).execute("audit", *message);
+    }
+    assert_eq!(Usage { bytes: 6 * 4096, messages: 6 }, storage.usage());
+
+    TruncateCommand::with_storage(storage.clone()).execute("logs", 3);
+    ClearCommand::with_storage(storage.clone()).execute("audit");
+    assert_eq!(Usage { bytes: 4096, messages: 1 }, storage.usage());
+}
+
+#[test]
+fn checking_quotas_of_messages_moved_to_dead_letter_river() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("jobs", "poison 1");
+    PushCommand::with_storage(storage.clone()).execute("jobs", "poison 2");
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("jobs", Some(DeadLetterPolicy {
+        river: "jobs.dead".to_string(),
+        max_attempts: 1
+    })).unwrap();
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("jobs.dead", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(1),
+        policy: DropOldest
+    }));
+
+    for _ in range(0u, 2) {
+        let received = ReceiveCommand::with_storage(storage.clone()).execute("jobs", 60000).unwrap();
+        assert!(NackCommand::with_storage(storage.clone()).execute("jobs", received.offset, received.receipt));
+    }
+
+    assert_eq!((2, 2), (storage.earliest_offset("jobs.dead"), storage.latest_offset("jobs.dead")));
+    assert_eq!(Some("poison 2".to_string()), storage.read("jobs.dead", 2).map(|message| message.payload));
+}
+
+#[test]
+fn rejecting_messages_not_fitting_into_a_slot() {
+    let storage = FileStorage::in_root(&Path::new("./tmp/slot_rivers"));
+    let command = PushCommand::with_storage(storage.clone());
+    ClearCommand::with_storage(storage.clone()).execute("jobs");
+
+    // payload fits, but leaves no room for metadata
+    let payload = String::from_char(4090, 'a');
+    assert_eq!(Err(MessageTooLarge), command.push("jobs", &Message::new(payload.as_slice())));
+
+    let reason = String::from_char(4000, 'x');
+    assert_eq!(Err(MessageTooLarge), command.push("jobs", &Message {
+        dead_letter: Some(DeadLetter { river: "jobs".to_string(), offset: 1, reason: reason, attempts: 1 }),
+        ..Message::new("job 1")
+    }));
+
+    assert_eq!(Ok(1), command.push("jobs", &Message::new("job 1")));
+    assert_eq!(Some("job 1".to_string()), storage.read("jobs", 1).map(|message| message.payload));
+}
+
+#[test]
+fn failing_to_write_a_river() {
+    let storage = FileStorage::in_root(&Path::new("./tmp/failing_rivers"));
+    ClearCommand::with_storage(storage.clone()).execute("orders");
+    let _ = io::fs::rmdir(&Path::new("./tmp/failing_rivers/orders"));
+
+    // the river cannot be opened for writing, while a directory takes its place
+    io::fs::mkdir_recursive(&Path::new("./tmp/failing_rivers/orders"), io::USER_RWX).unwrap();
+    match PushCommand::with_storage(storage.clone()).push("orders", &Message::new("order 1")) {
+        Err(WriteFailed(_)) => {},
+        other => panic!("Unexpected result of push: {}", other)
+    }
+
+    io::fs::rmdir(&Path::new("./tmp/failing_rivers/orders")).unwrap();
+    assert_eq!(Ok(1), PushCommand::with_storage(storage.clone()).push("orders", &Message::new("order 1")));
+}
+
+#[test]
+fn pushing_concurrently_to_a_river() {
+    let storage = FileStorage::in_root(&Path::new("./tmp/concurrent_rivers"));
+    ClearCommand::with_storage(storage.clone()).execute("orders");
+
+    let (sender, receiver) = channel();
+    for producer in range(0u, 10) {
+        let storage = storage.clone();
+        let sender = sender.clone();
+
+        spawn(proc() {
+            for seq in range(0u, 20) {
+                let message = Message::new(format!("{}:{}", producer, seq).as_slice());
+                sender.send((message.payload.clone(), PushCommand::with_storage(storage.clone()).push("orders", &message).unwrap()));
+            }
+        });
+    }
+
+    let mut offsets = vec![];
+    for _ in range(0u, 200) {
+        let (payload, offset) = receiver.recv();
+
+        assert_eq!(Some(payload), storage.read("orders", offset).map(|message| message.payload));
+        offsets.push(offset);
+    }
+
+    offsets.sort();
+    assert_eq!(range(1u, 201).collect::< Vec < uint > >(), offsets);
+}
+
+#[test]
+fn pushing_concurrently_with_separate_storages() {
+    ClearCommand::with_storage(FileStorage::in_root(&Path::new("./tmp/concurrent_rivers"))).execute("payments");
+
+    let (sender, receiver) = channel();
+    for producer in range(0u, 10) {
+        let sender = sender.clone();
+
+        spawn(proc() {
+            let storage = FileStorage::in_root(&Path::new("./tmp/concurrent_rivers"));
+
+            for seq in range(0u, 20) {
+                let message = Message::new(format!("{}:{}", producer, seq).as_slice());
+                sender.send(PushCommand::with_storage(storage.clone()).push("payments", &message).unwrap());
+            }
+        });
+    }
+
+    let mut offsets: Vec < uint > = range(0u, 200).map(|_| receiver.recv()).collect();
+    offsets.sort();
+    assert_eq!(range(1u, 201).collect::< Vec < uint > >(), offsets);
+}
+
+#[test]
+fn registering_versions_of_schemas() {
+    let command = RegisterSchemaCommand::with_storage(MemoryStorage::new());
+
+    assert_eq!(Ok(1), command.execute("orders", "{\"type\":\"object\"}"));
+    assert_eq!(Ok(1), command.execute("orders", "{\"type\":\"object\"}"));
+    assert_eq!(Ok(2), command.execute("orders", "{\"type\":\"object\",\"required\":[\"id\"]}"));
+    assert_eq!(Ok(1), command.execute("invoices", "{}"));
+
+    assert_eq!(Some((2, "{\"type\":\"object\",\"required\":[\"id\"]}".to_string())), command.schema("orders", None));
+    assert_eq!(Some((1, "{\"type\":\"object\"}".to_string())), command.schema("orders", Some(1)));
+    assert_eq!(None, command.schema("orders", Some(3)));
+    assert_eq!(None, command.schema("payments", None));
+
+    assert!(match command.execute("orders", "{\"type\":") { Err(InvalidSchema(_)) => true, _ => false });
+    assert_eq!(Err(InvalidSchema("schema should be an object".to_string())), command.execute("orders", "[]"));
+    assert_eq!(Err(InvalidSchema("schema name should be at most 64 bytes".to_string())),
+               command.execute(String::from_char(65, 'o').as_slice(), "{}"));
+}
+
+#[test]
+fn validating_pushes_against_the_latest_version_of_schema() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("orders", "{\"type\":\"object\"}").unwrap();
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("orders", Some(SchemaBinding {
+        schema: "orders".to_string(),
+        version: None
+    }));
+
+    assert_eq!(Ok(1), command.push("orders", &Message::new("{}")));
+
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("orders", "{\"type\":\"object\",\"required\":[\"id\"]}").unwrap();
+
+    assert_eq!(Err(SchemaViolation("$.id is required".to_string())), command.push("orders", &Message::new("{}")));
+    assert_eq!(Ok(2), command.push("orders", &Message::new("{\"id\":42}")));
+    assert_eq!(Some(Some(SchemaVersion { name: "orders".to_string(), version: 2 })), storage.read("orders", 2).map(|message| message.schema));
+}
+
+#[test]
+fn validating_pushes_against_schema() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+    let schema = "{\"type\":\"object\",\"required\":[\"id\",\"items\"],\"additionalProperties\":false,\"properties\":{\
+                  \"id\":{\"type\":\"integer\",\"minimum\":1},\
+                  \"status\":{\"enum\":[\"new\",\"paid\"]},\
+                  \"items\":{\"type\":\"array\",\"minItems\":1,\"items\":{\"type\":\"string\",\"maxLength\":5}}}}";
+
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("orders", schema).unwrap();
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("orders", Some(SchemaBinding {
+        schema: "orders".to_string(),
+        version: None
+    }));
+
+    assert_eq!(Ok(1), command.push("orders", &Message::new("{\"id\":42,\"status\":\"new\",\"items\":[\"book\"]}")));
+    assert_eq!(Some(Some(SchemaVersion { name: "orders".to_string(), version: 1 })), storage.read("orders", 1).map(|message| message.schema));
+
+    let violations = [
+        ("{\"id\":\"42\",\"items\":[\"book\"]}", "$.id should be of type \"integer\""),
+        ("{\"id\":0,\"items\":[\"book\"]}", "$.id should be at least 1"),
+        ("{\"id\":42}", "$.items is required"),
+        ("{\"id\":42,\"items\":[]}", "$.items should have at least 1 items"),
+        ("{\"id\":42,\"items\":[\"bookshelf\"]}", "$.items[0] should be at most 5 characters long"),
+        ("{\"id\":42,\"items\":[\"book\"],\"status\":\"lost\"}", "$.status should be one of [\"new\",\"paid\"]"),
+        ("{\"id\":42,\"items\":[\"book\"],\"note\":\"\"}", "$.note is not allowed")
+    ];
+
+    for &(payload, reason) in violations.iter() {
+        assert_eq!(Err(SchemaViolation(reason.to_string())), command.push("orders", &Message::new(payload)));
+    }
+
+    assert!(match command.push("orders", &Message::new("order 42")) { Err(SchemaViolation(_)) => true, _ => false });
+    assert_eq!(1, storage.latest_offset("orders"));
+}
+
+#[test]
+fn comparing_numbers_of_enums_by_value() {
+    let storage = MemoryStorage::new();
+    let command = PushCommand::with_storage(storage.clone());
+
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("levels", "{\"enum\":[1,2.5]}").unwrap();
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("levels", Some(SchemaBinding {
+        schema: "levels".to_string(),
+        version: None
+    }));
+
+    assert_eq!(Ok(1), command.push("levels", &Message::new("1.0")));
+    assert_eq!(Ok(2), command.push("levels", &Message::new("2.5")));
+    assert!(command.push("levels", &Message::new("2")).is_err());
+}
+
+#[test]
+fn rejecting_pushes_to_rivers_bound_to_unknown_schema() {
+    let storage = MemoryStorage::new();
+
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("orders", Some(SchemaBinding {
+        schema: "orders".to_string(),
+        version: Some(2)
+    }));
+
+    assert_eq!(Err(SchemaViolation("schema orders version 2 is not registered".to_string())),
+               PushCommand::with_storage(storage.clone()).push("orders", &Message::new("{}")));
+
+    let mut transaction = Transaction::new();
+    transaction.push("invoices", "{}");
+    transaction.push("orders", "{}");
+
+    assert_eq!(Err(Rejected(SchemaViolation("schema orders version 2 is not registered".to_string()))),
+               CommitCommand::with_storage(storage.clone()).execute(&transaction));
+    assert_eq!(0, storage.latest_offset("invoices"));
+
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("orders", None);
+    assert_eq!(Ok(vec![1, 1]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+}
+
+#[test]
+fn matching_messages_against_filters() {
+    let order = Message {
+        priority: Some(3),
+        ..Message::new(r#"{"type":"order.created","total":42.5,"region":"eu","tags":["gift"],"lines":[{"sku":"b-1"}]}"#)
+    };
+    let matches = |filter: &str| Filter::parse(filter).unwrap().matches(&order);
+
+    assert!(matches(r#"$.type == "order.created""#));
+    assert!(matches(r#"$.type = "order.created" and $.total > 40"#));
+    assert!(matches(r#"$.total >= 42.5 and $.total < 43 and $.total != 42"#));
+    assert!(matches(r#"$.lines[0].sku == "b-1" and $.tags contains "gift""#));
+    assert!(matches(r#"payload contains "order" and priority == 3"#));
+    assert!(matches(r#"$.region == "us" or not ($.region == "us" or $.missing == 1)"#));
+    assert!(matches(r#"$.missing != "anything""#));
+
+    assert!(! matches(r#"$.type == "order.paid""#));
+    assert!(! matches(r#"$.lines[1].sku == "b-1""#));
+    assert!(! matches(r#"$.total > "40""#));
+    assert!(! matches(r#"schema == "orders""#));
+    assert!(! Filter::parse(r#"$.type == "order""#).unwrap().matches(&Message::new("order")));
+
+    assert!(Filter::parse(r#"$.type == "#).is_err());
+    assert!(Filter::parse(r#"$.type ~ "order""#).is_err());
+    assert!(Filter::parse(r#"headers == 1"#).is_err());
+    assert!(Filter::parse(r#"($.total > 1"#).is_err());
+    assert!(Filter::parse(format!("{}$.total > 1{}", String::from_char(32, '('), String::from_char(32, ')')).as_slice()).is_ok());
+    assert_eq!(Err("filter should be nested at most 32 levels deep".to_string()),
+               Filter::parse(format!("{}$.total > 1", Vec::from_elem(33, "not ").as_slice().concat()).as_slice()).map(|filter| filter.to_string()));
+    assert_eq!(Some(r#"$.total > 1"#.to_string()), from_str::< Filter >(r#"$.total > 1"#).map(|filter| filter.to_string()));
+}
+
+#[test]
+fn peeking_messages_matching_a_filter() {
+    let storage = MemoryStorage::new();
+    let command = PeekCommand::with_storage(storage.clone());
+    let created = Filter::parse(r#"$.type == "order.created""#).unwrap();
+
+    PushCommand::with_storage(storage.clone()).execute("orders", r#"{"type":"order.created","id":1}"#);
+    PushCommand::with_storage(storage.clone()).execute("orders", r#"{"type":"order.paid","id":1}"#);
+    PushCommand::with_storage(storage.clone()).execute("orders", "not even JSON");
+    PushCommand::with_storage(storage.clone()).execute("orders", r#"{"type":"order.created","id":2}"#);
+    PushCommand::with_storage(storage.clone()).execute("orders", r#"{"type":"order.paid","id":2}"#);
+
+    assert_eq!(Ok(1), command.read_matching("orders", Earliest, &created).map(|message| message.offset));
+    assert_eq!(Ok(4), command.read_matching("orders", At(2), &created).map(|message| message.offset));
+    assert_eq!(Err(NoMatchingMessage(6)), command.read_matching("orders", Latest, &Filter::parse("offset < 5").unwrap()));
+    assert_eq!(Err(NoMatchingMessage(6)), command.read_matching("orders", At(5), &created));
+    assert_eq!(Err(NoMatchingMessage(7)), command.read_matching("orders", At(7), &created));
+
+    assert_eq!(Ok(("{\"type\":\"order.created\",\"id\":2}".to_string(), 5)),
+               command.peek_matching("orders", Some(2), &created).map(|result| (result.message, result.offset)));
+
+    PushCommand::with_storage(storage.clone()).push("orders", &Message {
+        deliver_at: Some(john::now() + 60000),
+        ..Message::new(r#"{"type":"order.paid","id":3}"#)
+    }).unwrap();
+    PushCommand::with_storage(storage.clone()).execute("orders", r#"{"type":"order.created","id":3}"#);
+
+    assert_eq!(Ok(7), command.read_matching("orders", At(5), &created).map(|message| message.offset));
+    assert!(match command.read_matching("orders", At(5), &Filter::parse(r#"$.id == 3"#).unwrap()) { Err(Delayed(_)) => true, _ => false });
+}
+
+#[test]
+fn routing_messages_by_kind_of_exchange() {
+    let strings = |rivers: &[&str]| -> Vec < String > { rivers.iter().map(|river| river.to_string()).collect() };
+
+    let mut direct = Exchange::new(DirectExchange);
+    direct.bind("created", "orders.created");
+    direct.bind("paid", "orders.paid");
+    direct.bind("all", "orders.created");
+    direct.bind("all", "orders.paid");
+
+    assert_eq!(strings(["created", "all"].as_slice()), direct.route("orders.created"));
+    assert_eq!(strings(["paid", "all"].as_slice()), direct.route("orders.paid"));
+    assert!(direct.route("orders").is_empty());
+
+    let mut fanout = Exchange::new(FanoutExchange);
+    fanout.bind("created", "orders.created");
+    fanout.bind("audit", "");
+    assert_eq!(strings(["created", "audit"].as_slice()), fanout.route("anything"));
+
+    let mut topic = Exchange::new(TopicExchange);
+    topic.bind("eu", "orders.*.eu");
+    topic.bind("orders", "orders.#");
+    topic.bind("created", "*.created.*");
+    topic.bind("everything", "#");
+    topic.bind("everything", "orders.#");
+
+    assert_eq!(strings(["eu", "orders", "created", "everything"].as_slice()), topic.route("orders.created.eu"));
+    assert_eq!(strings(["orders", "everything"].as_slice()), topic.route("orders.paid.us"));
+    assert_eq!(strings(["orders", "everything"].as_slice()), topic.route("orders"));
+    assert_eq!(strings(["everything"].as_slice()), topic.route("invoices.created"));
+
+    topic.unbind("everything", "#");
+    assert!(topic.route("invoices.created").is_empty());
+    assert_eq!(strings(["orders", "everything"].as_slice()), topic.route("orders.paid.us"));
+}
+
+#[test]
+fn publishing_to_exchanges() {
+    let storage = MemoryStorage::new();
+    let exchanges = ConfigureExchangeCommand::with_storage(storage.clone());
+    let command = PublishCommand::with_storage(storage.clone());
+
+    assert_eq!(Err(NoExchange), command.publish("orders", "orders.created.eu", &Message::new("order 42")));
+    assert!(! exchanges.bind("orders", "eu-orders", "orders.*.eu"));
+
+    exchanges.execute("orders", Some(Exchange::new(TopicExchange)));
+    assert!(exchanges.bind("orders", "eu-orders", "orders.*.eu"));
+    assert!(exchanges.bind("orders", "audit", "#"));
+    assert_eq!(2, exchanges.exchange("orders").unwrap().bindings.len());
+
+    PushCommand::with_storage(storage.clone()).execute("audit", "an entry");
+
+    assert_eq!(Ok(vec![("eu-orders".to_string(), 1), ("audit".to_string(), 2)]),
+               command.publish("orders", "orders.created.eu", &Message::new("order 42")));
+    assert_eq!(Ok(vec![("audit".to_string(), 3)]),
+               command.publish("orders", "orders.created.us", &Message::new("order 43")));
+    assert_eq!(Ok("order 42".to_string()), PeekCommand::with_storage(storage.clone()).peek("eu-orders", Some(1)).map(|r| r.message));
+    assert_eq!(Ok("order 43".to_string()), PeekCommand::with_storage(storage.clone()).peek("audit", Some(3)).map(|r| r.message));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("audit", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(3),
+        policy: RejectPushes
+    }));
+
+    assert_eq!(Err(PublishRejected(QuotaExceeded(RiverQuotaExceeded))),
+               command.publish("orders", "orders.created.eu", &Message::new("order 44")));
+    assert_eq!(Err(RolledBack), PeekCommand::with_storage(storage.clone()).peek("eu-orders", Some(2)).map(|r| r.message));
+
+    assert!(exchanges.unbind("orders", "audit", "#"));
+    assert_eq!(Ok(vec![]), command.publish("orders", "orders.created.us", &Message::new("order 45")));
+
+    exchanges.execute("orders", None);
+    assert_eq!(None, exchanges.exchange("orders"));
+}
+
+#[test]
+fn routing_messages_by_rules() {
+    let storage = MemoryStorage::new();
+    let routing = ConfigureRoutingCommand::with_storage(storage.clone());
+    let command = PushCommand::with_storage(storage.clone());
+    let rule = |filter: &str, target: &str| RoutingRule { filter: filter.to_string(), target: target.to_string() };
+
+    assert_eq!(Ok(()), routing.execute("payments", vec![
+        rule("$.amount > 1000", "fraud-review"),
+        rule(r#"$.currency == "EUR""#, "eu-payments"),
+        rule("$.amount > 5000", "fraud-review")
+    ]));
+    assert_eq!(Ok(()), routing.execute("fraud-review", vec![rule("offset > 0", "archive")]));
+    assert_eq!(3, routing.rules("payments").len());
+
+    command.execute("payments", r#"{"amount":10,"currency":"USD"}"#);
+    command.execute("payments", r#"{"amount":9000,"currency":"EUR"}"#);
+    assert_eq!(Ok(Appended(3)), command.push_once("payments", "3", &Message::new(r#"{"amount":2000,"currency":"USD"}"#)));
+    assert_eq!(Ok(Duplicate(3)), command.push_once("payments", "3", &Message::new(r#"{"amount":2000,"currency":"USD"}"#)));
+
+    let mut transaction = Transaction::new();
+    transaction.push("payments", r#"{"amount":20,"currency":"EUR"}"#);
+    assert_eq!(Ok(vec![4]), CommitCommand::with_storage(storage.clone()).execute(&transaction));
+
+    let payloads = |river: &str| -> Vec < String > {
+        range(1, storage.latest_offset(river) + 1).filter_map(|offset| storage.read(river, offset)).map(|message| message.payload).collect()
+    };
+
+    assert_eq!(vec![r#"{"amount":9000,"currency":"EUR"}"#.to_string(), r#"{"amount":2000,"currency":"USD"}"#.to_string()], payloads("fraud-review"));
+    assert_eq!(vec![r#"{"amount":9000,"currency":"EUR"}"#.to_string(), r#"{"amount":20,"currency":"EUR"}"#.to_string()], payloads("eu-payments"));
+    assert!(payloads("archive").is_empty());
+    assert_eq!(None, storage.read("eu-payments", 2).and_then(|message| message.transaction));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("fraud-review", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(2),
+        policy: RejectPushes
+    }));
+    assert_eq!(Ok(5), command.push("payments", &Message::new(r#"{"amount":3000}"#)));
+    assert_eq!(2, storage.latest_offset("fraud-review"));
+
+    assert_eq!(Err(InvalidRule("rule should not route the river to itself".to_string())),
+               routing.execute("payments", vec![rule("$.amount > 1000", "payments")]));
+    assert!(match routing.execute("payments", vec![rule("$.amount >", "fraud-review")]) { Err(InvalidRule(_)) => true, _ => false });
+    assert_eq!(3, routing.rules("payments").len());
+
+    assert_eq!(Ok(()), routing.execute("payments", vec![]));
+    command.execute("payments", r#"{"amount":9000,"currency":"EUR"}"#);
+    assert_eq!(2, storage.latest_offset("eu-payments"));
+}
+
+fn shout(message: &Message) -> Message {
+    Message::new(message.payload.as_slice().chars().map(|c| c.to_uppercase()).collect::< String >().as_slice())
+}
+
+fn is_short(message: &Message) -> bool {
+    message.payload.len() < 10
+}
+
+fn words(message: &Message) -> Vec < Message > {
+    message.payload.as_slice().words().map(|word| Message::new(word)).collect()
+}
+
+#[test]
+fn deriving_rivers_by_transforms() {
+    let storage = MemoryStorage::new();
+    let command = DeriveRiverCommand::with_storage(storage.clone());
+    let payloads = |river: &str| -> Vec < String > {
+        range(storage.earliest_offset(river), storage.latest_offset(river) + 1)
+            .filter_map(|offset| storage.read(river, offset)).map(|message| message.payload).collect()
+    };
+
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hello world");
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hi");
+
+    assert_eq!(None, command.offset("shouted"));
+    assert_eq!(Ok(2), command.run("shouted", "greetings", "shouts", &MapTransform(shout)));
+    assert_eq!(Ok(0), command.run("shouted", "greetings", "shouts", &MapTransform(shout)));
+    assert_eq!(Some(3), command.offset("shouted"));
+
+    assert_eq!(Ok(2), command.run("short", "greetings", "short-greetings", &FilterTransform(is_short)));
+    assert_eq!(Ok(2), command.run("words", "greetings", "words", &FlatMapTransform(words)));
+
+    assert_eq!(vec!["HELLO WORLD".to_string(), "HI".to_string()], payloads("shouts"));
+    assert_eq!(vec!["hi".to_string()], payloads("short-greetings"));
+    assert_eq!(vec!["hello".to_string(), "world".to_string(), "hi".to_string()], payloads("words"));
+
+    // restarted after a truncation of the input river
+    TruncateCommand::with_storage(storage.clone()).execute("greetings", 3);
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hey");
+    storage.update_state(".transforms", "offsets", |_| "{\"shouted\":1}".to_string()).unwrap();
+
+    assert_eq!(Ok(1), command.run("shouted", "greetings", "shouts", &MapTransform(shout)));
+    assert_eq!(vec!["HELLO WORLD".to_string(), "HI".to_string(), "HEY".to_string()], payloads("shouts"));
+}
+
+#[test]
+fn retrying_outputs_rejected_by_derived_river() {
+    let storage = MemoryStorage::new();
+    let command = DeriveRiverCommand::with_storage(storage.clone());
+
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hello world");
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hi there");
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("words", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(3),
+        policy: RejectPushes
+    }));
+
+    assert_eq!(Err(RiverQuotaExceeded), command.run("words", "greetings", "words", &FlatMapTransform(words)));
+    assert_eq!(Some(2), command.offset("words"));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("words", None);
+    assert_eq!(Ok(1), command.run("words", "greetings", "words", &FlatMapTransform(words)));
+    assert_eq!(4, storage.latest_offset("words"));
+}
+
+#[test]
+fn dead_lettering_outputs_rejected_by_schema_of_derived_river() {
+    let storage = MemoryStorage::new();
+    let command = DeriveRiverCommand::with_storage(storage.clone());
+    RegisterSchemaCommand::with_storage(storage.clone()).execute("orders", "{\"type\":\"object\",\"required\":[\"id\"]}").unwrap();
+    ConfigureSchemaCommand::with_storage(storage.clone()).execute("short-orders", Some(SchemaBinding {
+        schema: "orders".to_string(),
+        version: None
+    }));
+
+    PushCommand::with_storage(storage.clone()).execute("orders", "{\"id\":1}");
+    PushCommand::with_storage(storage.clone()).execute("orders", "{}");
+    PushCommand::with_storage(storage.clone()).execute("orders", "{\"id\":2}");
+
+    assert_eq!(Ok(3), command.run("short-orders", "orders", "short-orders", &FilterTransform(is_short)));
+    assert_eq!(2, storage.latest_offset("short-orders"));
+
+    ConfigureDeadLetterCommand::with_storage(storage.clone()).execute("short-orders", Some(DeadLetterPolicy {
+        river: "short-orders-dlq".to_string(),
+        max_attempts: 3
+    })).unwrap();
+    PushCommand::with_storage(storage.clone()).execute("orders", "{}");
+
+    assert_eq!(Ok(1), command.run("short-orders", "orders", "short-orders", &FilterTransform(is_short)));
+    assert_eq!(2, storage.latest_offset("short-orders"));
+    assert_eq!(Some(Some(DeadLetter {
+        river: "short-orders".to_string(),
+        offset: 0,
+        reason: "$.id is required".to_string(),
+        attempts: 1
+    })), storage.read("short-orders-dlq", 1).map(|message| message.dead_letter));
+}
+
+#[test]
+fn deriving_rivers_in_background() {
+    let storage = MemoryStorage::new();
+    let shouts = DeriveRiverCommand::with_storage(storage.clone()).execute("shouted", "greetings", "shouts", MapTransform(shout));
+
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hello");
+
+    for _ in range(0u, 100) {
+        if storage.latest_offset("shouts") > 0 {
+            break
+        }
+
+        io::timer::sleep(Duration::milliseconds(20));
+    }
+
+    shouts.stop();
+    assert_eq!(Some("HELLO".to_string()), storage.read("shouts", 1).map(|message| message.payload));
+}
+
+/// Start, key, count and sum of every aggregation result pushed to the river
+fn aggregates < S: Storage > (storage: &S, river: &str) -> Vec < (u64, String, u64, f64) > {
+    range(storage.earliest_offset(river), storage.latest_offset(river) + 1)
+        .filter_map(|offset| storage.read(river, offset))
+        .map(|message| {
+            let result = json::from_str(message.payload.as_slice()).unwrap();
+            let field = |name: &str| result.find(&name.to_string()).unwrap().clone();
+
+            (field("start").as_u64().unwrap(), field("key").to_string(), field("count").as_u64().unwrap(), field("sum").as_f64().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn aggregating_messages_in_tumbling_windows() {
+    let storage = MemoryStorage::new();
+    let command = AggregateCommand::with_storage(storage.clone());
+    let aggregation = Aggregation::new(TumblingWindow(1000), Some("$.region"), Some("$.total"), 500).unwrap();
+    let push = |timestamp: u64, payload: &str| {
+        PushCommand::with_storage(storage.clone()).push("orders", &Message { timestamp: timestamp, ..Message::new(payload) }).unwrap();
+    };
+
+    push(100, r#"{"region":"eu","total":10}"#);
+    push(900, r#"{"region":"us","total":5}"#);
+    push(1200, r#"{"region":"eu","total":1}"#);
+    push(950, r#"{"region":"eu","total":2}"#);     // late, but within grace period
+    assert_eq!(Ok(4), command.run("per second", "orders", "orders-per-second", &aggregation));
+    assert!(aggregates(&storage, "orders-per-second").is_empty());
+
+    push(1600, r#"{"region":"eu","total":3}"#);    // closes the first window
+    push(700, r#"{"region":"eu","total":100}"#);   // too late, dropped
+    push(3000, r#"{"region":"us","total":1}"#);
+    assert_eq!(Ok(3), command.run("per second", "orders", "orders-per-second", &aggregation));
+    assert_eq!(Ok(0), command.run("per second", "orders", "orders-per-second", &aggregation));
+
+    assert_eq!(vec![
+        (0, "\"eu\"".to_string(), 2, 12.0),
+        (0, "\"us\"".to_string(), 1, 5.0),
+        (1000, "\"eu\"".to_string(), 2, 4.0)
+    ], aggregates(&storage, "orders-per-second"));
+
+    assert!(Aggregation::new(TumblingWindow(0), None, None, 0).is_err());
+    assert!(Aggregation::new(TumblingWindow(1000), Some("$."), None, 0).is_err());
+    assert!(Aggregation::new(SlidingWindow(1000, 0), None, None, 0).is_err());
+}
+
+#[test]
+fn aggregating_messages_in_sliding_windows() {
+    let storage = MemoryStorage::new();
+    let command = AggregateCommand::with_storage(storage.clone());
+    let aggregation = Aggregation::new(SlidingWindow(2000, 1000), None, None, 0).unwrap();
+
+    for &timestamp in [500u64, 1500, 2500, 4000].iter() {
+        PushCommand::with_storage(storage.clone()).push("clicks", &Message { timestamp: timestamp, ..Message::new("click") }).unwrap();
+    }
+
+    assert_eq!(Ok(4), command.run("clicks", "clicks", "clicks-per-2s", &aggregation));
+    assert_eq!(vec![
+        (0, "null".to_string(), 2, 0.0),
+        (1000, "null".to_string(), 2, 0.0),
+        (2000, "null".to_string(), 1, 0.0)
+    ], aggregates(&storage, "clicks-per-2s"));
+}
+
+#[test]
+fn closing_windows_when_no_messages_arrive() {
+    let storage = MemoryStorage::new();
+    let command = AggregateCommand::with_storage(storage.clone());
+    let aggregation = Aggregation::new(TumblingWindow(100), None, None, 100).unwrap();
+
+    PushCommand::with_storage(storage.clone()).push("clicks", &Message { timestamp: 50, ..Message::new("click") }).unwrap();
+    assert_eq!(Ok(1), command.run("clicks", "clicks", "clicks-per-100ms", &aggregation));
+    assert!(aggregates(&storage, "clicks-per-100ms").is_empty());
+
+    io::timer::sleep(Duration::milliseconds(300));
+
+    assert_eq!(Ok(0), command.run("clicks", "clicks", "clicks-per-100ms", &aggregation));
+    assert_eq!(vec![(0, "null".to_string(), 1, 0.0)], aggregates(&storage, "clicks-per-100ms"));
+}
+
+#[test]
+fn retrying_results_rejected_by_aggregated_river() {
+    let storage = MemoryStorage::new();
+    let command = AggregateCommand::with_storage(storage.clone());
+    let aggregation = Aggregation::new(TumblingWindow(1000), None, None, 0).unwrap();
+
+    for &timestamp in [500u64, 1500, 2500].iter() {
+        PushCommand::with_storage(storage.clone()).push("clicks", &Message { timestamp: timestamp, ..Message::new("click") }).unwrap();
+    }
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("clicks-per-second", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(1),
+        policy: RejectPushes
+    }));
+
+    assert_eq!(Err(RiverQuotaExceeded), command.run("clicks", "clicks", "clicks-per-second", &aggregation));
+    assert_eq!(1, storage.latest_offset("clicks-per-second"));
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("clicks-per-second", None);
+    assert_eq!(Ok(0), command.run("clicks", "clicks", "clicks-per-second", &aggregation));
+    assert_eq!(vec![
+        (0, "null".to_string(), 1, 0.0),
+        (1000, "null".to_string(), 1, 0.0)
+    ], aggregates(&storage, "clicks-per-second"));
+}
+
+#[test]
+fn stopping_aggregations_with_unreadable_progress() {
+    let storage = MemoryStorage::new();
+    let command = AggregateCommand::with_storage(storage.clone());
+    let aggregation = Aggregation::new(TumblingWindow(1000), None, None, 0).unwrap();
+
+    PushCommand::with_storage(storage.clone()).push("clicks", &Message { timestamp: 500, ..Message::new("click") }).unwrap();
+    storage.update_state(".aggregations", "aggregations", |_| "{\"clicks\":".to_string()).unwrap();
+
+    assert!(command.run("clicks", "clicks", "clicks-per-second", &aggregation).is_err());
+    assert_eq!(Some("{\"clicks\":".to_string()), storage.state(".aggregations", "aggregations"));
+    assert!(aggregates(&storage, "clicks-per-second").is_empty());
+}
+
+#[test]
+fn collecting_metrics() {
+    let storage = MemoryStorage::new();
+    let push = PushCommand::with_storage(storage.clone());
+
+    ConfigureQuotaCommand::with_storage(storage.clone()).execute("metered river", Some(Quota {
+        max_bytes: None,
+        max_messages: Some(2),
+        policy: RejectPushes
+    }));
+
+    assert_eq!(Ok(1), push.push("metered river", &Message::new("hello")));
+    assert_eq!(Ok(2), push.push("metered river", &Message::new("world!")));
+    assert_eq!(Err(RiverQuotaExceeded), push.push("metered river", &Message::new("rejected")));
+    PeekCommand::with_storage(storage.clone()).execute("metered river", Some(1));
+
+    let metrics = MetricsCommand::with_storage(storage.clone()).execute();
+    let lines: Vec < &str > = metrics.as_slice().lines().collect();
+
+    for line in [
+        "# TYPE john_messages_in_total counter",
+        "john_messages_in_total{river=\"metered river\"} 2",
+        "john_bytes_in_total{river=\"metered river\"} 11",
+        "john_messages_out_total{river=\"metered river\"} 1",
+        "john_bytes_out_total{river=\"metered river\"} 5",
+        "john_push_errors_total{river=\"metered river\",reason=\"river_quota_exceeded\"} 1",
+        "# TYPE john_river_messages gauge",
+        "john_river_messages{river=\"metered river\"} 2",
+        "john_river_earliest_offset{river=\"metered river\"} 1",
+        "john_river_latest_offset{river=\"metered river\"} 2",
+        "# TYPE john_request_duration_seconds histogram"
+    ].iter() {
+        assert!(lines.contains(line), "{} is missing", line);
+    }
+}
+
+#[test]
+fn counting_only_reads_of_clients_in_metrics() {
+    let storage = MemoryStorage::new();
+    PushCommand::with_storage(storage.clone()).execute("greetings", "hello");
+    DeriveRiverCommand::with_storage(storage.clone()).run("shouted", "greetings", "shouts", &MapTransform(shout)).unwrap();
+
+    let metrics = MetricsCommand::with_storage(storage.clone()).execute();
+    assert!(! metrics.as_slice().contains("john_messages_out_total{river=\"greetings\"}"));
+
+    PeekCommand::with_storage(storage.clone()).execute("shouts", None);
+
+    let metrics = MetricsCommand::with_storage(storage.clone()).execute();
+    assert!(metrics.as_slice().lines().any(|line| line == "john_messages_out_total{river=\"shouts\"} 1"));
+    assert!(! MetricsCommand::with_storage(MemoryStorage::new()).execute().as_slice().contains("john_messages_in_total{"));
+}
+
+/// Pushes two messages to a fresh river, then appends bytes left by a crashed push
+fn crash_while_pushing(root: &Path, river: &str, torn: &[u8]) -> FileStorage {
+    let storage = FileStorage::in_root(root);
+
+    ClearCommand::with_storage(storage.clone()).execute(river);
+    PushCommand::with_storage(storage.clone()).execute(river, "message 1");
+    PushCommand::with_storage(storage.clone()).execute(river, "message 2");
+
+    io::File::open_mode(&root.join(river), io::Append, io::Write).write(torn).unwrap();
+    storage
+}
+
+#[test]
+fn repairing_rivers_torn_at_each_write_boundary() {
+    let mut zero_filled = b"message 3".to_vec();
+    let length = zero_filled.len();
+    zero_filled.grow(4096 - length, 0);
+
+    let mut torn_meta = b"message 3\n{\"timestamp\":14".to_vec();
+    let length = torn_meta.len();
+    torn_meta.grow(4096 - length, 0);
+
+    let crashes: Vec < (&str, Vec < u8 >) > = vec![
+        ("torn river 1", b"mess".to_vec()),                  // while writing payload
+        ("torn river 2", b"message 3".to_vec()),             // between payload and padding
+        ("torn river 3", b"message 3\n{\"timest".to_vec()),  // while writing padding
+        ("torn river 4", zero_filled),                       // slot allocated, payload lost
+        ("torn river 5", torn_meta)                          // slot allocated, metadata lost
+    ];
+
+    for &(river, ref torn) in crashes.iter() {
+        let storage = crash_while_pushing(&Path::new("./tmp/torn_rivers"), river, torn.as_slice());
+        let torn_writes = storage.repair();
+
+        assert!(torn_writes.contains(&TornWrite { river: river.to_string(), offset: 3, bytes: torn.len() }));
+        assert_eq!(2, storage.latest_offset(river));
+        assert_eq!(2 * 4096, Path::new("./tmp/torn_rivers").join(river).stat().unwrap().size);
+
+        PushCommand::with_storage(storage.clone()).execute(river, "message 3");
+        assert_eq!(Ok(("message 3".to_string(), 4)), PeekCommand::with_storage(storage.clone()).peek(river, Some(3)).map(|r| (r.message, r.offset)));
+        assert_eq!(Ok(("message 2".to_string(), 3)), PeekCommand::with_storage(storage.clone()).peek(river, Some(2)).map(|r| (r.message, r.offset)));
+    }
+}
+
+#[test]
+fn repairing_intact_rivers() {
+    let storage = crash_while_pushing(&Path::new("./tmp/intact_rivers"), "torn river 6", b"");
+
+    assert!(storage.repair().iter().all(|torn_write| torn_write.river.as_slice() != "torn river 6"));
+    assert_eq!(2, storage.latest_offset("torn river 6"));
+}
+
+#[test]
+fn repairing_rivers_with_multiline_messages() {
+    let storage = crash_while_pushing(&Path::new("./tmp/multiline_rivers"), "torn river 7", b"");
+    PushCommand::with_storage(storage.clone()).execute("torn river 7", "line 1\nline 2\n");
+
+    assert!(storage.repair().iter().all(|torn_write| torn_write.river.as_slice() != "torn river 7"));
+    assert_eq!(Some("line 1\nline 2\n".to_string()), storage.read("torn river 7", 3).map(|message| message.payload));
+}
+
+#[test]
+fn repairing_interrupted_truncation() {
+    let root = Path::new("./tmp/truncated_rivers");
+    let storage = FileStorage::in_root(&root);
+    ClearCommand::with_storage(storage.clone()).execute("logs");
+    for entry in ["entry 1", "entry 2", "entry 3"].iter() {
+        PushCommand::with_storage(storage.clone()).execute("logs", *entry);
+    }
+
+    // crash after the kept message and the start of the river were saved
+    let data = io::File::open(&root.join("logs")).read_to_end().unwrap();
+    io::File::create(&root.join(".logs.truncating")).write(data.slice_from(2 * 4096)).unwrap();
+    io::File::create(&root.join(".logs.start")).write_str("3 1").unwrap();
+
+    assert_eq!((3, 3), (storage.earliest_offset("logs"), storage.latest_offset("logs")));
+    assert_eq!(None, storage.read("logs", 2));
+    assert_eq!(Some("entry 3".to_string()), storage.read("logs", 3).map(|message| message.payload));
+
+    storage.repair();
+
+    assert!(! root.join(".logs.truncating").exists());
+    assert_eq!(4096, root.join("logs").stat().unwrap().size);
+    assert_eq!((3, 3), (storage.earliest_offset("logs"), storage.latest_offset("logs")));
+    assert_eq!(Some("entry 3".to_string()), storage.read("logs", 3).map(|message| message.payload));
+}
+
 #[bench]
 fn clearing_an_empty_river(b: &mut test::Bencher) {
     let clear = john::ClearCommand::new();
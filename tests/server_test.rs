@@ -3,7 +3,10 @@ extern crate http;
 extern crate url;
 extern crate serialize;
 
-use john::{ClearCommand, PushCommand, PeekResult, PeekCommand};
+use std::io::fs::PathExtensions;
+
+use john::{ClearCommand, PushCommand, PeekResult, PeekCommand, Snapshot, RiverInfo, TruncateCommand};
+use john::{ReceiveResult, ReceiveCommand, FileStorage, Storage, Message};
 
 use http::client::RequestWriter;
 use http::status;
@@ -31,8 +34,16 @@ fn get(url: String) -> (status::Status, String) {
 }
 
 fn post(url: String, body: String) -> (status::Status, String) {
+    send(http::method::Post, url, body)
+}
+
+fn put(url: String, body: String) -> (status::Status, String) {
+    send(http::method::Put, url, body)
+}
+
+fn send(method: http::method::Method, url: String, body: String) -> (status::Status, String) {
     let parsed_url = Url::parse(url.as_slice()).ok().expect("Invalid url");
-    let mut request: RequestWriter = RequestWriter::new(http::method::Post, parsed_url).unwrap();
+    let mut request: RequestWriter = RequestWriter::new(method, parsed_url).unwrap();
     let data = body.as_slice().as_bytes();
 
     request.headers.content_length = Some(data.len());
@@ -134,3 +145,556 @@ fn push_on_full_river_with_some_offset() {
         _ => panic!("New message should have been created")
     }
 }
+
+#[test]
+fn snapshot_of_all_rivers() {
+    ClearCommand::new().execute("server_side_river_5");
+    PushCommand::new().execute("server_side_river_5", "a message");
+    PushCommand::new().execute("server_side_river_5", "a message 2");
+
+    match get(test_url("/snapshot")) {
+        (status::Ok, json) => match json::decode::< Snapshot >(json.as_slice()) {
+            Ok(snapshot) => {
+                let river = snapshot.rivers.iter()
+                    .find(|river| river.name.as_slice() == "server_side_river_5")
+                    .expect("Snapshot should contain the river");
+
+                assert_eq!(2, river.messages.len());
+                assert_eq!(3, river.offset);
+            },
+            _ => panic!("Unable to parse response into Snapshot")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn info_of_a_river() {
+    ClearCommand::new().execute("server_side_river_6");
+    PushCommand::new().execute("server_side_river_6", "a message");
+    PushCommand::new().execute("server_side_river_6", "a message 2");
+    PushCommand::new().execute("server_side_river_6", "a message 3");
+
+    match get(test_url("/rivers/server_side_river_6")) {
+        (status::Ok, json) => match json::decode::< RiverInfo >(json.as_slice()) {
+            Ok(info) => {
+                assert_eq!("server_side_river_6", info.name.as_slice());
+                assert_eq!(Some(3), info.latest_offset);
+                assert_eq!(3, info.message_count);
+            },
+            _ => panic!("Unable to parse response into RiverInfo")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn info_of_a_missing_river() {
+    ClearCommand::new().execute("server_side_river_7");
+
+    match get(test_url("/rivers/server_side_river_7")) {
+        (status::NotFound, _) => {},
+        _ => panic!("Status should be status::NotFound")
+    }
+}
+
+#[test]
+fn listing_rivers() {
+    PushCommand::new().execute("server_side_river_8", "a message");
+
+    match get(test_url("/rivers")) {
+        (status::Ok, json) => match json::decode::< Vec < RiverInfo > >(json.as_slice()) {
+            Ok(rivers) => assert!(rivers.iter().any(|info| info.name.as_slice() == "server_side_river_8")),
+            _ => panic!("Unable to parse response into list of RiverInfo")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn peek_on_truncated_offset() {
+    ClearCommand::new().execute("server_side_river_9");
+    PushCommand::new().execute("server_side_river_9", "a message");
+    PushCommand::new().execute("server_side_river_9", "a message 2");
+    PushCommand::new().execute("server_side_river_9", "a message 3");
+    TruncateCommand::new().execute("server_side_river_9", 3);
+
+    match get(test_url("/peek/server_side_river_9/1")) {
+        (status::Gone, body) => assert_eq!("{\"earliest_offset\":3}", body.as_slice()),
+        _ => panic!("Status should be status::Gone")
+    }
+}
+
+#[test]
+fn truncate_a_river() {
+    ClearCommand::new().execute("server_side_river_10");
+    PushCommand::new().execute("server_side_river_10", "a message");
+    PushCommand::new().execute("server_side_river_10", "a message 2");
+
+    match post(test_url("/truncate/server_side_river_10/2"), "".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    assert!(PeekCommand::new().execute("server_side_river_10", Some(1)).is_none());
+    assert!(PeekCommand::new().execute("server_side_river_10", Some(2)).is_some());
+}
+
+#[test]
+fn receive_and_ack_a_message() {
+    ClearCommand::new().execute("server_side_river_11");
+    PushCommand::new().execute("server_side_river_11", "a job");
+
+    let received = match post(test_url("/receive/server_side_river_11/60000"), "".to_string()) {
+        (status::Ok, json) => match json::decode::< ReceiveResult >(json.as_slice()) {
+            Ok(result) => result,
+            _ => panic!("Unable to parse response into ReceiveResult")
+        },
+        _ => panic!("Status should be status::Ok")
+    };
+    assert_eq!("a job", received.message.as_slice());
+
+    match post(test_url("/receive/server_side_river_11"), "".to_string()) {
+        (status::NotFound, _) => {},
+        _ => panic!("Status should be status::NotFound")
+    }
+
+    match post(test_url(format!("/ack/server_side_river_11/{}/{}", received.offset, received.receipt).as_slice()), "".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url(format!("/ack/server_side_river_11/{}/{}", received.offset, received.receipt).as_slice()), "".to_string()) {
+        (status::Conflict, _) => {},
+        _ => panic!("Status should be status::Conflict")
+    }
+}
+
+#[test]
+fn nack_moves_a_message_to_dead_letter_river() {
+    ClearCommand::new().execute("server_side_river_12");
+    ClearCommand::new().execute("server_side_river_12.dead");
+    PushCommand::new().execute("server_side_river_12", "a poison job");
+
+    match post(test_url("/dead_letter/server_side_river_12"), "{\"river\":\"server_side_river_12.dead\",\"max_attempts\":1}".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    let received = ReceiveCommand::new().execute("server_side_river_12", 60000).unwrap();
+
+    match post(test_url(format!("/nack/server_side_river_12/{}/{}", received.offset, received.receipt).as_slice()), "it exploded".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    let dead = FileStorage::new().read("server_side_river_12.dead", 1).unwrap();
+    assert_eq!("it exploded", dead.dead_letter.unwrap().reason.as_slice());
+
+    match post(test_url("/replay/server_side_river_12.dead"), "".to_string()) {
+        (status::Ok, body) => assert_eq!("{\"replayed\":1}", body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn push_a_delayed_message() {
+    ClearCommand::new().execute("server_side_river_13");
+
+    match post(test_url("/push/server_side_river_13?delay=60000"), "a reminder".to_string()) {
+        (status::Created, _) => {},
+        _ => panic!("Status should be status::Created")
+    }
+
+    let (status, body) = get(test_url("/peek/server_side_river_13/1"));
+    assert_eq!(425, status.code());
+    assert!(body.as_slice().starts_with("{\"deliver_at\":"));
+
+    match post(test_url("/push/server_side_river_13?delay=soon"), "a reminder".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn peek_on_expired_message() {
+    ClearCommand::new().execute("server_side_river_14");
+
+    match post(test_url("/push/server_side_river_14?ttl=0"), "a ping".to_string()) {
+        (status::Created, _) => {},
+        _ => panic!("Status should be status::Created")
+    }
+
+    match get(test_url("/peek/server_side_river_14/1")) {
+        (status::Gone, body) => assert!(body.as_slice().starts_with("{\"expired_at\":")),
+        _ => panic!("Status should be status::Gone")
+    }
+
+    match get(test_url("/peek/server_side_river_14/2")) {
+        (status::NotFound, _) => {},
+        _ => panic!("Status should be status::NotFound")
+    }
+}
+
+#[test]
+fn receive_from_a_priority_river() {
+    ClearCommand::new().execute("server_side_river_15");
+
+    match post(test_url("/priority/server_side_river_15"), "true".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    post(test_url("/push/server_side_river_15?priority=1"), "a job".to_string());
+    post(test_url("/push/server_side_river_15?priority=7"), "an urgent job".to_string());
+
+    match post(test_url("/receive/server_side_river_15"), "".to_string()) {
+        (status::Ok, json) => match json::decode::< ReceiveResult >(json.as_slice()) {
+            Ok(result) => assert_eq!("an urgent job", result.message.as_slice()),
+            _ => panic!("Unable to parse response into ReceiveResult")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn retrying_a_push_with_idempotency_key() {
+    ClearCommand::new().execute("server_side_river_16");
+    PushCommand::new().execute("server_side_river_16", "a message");
+
+    match post(test_url("/push/server_side_river_16?producer=billing&seq=7"), "an invoice".to_string()) {
+        (status::Created, body) => assert_eq!("{\"offset\":2}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/push/server_side_river_16?producer=billing&seq=7"), "an invoice".to_string()) {
+        (status::Ok, body) => assert_eq!("{\"offset\":2}", body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    assert!(PeekCommand::new().execute("server_side_river_16", Some(3)).is_none());
+}
+
+#[test]
+fn push_to_several_rivers_in_a_transaction() {
+    ClearCommand::new().execute("server_side_river_17");
+    ClearCommand::new().execute("server_side_river_18");
+
+    let body = "[{\"river\":\"server_side_river_17\",\"message\":\"an order\"},\
+                {\"river\":\"server_side_river_18\",\"message\":\"an audit entry\"}]";
+
+    match post(test_url("/transaction"), body.to_string()) {
+        (status::Created, body) => assert_eq!("{\"offsets\":[1,1]}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+
+    assert!(PeekCommand::new().execute("server_side_river_17", Some(1)).is_some());
+    assert!(PeekCommand::new().execute("server_side_river_18", Some(1)).is_some());
+}
+
+#[test]
+fn read_at_explicit_offsets() {
+    ClearCommand::new().execute("server_side_river_19");
+    PushCommand::new().execute("server_side_river_19", "a message");
+    PushCommand::new().execute("server_side_river_19", "a message 2");
+    PushCommand::new().execute("server_side_river_19", "a message 3");
+
+    for &(offset, expected) in [("earliest", 1u), ("latest", 3), ("2", 2), ("latest-2", 1), ("earliest+1", 2)].iter() {
+        match get(test_url(format!("/read/server_side_river_19/{}", offset).as_slice())) {
+            (status::Ok, json) => match json::decode::< Message >(json.as_slice()) {
+                Ok(message) => assert_eq!(expected, message.offset),
+                _ => panic!("Unable to parse response into Message")
+            },
+            _ => panic!("Status should be status::Ok")
+        }
+    }
+
+    match get(test_url("/read/server_side_river_19/next")) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn push_over_quota_of_the_river() {
+    ClearCommand::new().execute("server_side_river_20");
+
+    let quota = "{\"max_bytes\":null,\"max_messages\":1,\"policy\":\"RejectPushes\"}";
+    match post(test_url("/quota/server_side_river_20"), quota.to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/push/server_side_river_20"), "a message".to_string()) {
+        (status::Created, _) => {},
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/push/server_side_river_20"), "a message 2".to_string()) {
+        (status::TooManyRequests, _) => {},
+        _ => panic!("Status should be status::TooManyRequests")
+    }
+
+    match post(test_url("/quota/server_side_river_20"), "{\"max_messages\":1}".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn configure_a_river() {
+    ClearCommand::new().execute("server_side_river_21");
+
+    let config = "{\"retention\":null,\"durable\":true,\"max_message_size\":5,\"compressed\":false,\"mode\":\"LogMode\"}";
+    match put(test_url("/rivers/server_side_river_21/config"), config.to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match get(test_url("/rivers/server_side_river_21/config")) {
+        (status::Ok, body) => assert_eq!(config, body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/push/server_side_river_21"), "a message".to_string()) {
+        (status::RequestEntityTooLarge, _) => {},
+        _ => panic!("Status should be status::RequestEntityTooLarge")
+    }
+
+    match put(test_url("/rivers/server_side_river_21/config"), "{\"durable\":true}".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn push_against_schema_of_the_river() {
+    ClearCommand::new().execute("server_side_river_22");
+
+    let schema = "{\"type\":\"object\",\"required\":[\"id\"]}";
+    match post(test_url("/schemas/server_side_schema_22"), schema.to_string()) {
+        (status::Created, _) => {},
+        _ => panic!("Status should be status::Created")
+    }
+
+    match get(test_url("/schemas/server_side_schema_22")) {
+        (status::Ok, body) => assert_eq!(schema, body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/schemas/server_side_schema_22"), "{\"type\":".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+
+    match post(test_url("/schema/server_side_river_22"), "{\"schema\":\"server_side_schema_22\",\"version\":null}".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/push/server_side_river_22"), "{\"name\":\"an order\"}".to_string()) {
+        (status::UnprocessableEntity, body) => assert_eq!("$.id is required", body.as_slice()),
+        _ => panic!("Status should be status::UnprocessableEntity")
+    }
+
+    match post(test_url("/push/server_side_river_22"), "{\"id\":42}".to_string()) {
+        (status::Created, _) => {},
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/schema/server_side_river_22"), "null".to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn peek_with_a_filter() {
+    ClearCommand::new().execute("server_side_river_23");
+    PushCommand::new().execute("server_side_river_23", "{\"type\":\"order.created\"}");
+    PushCommand::new().execute("server_side_river_23", "{\"type\":\"order.paid\"}");
+    PushCommand::new().execute("server_side_river_23", "{\"type\":\"order.created\"}");
+
+    // $.type == "order.paid"
+    let filter = "filter=%24.type+%3D%3D+%22order.paid%22";
+
+    match get(test_url(format!("/peek/server_side_river_23/1?{}", filter).as_slice())) {
+        (status::Ok, json) => match json::decode::< PeekResult >(json.as_slice()) {
+            Ok(result) => assert_eq!(("{\"type\":\"order.paid\"}".to_string(), 3), (result.message, result.offset)),
+            _ => panic!("Unable to parse response into PeekResult")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match get(test_url(format!("/read/server_side_river_23/3?{}", filter).as_slice())) {
+        (status::NotFound, body) => assert_eq!("{\"offset\":4}", body.as_slice()),
+        _ => panic!("Status should be status::NotFound")
+    }
+
+    match get(test_url("/read/server_side_river_23/earliest?filter=%24.type+%3D%3D")) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn publish_to_an_exchange() {
+    ClearCommand::new().execute("server_side_river_24");
+    ClearCommand::new().execute("server_side_river_25");
+
+    let exchange = "{\"kind\":\"TopicExchange\",\"bindings\":[\
+                    {\"river\":\"server_side_river_24\",\"key\":\"orders.*.eu\"},\
+                    {\"river\":\"server_side_river_25\",\"key\":\"orders.#\"}]}";
+    match post(test_url("/exchanges/server_side_exchange_24"), exchange.to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match get(test_url("/exchanges/server_side_exchange_24")) {
+        (status::Ok, body) => assert_eq!(exchange, body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/publish/server_side_exchange_24/orders.created.eu"), "order 42".to_string()) {
+        (status::Created, body) => assert_eq!("{\"offsets\":{\"server_side_river_24\":1,\"server_side_river_25\":1}}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/publish/server_side_exchange_24/orders.created.us"), "order 43".to_string()) {
+        (status::Created, body) => assert_eq!("{\"offsets\":{\"server_side_river_25\":2}}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/publish/server_side_exchange_missing/orders"), "order 44".to_string()) {
+        (status::NotFound, _) => {},
+        _ => panic!("Status should be status::NotFound")
+    }
+
+    match post(test_url("/exchanges/server_side_exchange_24"), "{\"kind\":\"TopicExchange\"}".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn route_pushes_by_rules() {
+    ClearCommand::new().execute("server_side_river_26");
+    ClearCommand::new().execute("server_side_river_27");
+
+    let rules = "[{\"filter\":\"$.amount > 1000\",\"target\":\"server_side_river_27\"}]";
+    match post(test_url("/routing/server_side_river_26"), rules.to_string()) {
+        (status::Ok, _) => {},
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match get(test_url("/routing/server_side_river_26")) {
+        (status::Ok, body) => assert_eq!(rules, body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    post(test_url("/push/server_side_river_26"), "{\"amount\":10}".to_string());
+    post(test_url("/push/server_side_river_26"), "{\"amount\":5000}".to_string());
+
+    match get(test_url("/read/server_side_river_27/latest")) {
+        (status::Ok, json) => match json::decode::< Message >(json.as_slice()) {
+            Ok(message) => assert_eq!(("{\"amount\":5000}".to_string(), 1), (message.payload, message.offset)),
+            _ => panic!("Unable to parse response into Message")
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/routing/server_side_river_26"), "[{\"filter\":\"$.amount >\",\"target\":\"server_side_river_27\"}]".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn get_metrics() {
+    ClearCommand::new().execute("server_side_river_28");
+
+    post(test_url("/push/server_side_river_28"), "a message".to_string());
+    get(test_url("/peek/server_side_river_28"));
+    get(test_url("/server_side_river_28/unknown"));
+
+    match get(test_url("/metrics")) {
+        (status::Ok, body) => {
+            let lines: Vec < &str > = body.as_slice().lines().collect();
+
+            assert!(lines.contains(&"john_river_messages{river=\"server_side_river_28\"} 1"));
+            assert!(lines.contains(&"john_river_latest_offset{river=\"server_side_river_28\"} 1"));
+            assert!(lines.iter().any(|line| line.starts_with("john_request_duration_seconds_bucket{route=\"push\",method=\"POST\"")));
+            assert!(! lines.iter().any(|line| line.contains("route=\"server_side_river_28\"")));
+            assert!(lines.iter().any(|line| line.starts_with("john_request_duration_seconds_bucket{route=\"other\",method=\"GET\"")));
+        },
+        _ => panic!("Status should be status::Ok")
+    }
+}
+
+#[test]
+fn purge_expired_messages() {
+    ClearCommand::new().execute("server_side_river_29");
+
+    post(test_url("/push/server_side_river_29?ttl=0"), "expired".to_string());
+    post(test_url("/push/server_side_river_29"), "alive".to_string());
+
+    match post(test_url("/purge/server_side_river_29"), "".to_string()) {
+        (status::Ok, body) => assert_eq!("{\"purged\":1}", body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    assert_eq!(Some("alive".to_string()), PeekCommand::new().execute("server_side_river_29", Some(2)).map(|r| r.message));
+}
+
+#[test]
+fn push_with_percent_encoded_key() {
+    ClearCommand::new().execute("server_side_river_30");
+
+    match post(test_url("/push/server_side_river_30?key=order%2042"), "order 42".to_string()) {
+        (status::Created, body) => assert_eq!("{\"offset\":1}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+
+    match post(test_url("/push/server_side_river_30?key=order+42"), "order 42".to_string()) {
+        (status::Ok, body) => assert_eq!("{\"offset\":1}", body.as_slice()),
+        _ => panic!("Status should be status::Ok")
+    }
+
+    match post(test_url("/push/server_side_river_30?key=order%4"), "order 42".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+}
+
+#[test]
+fn reject_rivers_named_like_states() {
+    ClearCommand::new().execute("server_side_river_31");
+    PushCommand::new().execute("server_side_river_31", "order 1");
+
+    match post(test_url("/push/.server_side_river_31.state"), "{}".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+
+    match post(test_url("/transaction"), "[{\"river\":\".server_side_river_31.state\",\"message\":\"{}\"}]".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+
+    assert!(! Path::new("./tmp/rivers/.server_side_river_31.state").exists());
+}
+
+#[test]
+fn reject_producers_with_colons() {
+    ClearCommand::new().execute("server_side_river_32");
+
+    match post(test_url("/push/server_side_river_32?producer=a%3Ab&seq=c"), "order 1".to_string()) {
+        (status::BadRequest, _) => {},
+        _ => panic!("Status should be status::BadRequest")
+    }
+
+    match post(test_url("/push/server_side_river_32?producer=a&seq=b%3Ac"), "order 1".to_string()) {
+        (status::Created, body) => assert_eq!("{\"offset\":1}", body.as_slice()),
+        _ => panic!("Status should be status::Created")
+    }
+}